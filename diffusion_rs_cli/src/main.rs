@@ -3,7 +3,9 @@ use std::{path::PathBuf, time::Instant};
 
 use clap::{Parser, Subcommand};
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    write_dduf, ControlNetCondition, ControlPreprocessor, DeviceSpec, DiffusionGenerationParams,
+    IpAdapterImage, ModelDType, ModelSource, Offloading, Pipeline, PlacementMap, PlanParams,
+    ReduxImage, SaveFormat, TokenSource,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
@@ -27,10 +29,75 @@ pub enum SourceCommand {
     },
 }
 
+impl SourceCommand {
+    fn into_model_source(self) -> anyhow::Result<ModelSource> {
+        match self {
+            Self::Dduf { file } => ModelSource::dduf(file),
+            Self::ModelId { model_id } => Ok(ModelSource::from_model_id(model_id)),
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(flatten)]
+    Generate(SourceCommand),
+
+    /// Estimate the memory usage of the model without loading any weights.
+    Plan {
+        /// Image height to estimate the activation memory for.
+        #[arg(long, default_value_t = 720)]
+        height: usize,
+
+        /// Image width to estimate the activation memory for.
+        #[arg(long, default_value_t = 1280)]
+        width: usize,
+
+        /// Number of images generated together.
+        #[arg(long, default_value_t = 1)]
+        batch_size: usize,
+
+        #[clap(subcommand)]
+        source: SourceCommand,
+    },
+
+    /// List the components, files and DDUF entries of the model, and check it can be loaded without loading
+    /// any weights.
+    Inspect {
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+
+        #[clap(subcommand)]
+        source: SourceCommand,
+    },
+
+    /// Load the model and save it as a diffusers model directory, or as a DDUF file if the output ends with `.dduf`.
+    Save {
+        /// Output directory or DDUF file.
+        #[arg(short, long)]
+        output: PathBuf,
+
+        #[clap(subcommand)]
+        source: SourceCommand,
+    },
+
+    /// Pack a diffusers model directory into a DDUF file.
+    Pack {
+        /// Model directory, containing `model_index.json` and a directory for each component.
+        #[arg(short, long)]
+        model_dir: PathBuf,
+
+        /// DDUF file to write.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
 #[derive(Parser)]
 struct Args {
     #[clap(subcommand)]
-    source: SourceCommand,
+    command: Command,
 
     /// Hugging Face token. Useful for accessing gated repositories.
     /// By default, the Hugging Face token at ~/.cache/huggingface/token is used.
@@ -41,17 +108,173 @@ struct Args {
     #[arg(short, long)]
     scale: Option<f64>,
 
+    /// Negative prompt, for models with real classifier-free guidance such as Chroma.
+    #[arg(long)]
+    negative_prompt: Option<String>,
+
+    /// Number of T5 tokens the prompt is padded or truncated to, for FLUX. Defaults to 512 for FLUX.1 dev and
+    /// 256 for FLUX.1 schnell.
+    #[arg(long)]
+    max_sequence_length: Option<usize>,
+
+    /// Reference image to edit, for image-conditioned models such as FLUX.1 Kontext. Can be repeated.
+    #[arg(long)]
+    reference_image: Vec<PathBuf>,
+
+    /// Image to inpaint, for FLUX.1 Fill. Requires `--mask-image`.
+    #[arg(long)]
+    image: Option<PathBuf>,
+
+    /// Mask of the area to inpaint, white where the image is regenerated, for FLUX.1 Fill.
+    #[arg(long)]
+    mask_image: Option<PathBuf>,
+
+    /// Control image such as a Canny edge map or a depth map, for FLUX.1 Canny and Depth.
+    #[arg(long)]
+    control_image: Option<PathBuf>,
+
+    /// ControlNet to load (local path or Hugging Face model ID). Can be repeated to combine ControlNets.
+    #[arg(long)]
+    controlnet: Vec<String>,
+
+    /// Control image of a ControlNet. Can be repeated, once for each ControlNet, or for each control mode of a
+    /// single Union ControlNet.
+    #[arg(long)]
+    controlnet_image: Vec<PathBuf>,
+
+    /// Scale of each ControlNet control image, or a single scale for all of them. Defaults to 1.0.
+    #[arg(long)]
+    controlnet_scale: Vec<f64>,
+
+    /// Fraction of the denoising steps after which each ControlNet is applied, or a single value for all of
+    /// them. Defaults to 0.0.
+    #[arg(long)]
+    controlnet_start: Vec<f64>,
+
+    /// Fraction of the denoising steps after which each ControlNet is no longer applied, or a single value for
+    /// all of them. Defaults to 1.0.
+    #[arg(long)]
+    controlnet_end: Vec<f64>,
+
+    /// Control mode of each control image, for Union ControlNets.
+    #[arg(long)]
+    controlnet_mode: Vec<usize>,
+
+    /// Preprocess the control images: `canny`, `canny:<low>:<high>`, `soft-edge`, `scribble`,
+    /// `scribble:<threshold>`, `grayscale`, `blur`, `blur:<sigma>`, `tile`, or `tile:<factor>`.
+    #[arg(long)]
+    control_preprocessor: Option<ControlPreprocessor>,
+
+    /// XLabs IP-Adapter to load for FLUX (local path or Hugging Face model ID).
+    #[arg(long)]
+    ip_adapter: Option<String>,
+
+    /// CLIP image encoder of the IP-Adapter (local path or Hugging Face model ID).
+    #[arg(long, default_value = "openai/clip-vit-large-patch14")]
+    ip_adapter_image_encoder: String,
+
+    /// Image prompt of the IP-Adapter, such as a style reference. Can be repeated.
+    #[arg(long)]
+    ip_adapter_image: Vec<PathBuf>,
+
+    /// Scale of each IP-Adapter image, or a single scale for all of them. Defaults to 1.0.
+    #[arg(long)]
+    ip_adapter_scale: Vec<f64>,
+
+    /// FLUX.1 Redux prior to load, such as `black-forest-labs/FLUX.1-Redux-dev` (local path or Hugging Face
+    /// model ID).
+    #[arg(long)]
+    redux: Option<String>,
+
+    /// Image prompt of the Redux prior, to generate variations of.
+    #[arg(long)]
+    redux_image: Option<PathBuf>,
+
+    /// Strength of the Redux image prompt.
+    #[arg(long, default_value_t = 1.0)]
+    redux_strength: f64,
+
     /// Number of denoising steps. This is model specific. A higher number of steps often means higher quality.
+    /// Required to generate images.
     #[arg(short, long)]
-    num_steps: usize,
+    num_steps: Option<usize>,
 
-    /// Offloading setting to use for this model
+    /// Offloading setting to use for this model: `full`, `disk`, or `disk:<budget in MiB>`.
+    /// Disk offloading keeps the transformer blocks on disk and loads them one at a time.
     #[arg(short, long)]
     offloading: Option<Offloading>,
 
+    /// Device to run the model on: `auto`, `cpu`, `cuda:<ordinal>`, or `metal:<ordinal>`.
+    /// The default is to use Metal or CUDA if this build supports it, otherwise the CPU.
+    #[arg(long, default_value = "auto")]
+    device: DeviceSpec,
+
     /// DType for the model. The default is to use an automatic strategy with a fallback pattern: BF16 -> F16 -> F32
     #[arg(short, long, default_value = "auto")]
     dtype: ModelDType,
+
+    /// Check the integrity of the model files before loading them: the CRC32 of DDUF entries, the safetensors
+    /// headers, and the sha256 of files downloaded from the Hugging Face Hub.
+    #[arg(long)]
+    verify: bool,
+}
+
+/// Build the ControlNet conditions from the `--controlnet-*` arguments, which are given once for each control
+/// image or once for all of them.
+fn controlnet_conditions(
+    images: &[PathBuf],
+    scales: &[f64],
+    starts: &[f64],
+    ends: &[f64],
+    modes: &[usize],
+) -> anyhow::Result<Vec<ControlNetCondition>> {
+    let num_images = images.len();
+    let value = |values: &[f64], name: &str, i: usize, default: f64| match values.len() {
+        0 => Ok(default),
+        1 => Ok(values[0]),
+        n if n == num_images => Ok(values[i]),
+        n => anyhow::bail!("Expected 1 or {num_images} values for `--controlnet-{name}`, got {n}."),
+    };
+    if !modes.is_empty() && modes.len() != num_images {
+        anyhow::bail!(
+            "Expected {num_images} values for `--controlnet-mode`, got {}.",
+            modes.len()
+        );
+    }
+    images
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            Ok(ControlNetCondition {
+                image: image::open(path)?,
+                scale: value(scales, "scale", i, 1.)?,
+                start: value(starts, "start", i, 0.)?,
+                end: value(ends, "end", i, 1.)?,
+                mode: modes.get(i).copied(),
+            })
+        })
+        .collect()
+}
+
+/// Build the IP-Adapter image prompts from the `--ip-adapter-image` and `--ip-adapter-scale` arguments.
+fn ip_adapter_images(images: &[PathBuf], scales: &[f64]) -> anyhow::Result<Vec<IpAdapterImage>> {
+    if scales.len() > 1 && scales.len() != images.len() {
+        anyhow::bail!(
+            "Expected 1 or {} values for `--ip-adapter-scale`, got {}.",
+            images.len(),
+            scales.len()
+        );
+    }
+    images
+        .iter()
+        .enumerate()
+        .map(|(i, path)| {
+            Ok(IpAdapterImage {
+                image: image::open(path)?,
+                scale: scales.get(i).or(scales.first()).copied().unwrap_or(1.),
+            })
+        })
+        .collect()
 }
 
 fn main() -> anyhow::Result<()> {
@@ -62,16 +285,138 @@ fn main() -> anyhow::Result<()> {
         .from_env_lossy();
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
-    let source = match args.source {
-        SourceCommand::Dduf { file } => ModelSource::dduf(file)?,
-        SourceCommand::ModelId { model_id } => ModelSource::from_model_id(model_id),
-    };
     let token = args
         .token
         .map(TokenSource::Literal)
         .unwrap_or(TokenSource::CacheToken);
 
-    let pipeline = Pipeline::load(source, false, token, None, args.offloading, &args.dtype)?;
+    let source = match args.command {
+        Command::Generate(source) => source,
+        Command::Plan {
+            height,
+            width,
+            batch_size,
+            source,
+        } => {
+            let plan = Pipeline::plan(
+                source.into_model_source()?,
+                false,
+                token,
+                None,
+                args.offloading,
+                args.device,
+                &args.dtype,
+                &PlacementMap::new(),
+                PlanParams {
+                    height,
+                    width,
+                    batch_size,
+                },
+            )?;
+            println!("{plan}");
+            return Ok(());
+        }
+        Command::Inspect { json, source } => {
+            let report =
+                Pipeline::inspect(source.into_model_source()?, false, token, None, args.verify)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                println!("{report}");
+            }
+            if !report.passed() {
+                anyhow::bail!("The model failed inspection.");
+            }
+            return Ok(());
+        }
+        Command::Save { output, source } => {
+            let pipeline = Pipeline::load(
+                source.into_model_source()?,
+                false,
+                token,
+                None,
+                args.offloading,
+                args.device,
+                &args.dtype,
+                &PlacementMap::new(),
+                args.verify,
+            )?;
+            let format = if output.extension().is_some_and(|ext| ext == "dduf") {
+                SaveFormat::Dduf
+            } else {
+                SaveFormat::Directory
+            };
+            pipeline.save(&output, format)?;
+            println!("Saved the model to {}.", output.display());
+            return Ok(());
+        }
+        Command::Pack { model_dir, output } => {
+            let entries = write_dduf(&model_dir, &output)?;
+            for entry in &entries {
+                println!("{entry}");
+            }
+            println!("Wrote {} entries to {}.", entries.len(), output.display());
+            return Ok(());
+        }
+    };
+    let Some(num_steps) = args.num_steps else {
+        anyhow::bail!("`--num-steps` is required to generate images.");
+    };
+
+    let pipeline = Pipeline::load(
+        source.into_model_source()?,
+        false,
+        token.clone(),
+        None,
+        args.offloading,
+        args.device,
+        &args.dtype,
+        &PlacementMap::new(),
+        args.verify,
+    )?;
+    for controlnet in &args.controlnet {
+        pipeline.load_controlnet(
+            ModelSource::from_model_id(controlnet),
+            false,
+            token.clone(),
+            None,
+        )?;
+    }
+    if let Some(ip_adapter) = &args.ip_adapter {
+        pipeline.load_ip_adapter(
+            ModelSource::from_model_id(ip_adapter),
+            ModelSource::from_model_id(&args.ip_adapter_image_encoder),
+            false,
+            token.clone(),
+            None,
+        )?;
+    }
+    if let Some(redux) = &args.redux {
+        pipeline.load_redux(
+            ModelSource::from_model_id(redux),
+            false,
+            token.clone(),
+            None,
+        )?;
+    }
+    let ip_adapter_images = ip_adapter_images(&args.ip_adapter_image, &args.ip_adapter_scale)?;
+    let redux_image = args
+        .redux_image
+        .as_ref()
+        .map(|path| {
+            anyhow::Ok(ReduxImage {
+                image: image::open(path)?,
+                strength: args.redux_strength,
+            })
+        })
+        .transpose()?;
+    let mut controlnet_conditions = controlnet_conditions(
+        &args.controlnet_image,
+        &args.controlnet_scale,
+        &args.controlnet_start,
+        &args.controlnet_end,
+        &args.controlnet_mode,
+    )?;
 
     let height: usize = input("Height:")
         .default_input("720")
@@ -94,6 +439,23 @@ fn main() -> anyhow::Result<()> {
         })
         .interact()?;
 
+    let reference_images = args
+        .reference_image
+        .iter()
+        .map(image::open)
+        .collect::<Result<Vec<_>, _>>()?;
+    let image = args.image.as_ref().map(image::open).transpose()?;
+    let mask_image = args.mask_image.as_ref().map(image::open).transpose()?;
+    let mut control_image = args.control_image.as_ref().map(image::open).transpose()?;
+    if let Some(preprocessor) = args.control_preprocessor {
+        if let Some(image) = &mut control_image {
+            *image = preprocessor.process(image, width, height)?;
+        }
+        for condition in &mut controlnet_conditions {
+            condition.image = preprocessor.process(&condition.image, width, height)?;
+        }
+    }
+
     loop {
         let prompt: String = input("Prompt:")
             .validate(|input: &String| {
@@ -112,8 +474,17 @@ fn main() -> anyhow::Result<()> {
             DiffusionGenerationParams {
                 height,
                 width,
-                num_steps: args.num_steps,
+                num_steps,
                 guidance_scale: args.scale.unwrap_or(GUIDANCE_SCALE_DEFAULT),
+                negative_prompt: args.negative_prompt.clone(),
+                reference_images: reference_images.clone(),
+                image: image.clone(),
+                mask_image: mask_image.clone(),
+                control_image: control_image.clone(),
+                controlnet_conditions: controlnet_conditions.clone(),
+                ip_adapter_images: ip_adapter_images.clone(),
+                redux_image: redux_image.clone(),
+                max_sequence_length: args.max_sequence_length,
             },
         )?;
 
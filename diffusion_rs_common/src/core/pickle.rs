@@ -427,7 +427,10 @@ impl Stack {
         let op_code = match OpCode::try_from(r.read_u8()?) {
             Ok(op_code) => op_code,
             Err(op_code) => {
-                crate::bail!("unknown op-code {op_code}")
+                // Never fall back to a general unpickler, which could execute arbitrary code.
+                crate::bail!(
+                    "unsupported pickle op-code {op_code:#04x}, only the op-codes used by PyTorch checkpoints are supported"
+                )
             }
         };
         // println!("op: {op_code:?}");
@@ -650,7 +653,11 @@ pub fn read_pth_tensor_info<P: AsRef<std::path::Path>>(
 ) -> Result<Vec<TensorInfo>> {
     let file = std::fs::File::open(file)?;
     let zip_reader = std::io::BufReader::new(file);
-    let mut zip = zip::ZipArchive::new(zip_reader)?;
+    let mut zip = zip::ZipArchive::new(zip_reader).map_err(|e| {
+        E::Msg(format!(
+            "only PyTorch checkpoints saved in the zip format (PyTorch >= 1.6) are supported: {e}"
+        ))
+    })?;
     let zip_file_names = zip
         .file_names()
         .map(|f| f.to_string())
@@ -0,0 +1,203 @@
+//! Writing of DDUF files, see the [specification](https://huggingface.co/docs/hub/dduf).
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::ModelSource;
+
+/// Alignment of the data of each entry, so that tensors can be read in place from the memory-mapped file.
+pub const DDUF_ALIGNMENT: u16 = 64;
+
+/// File extensions which may be stored in a DDUF file.
+pub const DDUF_ALLOWED_EXTENSIONS: &[&str] = &["json", "model", "safetensors", "txt"];
+
+/// Each component directory must contain at least one of these files.
+pub const DDUF_COMPONENT_CONFIGS: &[&str] = &[
+    "config.json",
+    "preprocessor_config.json",
+    "scheduler_config.json",
+    "tokenizer_config.json",
+];
+
+/// An entry of a DDUF file, as stored in the zip archive.
+#[derive(Debug, Clone)]
+pub struct DdufEntry {
+    pub name: String,
+    /// Offset of the entry data from the start of the file.
+    pub data_start: u64,
+    /// Uncompressed size of the entry.
+    pub size: u64,
+    pub compression: CompressionMethod,
+}
+
+impl DdufEntry {
+    /// Whether the entry data starts at a multiple of [`DDUF_ALIGNMENT`] bytes.
+    pub fn is_aligned(&self) -> bool {
+        self.data_start.is_multiple_of(DDUF_ALIGNMENT as u64)
+    }
+}
+
+/// List the entries of a DDUF model source, in the order they are stored.
+pub fn read_dduf_entries(source: &ModelSource) -> anyhow::Result<Vec<DdufEntry>> {
+    let ModelSource::Dduf { file, name: _ } = source else {
+        anyhow::bail!("expected dduf model source!");
+    };
+    let mut archive = ZipArchive::new(io::Cursor::new(&file.get_ref()[..]))?;
+    let mut entries = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive.by_index_raw(i)?;
+        entries.push(DdufEntry {
+            name: entry.name().to_string(),
+            data_start: entry.data_start(),
+            size: entry.size(),
+            compression: entry.compression(),
+        });
+    }
+    // The central directory may list the entries in any order.
+    entries.sort_by_key(|entry| entry.data_start);
+    Ok(entries)
+}
+
+/// Writes the entries of a DDUF file one at a time, stored uncompressed with their data aligned to
+/// [`DDUF_ALIGNMENT`] bytes. The first entry must be `model_index.json`.
+pub struct DdufWriter {
+    writer: ZipWriter<BufWriter<File>>,
+    entries: Vec<String>,
+}
+
+impl DdufWriter {
+    pub fn create(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(Self {
+            writer: ZipWriter::new(BufWriter::new(File::create(path.as_ref())?)),
+            entries: Vec::new(),
+        })
+    }
+
+    /// Start an entry of `size` bytes, the returned writer must be given exactly `size` bytes.
+    pub fn start_entry(&mut self, name: &str, size: u64) -> anyhow::Result<&mut impl Write> {
+        if self.entries.is_empty() && name != "model_index.json" {
+            anyhow::bail!(
+                "The first entry of a DDUF file must be `model_index.json`, got `{name}`."
+            );
+        }
+        let allowed = Path::new(name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| DDUF_ALLOWED_EXTENSIONS.contains(&ext));
+        if !allowed {
+            anyhow::bail!(
+                "Entry `{name}` does not have one of the extensions {DDUF_ALLOWED_EXTENSIONS:?}."
+            );
+        }
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .with_alignment(DDUF_ALIGNMENT)
+            .large_file(size >= u32::MAX as u64);
+        self.writer.start_file(name, options)?;
+        self.entries.push(name.to_string());
+        Ok(&mut self.writer)
+    }
+
+    /// Write the central directory, returning the names of the written entries.
+    pub fn finish(self) -> anyhow::Result<Vec<String>> {
+        self.writer.finish()?.flush()?;
+        Ok(self.entries)
+    }
+}
+
+/// Pack a diffusers model directory into a DDUF file at `output`, returning the names of the written entries.
+///
+/// - `model_index.json` is written first, followed by the files of each component directory.
+/// - Entries are stored uncompressed with their data aligned to [`DDUF_ALIGNMENT`] bytes.
+/// - Files with an extension not in [`DDUF_ALLOWED_EXTENSIONS`], hidden files, and files nested more than one
+///   directory deep are skipped.
+///
+/// Every component listed in `model_index.json` with a non-null class must have a directory which contains
+/// one of the [`DDUF_COMPONENT_CONFIGS`], otherwise no file is written.
+pub fn write_dduf(
+    model_dir: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+) -> anyhow::Result<Vec<String>> {
+    let entries = collect_dduf_entries(model_dir.as_ref())?;
+
+    let mut writer = DdufWriter::create(output)?;
+    for (name, path) in &entries {
+        let size = fs::metadata(path)?.len();
+        io::copy(&mut File::open(path)?, writer.start_entry(name, size)?)?;
+    }
+    writer.finish()
+}
+
+/// List the entries of the DDUF file for `model_dir`, in the order they should be written.
+fn collect_dduf_entries(model_dir: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let model_index_path = model_dir.join("model_index.json");
+    if !model_index_path.is_file() {
+        anyhow::bail!(
+            "Expected `model_index.json` file present in `{}`.",
+            model_dir.display()
+        );
+    }
+    let model_index: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(&fs::read_to_string(&model_index_path)?)?;
+
+    let mut components = BTreeMap::new();
+    for entry in fs::read_dir(model_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') {
+            let mut files = Vec::new();
+            for file in fs::read_dir(entry.path())? {
+                let file = file?;
+                let file_name = file.file_name().to_string_lossy().to_string();
+                let allowed = Path::new(&file_name)
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| DDUF_ALLOWED_EXTENSIONS.contains(&ext));
+                if file.file_type()?.is_file() && allowed && !file_name.starts_with('.') {
+                    files.push((format!("{name}/{file_name}"), file.path()));
+                }
+            }
+            files.sort();
+            components.insert(name, files);
+        }
+    }
+
+    for (component, value) in &model_index {
+        // Private keys such as `_class_name` and `_diffusers_version` are not components.
+        if component.starts_with('_') {
+            continue;
+        }
+        // Optional components which are not used are listed as `null` or `[null, null]`.
+        let required = match value {
+            serde_json::Value::Array(class) => class.iter().any(|x| !x.is_null()),
+            serde_json::Value::Null => false,
+            _ => continue,
+        };
+        if !required {
+            continue;
+        }
+        let Some(files) = components.get(component) else {
+            anyhow::bail!("Component `{component}` is listed in `model_index.json` but its directory is missing.");
+        };
+        let has_config = files.iter().any(|(name, _)| {
+            DDUF_COMPONENT_CONFIGS
+                .iter()
+                .any(|config| name == &format!("{component}/{config}"))
+        });
+        if !has_config {
+            anyhow::bail!(
+                "Component `{component}` must contain one of {DDUF_COMPONENT_CONFIGS:?}."
+            );
+        }
+    }
+
+    let mut entries = vec![("model_index.json".to_string(), model_index_path)];
+    entries.extend(components.into_values().flatten());
+    Ok(entries)
+}
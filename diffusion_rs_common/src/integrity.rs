@@ -0,0 +1,152 @@
+//! Integrity checks of model files, to detect truncated or corrupted downloads before they are loaded.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Cursor},
+    path::Path,
+};
+
+use memmap2::Mmap;
+use safetensors::tensor as st;
+use sha2::{Digest, Sha256};
+use zip::ZipArchive;
+
+use crate::{core::DType, FileData, ModelSource};
+
+/// Verify the integrity of a model file:
+///
+/// - DDUF entries are read through the zip archive, which checks their CRC32.
+/// - Files downloaded from the Hugging Face Hub are hashed and compared with the sha256 of the LFS object,
+///   which names the blob in the cache. Files which are not stored with LFS are not hashed.
+/// - The header of safetensors files is checked against the size of the file, see [`verify_safetensors`].
+pub fn verify_file(file: &FileData, source: &ModelSource) -> anyhow::Result<()> {
+    let is_safetensors = file.extension().is_some_and(|ext| ext == "safetensors");
+    match file {
+        FileData::Dduf { name, start, end } => {
+            let ModelSource::Dduf { file, name: _ } = source else {
+                anyhow::bail!("expected dduf model source!");
+            };
+            let mut archive = ZipArchive::new(Cursor::new(&file.get_ref()[..]))?;
+            let mut entry = archive.by_name(&name.to_string_lossy())?;
+            io::copy(&mut entry, &mut io::sink())
+                .map_err(|e| anyhow::anyhow!("the DDUF entry does not match its CRC32: {e}"))?;
+            if is_safetensors {
+                verify_safetensors(&file.get_ref()[*start..*end])?;
+            }
+        }
+        // Owned entries were read through the zip archive, which already checked their CRC32.
+        FileData::DdufOwned { name: _, data } => {
+            if is_safetensors {
+                verify_safetensors(data)?;
+            }
+        }
+        FileData::Header { .. } => {
+            anyhow::bail!("only the header of the file was read, it cannot be verified");
+        }
+        FileData::Path(path) => {
+            verify_hub_blob(path)?;
+            if is_safetensors {
+                let file = File::open(path)?;
+                let mmap = unsafe { Mmap::map(&file)? };
+                verify_safetensors(&mmap)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Hub files are symlinks to a blob named after the etag of the file, which is the sha256 for LFS files.
+fn verify_hub_blob(path: &Path) -> anyhow::Result<()> {
+    let blob = fs::canonicalize(path)?;
+    let Some(expected) = blob.file_name().and_then(|name| name.to_str()) else {
+        return Ok(());
+    };
+    if expected.len() != 64 || !expected.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Ok(());
+    }
+
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(&blob)?, &mut hasher)?;
+    let actual = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    if !actual.eq_ignore_ascii_case(expected) {
+        anyhow::bail!(
+            "sha256 mismatch, expected {expected} from the Hub but the file has {actual}. Delete `{}` to download it again.",
+            blob.display()
+        );
+    }
+    Ok(())
+}
+
+/// Check that the header of a safetensors file is consistent with its size: every tensor must have a known
+/// dtype, its data must match its shape and lie within the file, and the tensors must cover all of the data.
+pub fn verify_safetensors(buffer: &[u8]) -> anyhow::Result<()> {
+    if buffer.len() < 8 {
+        anyhow::bail!(
+            "the file is {} bytes, too small for a safetensors header",
+            buffer.len()
+        );
+    }
+    let n = u64::from_le_bytes(buffer[..8].try_into().unwrap()) as usize;
+    if n > buffer.len() - 8 {
+        anyhow::bail!(
+            "the header is {n} bytes but the file is only {} bytes, it may be truncated",
+            buffer.len()
+        );
+    }
+    let header: HashMap<String, serde_json::Value> = serde_json::from_slice(&buffer[8..8 + n])?;
+    let data_len = buffer.len() - 8 - n;
+
+    let mut tensors = Vec::new();
+    for (name, info) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let info: st::TensorInfo = serde_json::from_value(info)
+            .map_err(|e| anyhow::anyhow!("invalid header of tensor `{name}`: {e}"))?;
+        let (start, end) = info.data_offsets;
+        let dtype = DType::try_from(info.dtype)?;
+        let Some(expected) = info
+            .shape
+            .iter()
+            .try_fold(dtype.size_in_bytes(), |acc, &dim| acc.checked_mul(dim))
+        else {
+            anyhow::bail!(
+                "tensor `{name}` has a shape {:?} too large for its dtype {dtype:?}",
+                info.shape
+            );
+        };
+        if end < start || end - start != expected {
+            anyhow::bail!(
+                "tensor `{name}` has {} bytes of data but its shape {:?} and dtype {dtype:?} need {expected}",
+                end.saturating_sub(start),
+                info.shape
+            );
+        }
+        if end > data_len {
+            anyhow::bail!(
+                "tensor `{name}` ends at byte {end} but the file only has {data_len} bytes of tensor data, it may be truncated"
+            );
+        }
+        tensors.push((start, end, name));
+    }
+
+    tensors.sort();
+    let mut offset = 0;
+    for (start, end, name) in &tensors {
+        if *start != offset {
+            anyhow::bail!("tensor `{name}` starts at byte {start}, expected {offset}");
+        }
+        offset = *end;
+    }
+    if offset != data_len {
+        anyhow::bail!(
+            "the tensors cover {offset} bytes but the file has {data_len} bytes of tensor data"
+        );
+    }
+    Ok(())
+}
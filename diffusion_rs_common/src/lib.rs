@@ -1,3 +1,5 @@
+mod dduf;
+mod integrity;
 mod model_source;
 mod nn_wrap;
 mod progress;
@@ -15,11 +17,19 @@ pub mod cuda_kernels;
 #[cfg(feature = "metal")]
 pub mod metal_kernels;
 
+pub use dduf::{
+    read_dduf_entries, write_dduf, DdufEntry, DdufWriter, DDUF_ALIGNMENT, DDUF_ALLOWED_EXTENSIONS,
+    DDUF_COMPONENT_CONFIGS,
+};
+pub use integrity::{verify_file, verify_safetensors};
 pub use model_source::*;
 pub use nn_wrap::*;
 pub use progress::NiceProgressBar;
-pub use tokenizer::load_bpe_tokenizer;
+pub use safetensors::{read_safetensors_header, SafetensorsLayout, TensorHeader};
+pub use tokenizer::load_clip_tokenizer;
 pub use tokens::get_token;
 pub use tokens::TokenSource;
 pub use varbuilder::VarBuilder;
-pub use varbuilder_loading::from_mmaped_safetensors;
+pub use varbuilder_loading::{
+    from_mmaped_safetensors, from_mmaped_safetensors_lazy, open_pth_checkpoint,
+};
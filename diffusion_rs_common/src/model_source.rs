@@ -2,14 +2,17 @@ use std::{
     ffi::OsStr,
     fmt::{Debug, Display},
     fs::{self, File},
-    io::Cursor,
+    io::{Cursor, Read},
     path::PathBuf,
 };
 
-use crate::{get_token, TokenSource};
+use crate::{get_token, safetensors::MAX_HEADER_SIZE, TokenSource};
 use hf_hub::{
-    api::sync::{ApiBuilder, ApiRepo},
-    Repo, RepoType,
+    api::{
+        sync::{Api, ApiBuilder, ApiError, ApiRepo},
+        RepoInfo,
+    },
+    Cache, Repo, RepoType,
 };
 use memmap2::Mmap;
 use zip::ZipArchive;
@@ -84,11 +87,85 @@ impl ModelSource {
     }
 }
 
+/// A model repository on the Hugging Face Hub.
+pub struct HubRepo {
+    api: ApiRepo,
+    repo: Repo,
+    token: Option<String>,
+}
+
+impl HubRepo {
+    fn new(api: &Api, repo: Repo, token: Option<String>) -> Self {
+        Self {
+            api: api.repo(repo.clone()),
+            repo,
+            token,
+        }
+    }
+
+    /// Get a file from the cache, downloading it if necessary.
+    pub fn get(&self, name: &str) -> Result<PathBuf, ApiError> {
+        self.api.get(name)
+    }
+
+    /// The metadata of the repository, such as its files.
+    pub fn info(&self) -> Result<RepoInfo, ApiError> {
+        self.api.info()
+    }
+
+    /// Fetch the bytes `start..end` of a file with an HTTP range request.
+    fn get_range(&self, name: &str, start: usize, end: usize) -> anyhow::Result<Vec<u8>> {
+        let mut request =
+            ureq::get(&self.api.url(name)).set("Range", &format!("bytes={start}-{}", end - 1));
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        let response = request
+            .call()
+            .map_err(|e| anyhow::anyhow!("Could not fetch `{name}`: {e}"))?;
+        // A server ignoring the range returns the whole file, of which only the start is read.
+        let skip = if response.status() == 206 { 0 } else { start };
+        let mut data = Vec::new();
+        response
+            .into_reader()
+            .take((skip + end - start) as u64)
+            .read_to_end(&mut data)?;
+        if data.len() != skip + end - start {
+            anyhow::bail!("`{name}` is too short, expected at least {end} bytes.");
+        }
+        Ok(data.split_off(skip))
+    }
+
+    /// The path of a file in the cache, without downloading it.
+    fn cached(&self, name: &str) -> Option<PathBuf> {
+        Cache::default().repo(self.repo.clone()).get(name)
+    }
+
+    /// Read the header of a safetensors file. A file in the cache is returned as is, otherwise only its header
+    /// is fetched.
+    fn read_safetensors_header(&self, name: &str) -> anyhow::Result<FileData> {
+        if let Some(path) = self.cached(name) {
+            return Ok(FileData::Path(path));
+        }
+        let len = self.get_range(name, 0, 8)?;
+        let n = u64::from_le_bytes(len.as_slice().try_into().unwrap());
+        if n > MAX_HEADER_SIZE as u64 {
+            anyhow::bail!("Invalid safetensors header length {n} for `{name}`.");
+        }
+        let mut data = len;
+        data.extend(self.get_range(name, 8, 8 + n as usize)?);
+        Ok(FileData::Header {
+            name: PathBuf::from(name),
+            data,
+        })
+    }
+}
+
 pub enum FileLoader<'a> {
-    Api(Box<ApiRepo>),
+    Api(Box<HubRepo>),
     ApiWithTransformer {
-        base: Box<ApiRepo>,
-        transformer: Box<ApiRepo>,
+        base: Box<HubRepo>,
+        transformer: Box<HubRepo>,
     },
     Dduf(ZipArchive<&'a mut Cursor<Mmap>>),
 }
@@ -102,16 +179,17 @@ impl<'a> FileLoader<'a> {
     ) -> anyhow::Result<Self> {
         match source {
             ModelSource::ModelId(model_id) => {
+                let token = get_token(&token)?;
                 let api_builder = ApiBuilder::new()
                     .with_progress(!silent)
-                    .with_token(get_token(&token)?)
+                    .with_token(token.clone())
                     .build()?;
                 let revision = revision.unwrap_or("main".to_string());
-                let api = api_builder.repo(Repo::with_revision(
-                    model_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
+                let api = HubRepo::new(
+                    &api_builder,
+                    Repo::with_revision(model_id.clone(), RepoType::Model, revision.clone()),
+                    token,
+                );
 
                 Ok(Self::Api(Box::new(api)))
             }
@@ -120,21 +198,26 @@ impl<'a> FileLoader<'a> {
                 model_id,
                 transformer_model_id,
             } => {
+                let token = get_token(&token)?;
                 let api_builder = ApiBuilder::new()
                     .with_progress(!silent)
-                    .with_token(get_token(&token)?)
+                    .with_token(token.clone())
                     .build()?;
                 let revision = revision.unwrap_or("main".to_string());
-                let api = api_builder.repo(Repo::with_revision(
-                    model_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
-                let transformer_api = api_builder.repo(Repo::with_revision(
-                    transformer_model_id.clone(),
-                    RepoType::Model,
-                    revision.clone(),
-                ));
+                let api = HubRepo::new(
+                    &api_builder,
+                    Repo::with_revision(model_id.clone(), RepoType::Model, revision.clone()),
+                    token.clone(),
+                );
+                let transformer_api = HubRepo::new(
+                    &api_builder,
+                    Repo::with_revision(
+                        transformer_model_id.clone(),
+                        RepoType::Model,
+                        revision.clone(),
+                    ),
+                    token,
+                );
 
                 Ok(Self::ApiWithTransformer {
                     base: Box::new(api),
@@ -233,6 +316,51 @@ impl<'a> FileLoader<'a> {
         }
     }
 
+    /// Read the header of a safetensors file, to read the dtypes and shapes of its tensors without loading them.
+    ///
+    /// - If loading from a DDUF file, this is equivalent to `read_file`
+    /// - For non-DDUF model sources, files which are not in the cache are not downloaded: only their header is
+    ///   fetched, with HTTP range requests
+    pub fn read_safetensors_header(
+        &mut self,
+        name: &str,
+        from_transformer: bool,
+    ) -> anyhow::Result<FileData> {
+        match (self, from_transformer) {
+            (Self::Api(api), false)
+            | (Self::ApiWithTransformer { base: api, .. }, false)
+            | (
+                Self::ApiWithTransformer {
+                    transformer: api, ..
+                },
+                true,
+            ) => api.read_safetensors_header(name),
+            (Self::Api(_), true) => anyhow::bail!("This model source has no transformer files."),
+            (this @ Self::Dduf(_), _) => this.read_file(name, from_transformer),
+        }
+    }
+
+    /// Read a file without downloading it: files of non-DDUF model sources are only returned if they are in the
+    /// cache.
+    pub fn read_cached_file(
+        &mut self,
+        name: &str,
+        from_transformer: bool,
+    ) -> anyhow::Result<Option<FileData>> {
+        match (self, from_transformer) {
+            (Self::Api(api), false)
+            | (Self::ApiWithTransformer { base: api, .. }, false)
+            | (
+                Self::ApiWithTransformer {
+                    transformer: api, ..
+                },
+                true,
+            ) => Ok(api.cached(name).map(FileData::Path)),
+            (Self::Api(_), true) => anyhow::bail!("This model source has no transformer files."),
+            (this @ Self::Dduf(_), _) => this.read_file(name, from_transformer).map(Some),
+        }
+    }
+
     /// Read a file, always returning owned data.
     ///
     /// - If loading from a DDUF file, this copies the file data.
@@ -258,6 +386,7 @@ impl<'a> FileLoader<'a> {
     }
 }
 
+#[derive(Clone)]
 pub enum FileData {
     Path(PathBuf),
     Dduf {
@@ -269,6 +398,12 @@ pub enum FileData {
         name: PathBuf,
         data: Vec<u8>,
     },
+    /// Only the header of a safetensors file, see [`FileLoader::read_safetensors_header`]. The tensor data
+    /// cannot be read.
+    Header {
+        name: PathBuf,
+        data: Vec<u8>,
+    },
 }
 
 impl Debug for FileData {
@@ -281,6 +416,7 @@ impl Debug for FileData {
                 end: _,
             } => write!(f, "dduf: {}", name.display()),
             Self::DdufOwned { name, data: _ } => write!(f, "dduf owned: {}", name.display()),
+            Self::Header { name, data: _ } => write!(f, "header: {}", name.display()),
         }
     }
 }
@@ -300,6 +436,29 @@ impl FileData {
                 Ok(String::from_utf8(file.get_ref()[*start..*end].to_vec())?)
             }
             Self::DdufOwned { name: _, data } => Ok(String::from_utf8(data.to_vec())?),
+            Self::Header { name, data: _ } => {
+                anyhow::bail!("only the header of `{}` was read!", name.display());
+            }
+        }
+    }
+
+    pub fn read_to_bytes(&self, src: &ModelSource) -> anyhow::Result<Vec<u8>> {
+        match self {
+            Self::Path(p) => Ok(fs::read(p)?),
+            Self::Dduf {
+                name: _,
+                start,
+                end,
+            } => {
+                let ModelSource::Dduf { file, name: _ } = src else {
+                    anyhow::bail!("expected dduf model source!");
+                };
+                Ok(file.get_ref()[*start..*end].to_vec())
+            }
+            Self::DdufOwned { name: _, data } => Ok(data.clone()),
+            Self::Header { name, data: _ } => {
+                anyhow::bail!("only the header of `{}` was read!", name.display());
+            }
         }
     }
 
@@ -310,6 +469,21 @@ impl FileData {
                 anyhow::bail!("dduf file data is not owned !");
             }
             Self::DdufOwned { name: _, data } => Ok(String::from_utf8(data.to_vec())?),
+            Self::Header { name, data: _ } => {
+                anyhow::bail!("only the header of `{}` was read!", name.display());
+            }
+        }
+    }
+
+    pub fn file_name(&self) -> Option<&OsStr> {
+        match self {
+            Self::Path(p) => p.file_name(),
+            Self::Dduf {
+                name,
+                start: _,
+                end: _,
+            } => name.file_name(),
+            Self::DdufOwned { name, data: _ } | Self::Header { name, data: _ } => name.file_name(),
         }
     }
 
@@ -321,7 +495,7 @@ impl FileData {
                 start: _,
                 end: _,
             } => name.extension(),
-            Self::DdufOwned { name, data: _ } => name.extension(),
+            Self::DdufOwned { name, data: _ } | Self::Header { name, data: _ } => name.extension(),
         }
     }
 }
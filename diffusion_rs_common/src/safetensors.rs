@@ -1,5 +1,11 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
 use crate::core::safetensors::Load;
-use crate::core::{Device, Error, Result, Tensor};
+use crate::core::{DType, Device, Error, Result, Tensor};
+use crate::{FileData, ModelSource};
 use safetensors::tensor as st;
 use safetensors::tensor::SafeTensors;
 
@@ -25,3 +31,201 @@ impl<'a> BytesSafetensors<'a> {
         Ok(self.safetensors.tensor(name)?)
     }
 }
+
+#[derive(yoke::Yokeable)]
+struct SafeTensors_<'a>(SafeTensors<'a>);
+
+/// A safetensors entry of a DDUF file, read in place from the memory-mapped archive.
+///
+/// Unlike [`BytesSafetensors`], this owns a handle to the model source so it can outlive the loading
+/// scope and be used to read tensors lazily.
+pub struct DdufSafetensors {
+    safetensors: yoke::Yoke<SafeTensors_<'static>, Arc<ModelSource>>,
+}
+
+impl DdufSafetensors {
+    /// Deserialize the safetensors header of the entry stored at `start..end` in the DDUF file.
+    pub fn new(src: Arc<ModelSource>, start: usize, end: usize) -> Result<Self> {
+        let safetensors =
+            yoke::Yoke::<SafeTensors_<'static>, Arc<ModelSource>>::try_attach_to_cart(
+                src,
+                |src: &ModelSource| {
+                    let ModelSource::Dduf { file, name: _ } = src else {
+                        crate::bail!("expected dduf model source!");
+                    };
+                    let st = safetensors::SafeTensors::deserialize(&file.get_ref()[start..end])?;
+                    Ok::<_, Error>(SafeTensors_(st))
+                },
+            )?;
+        Ok(Self { safetensors })
+    }
+
+    pub fn load(&self, name: &str, dev: &Device) -> Result<Tensor> {
+        self.get(name)?.load(dev)
+    }
+
+    pub fn tensors(&self) -> Vec<(String, st::TensorView<'_>)> {
+        self.safetensors.get().0.tensors()
+    }
+
+    pub fn get(&self, name: &str) -> Result<st::TensorView<'_>> {
+        Ok(self.safetensors.get().0.tensor(name)?)
+    }
+}
+
+/// Maximum size of a safetensors header, matching the limit of the `safetensors` crate.
+pub(crate) const MAX_HEADER_SIZE: usize = 100_000_000;
+
+/// Dtype and shape of a tensor, as declared in a safetensors header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TensorHeader {
+    pub dtype: DType,
+    pub shape: Vec<usize>,
+}
+
+impl TensorHeader {
+    pub fn elem_count(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.elem_count() * self.dtype.size_in_bytes()
+    }
+}
+
+fn header_bytes(buffer: &[u8]) -> Result<&[u8]> {
+    if buffer.len() < 8 {
+        crate::bail!("safetensors file is too small to contain a header");
+    }
+    let n = u64::from_le_bytes(buffer[..8].try_into().unwrap()) as usize;
+    if n > MAX_HEADER_SIZE || n + 8 > buffer.len() {
+        crate::bail!("invalid safetensors header length {n}");
+    }
+    Ok(&buffer[8..8 + n])
+}
+
+/// Read the tensor dtypes and shapes of a safetensors file, without reading the tensor data.
+pub fn read_safetensors_header(
+    file: &FileData,
+    src: &ModelSource,
+) -> Result<HashMap<String, TensorHeader>> {
+    let header = match file {
+        FileData::Path(path) => {
+            let mut file = File::open(path)?;
+            let mut n = [0u8; 8];
+            file.read_exact(&mut n)?;
+            let n = u64::from_le_bytes(n) as usize;
+            if n > MAX_HEADER_SIZE {
+                crate::bail!("invalid safetensors header length {n}");
+            }
+            let mut header = vec![0u8; n];
+            file.read_exact(&mut header)?;
+            header
+        }
+        FileData::Dduf {
+            name: _,
+            start,
+            end,
+        } => {
+            let ModelSource::Dduf { file, name: _ } = src else {
+                crate::bail!("expected dduf model source!");
+            };
+            header_bytes(&file.get_ref()[*start..*end])?.to_vec()
+        }
+        FileData::DdufOwned { name: _, data } | FileData::Header { name: _, data } => {
+            header_bytes(data)?.to_vec()
+        }
+    };
+
+    let header: HashMap<String, serde_json::Value> =
+        serde_json::from_slice(&header).map_err(Error::wrap)?;
+    let mut tensors = HashMap::new();
+    for (name, info) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let info: st::TensorInfo = serde_json::from_value(info).map_err(Error::wrap)?;
+        tensors.insert(
+            name,
+            TensorHeader {
+                dtype: DType::try_from(info.dtype)?,
+                shape: info.shape,
+            },
+        );
+    }
+    Ok(tensors)
+}
+
+/// A safetensors file which is written one tensor at a time, so that only a single tensor is held in memory.
+pub struct SafetensorsLayout {
+    header: Vec<u8>,
+    tensors: Vec<(String, TensorHeader)>,
+}
+
+impl SafetensorsLayout {
+    /// Lay out the tensors in the order given, with a `format` of `pt` in the metadata.
+    pub fn new(tensors: Vec<(String, TensorHeader)>) -> Result<Self> {
+        let mut header = serde_json::Map::new();
+        header.insert(
+            "__metadata__".to_string(),
+            serde_json::json!({ "format": "pt" }),
+        );
+        let mut offset = 0;
+        for (name, tensor) in &tensors {
+            let info = st::TensorInfo {
+                dtype: tensor.dtype.into(),
+                shape: tensor.shape.clone(),
+                data_offsets: (offset, offset + tensor.size_in_bytes()),
+            };
+            offset += tensor.size_in_bytes();
+            header.insert(
+                name.clone(),
+                serde_json::to_value(info).map_err(Error::wrap)?,
+            );
+        }
+
+        let mut json = serde_json::to_vec(&header).map_err(Error::wrap)?;
+        // The tensor data must start at a multiple of 8 bytes, the header is padded with spaces.
+        json.resize(json.len().next_multiple_of(8), b' ');
+        let mut header = (json.len() as u64).to_le_bytes().to_vec();
+        header.extend(json);
+        Ok(Self { header, tensors })
+    }
+
+    pub fn tensors(&self) -> &[(String, TensorHeader)] {
+        &self.tensors
+    }
+
+    /// Total size of the file, in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        self.header.len()
+            + self
+                .tensors
+                .iter()
+                .map(|(_, tensor)| tensor.size_in_bytes())
+                .sum::<usize>()
+    }
+
+    /// Write the file, calling `load` for each tensor in order. The loaded tensors must match the layout.
+    pub fn write(
+        &self,
+        writer: &mut dyn Write,
+        mut load: impl FnMut(&str, &TensorHeader) -> Result<Tensor>,
+    ) -> Result<()> {
+        writer.write_all(&self.header)?;
+        for (name, header) in &self.tensors {
+            let tensor = load(name, header)?;
+            if tensor.dtype() != header.dtype || tensor.dims() != header.shape {
+                crate::bail!(
+                    "tensor `{name}` has dtype {:?} and shape {:?}, expected {:?} and {:?}",
+                    tensor.dtype(),
+                    tensor.dims(),
+                    header.dtype,
+                    header.shape
+                );
+            }
+            writer.write_all(&st::View::data(&tensor))?;
+        }
+        Ok(())
+    }
+}
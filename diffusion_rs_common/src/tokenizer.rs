@@ -1,10 +1,37 @@
 use std::collections::HashMap;
 
-use tokenizers::{models::bpe::BPE, ModelWrapper, Tokenizer};
+use tokenizers::{
+    decoders::{byte_level::ByteLevel as ByteLevelDecoder, sequence::Sequence as DecoderSequence},
+    models::bpe::BPE,
+    normalizers::{
+        replace::ReplacePattern, unicode::NFC, utils::Lowercase,
+        utils::Sequence as NormalizerSequence, Replace,
+    },
+    pre_tokenizers::{
+        byte_level::ByteLevel, sequence::Sequence as PreTokenizerSequence, split::Split,
+        split::SplitPattern,
+    },
+    processors::roberta::RobertaProcessing,
+    AddedToken, SplitDelimiterBehavior, Tokenizer,
+};
 
 use crate::{FileData, ModelSource};
 
-pub fn load_bpe_tokenizer(
+const CLIP_BOS_TOKEN: &str = "<|startoftext|>";
+const CLIP_EOS_TOKEN: &str = "<|endoftext|>";
+
+/// The words and punctuation CLIP splits the text into before the BPE, as in the reference implementation.
+const CLIP_PATTERN: &str =
+    r"<\|startoftext\|>|<\|endoftext\|>|'s|'t|'re|'ve|'m|'ll|'d|[\p{L}]+|[\p{N}]|[^\s\p{L}\p{N}]+";
+
+/// Number of merges of the CLIP vocabulary: the vocabulary holds the 256 bytes, their end of word variants,
+/// a token for each merge and the 2 special tokens.
+const CLIP_NUM_MERGES: usize = 49152 - 256 - 2;
+
+/// Build the tokenizer of CLIP from the `vocab.json` and `merges.txt` of a HF `CLIPTokenizer`, producing the
+/// same tokens: the text is whitespace-collapsed and lowercased, words end with `</w>`, and the tokens are
+/// wrapped in `<|startoftext|>` and `<|endoftext|>`.
+pub fn load_clip_tokenizer(
     vocab_file: &FileData,
     merges_file: &FileData,
     src: &ModelSource,
@@ -17,7 +44,59 @@ pub fn load_bpe_tokenizer(
         .map(|x| x.split(' ').collect::<Vec<_>>())
         .filter(|x| x.len() == 2)
         .map(|x| (x[0].to_string(), x[1].to_string()))
+        .take(CLIP_NUM_MERGES)
         .collect();
+    let token_id = |token: &str| {
+        vocab
+            .get(token)
+            .copied()
+            .ok_or_else(|| anyhow::anyhow!("`vocab.json` has no `{token}` token."))
+    };
+    let bos_id = token_id(CLIP_BOS_TOKEN)?;
+    let eos_id = token_id(CLIP_EOS_TOKEN)?;
 
-    Ok(Tokenizer::new(ModelWrapper::BPE(BPE::new(vocab, merges))))
+    let bpe = BPE::builder()
+        .vocab_and_merges(vocab, merges)
+        .unk_token(CLIP_EOS_TOKEN.to_string())
+        .end_of_word_suffix("</w>".to_string())
+        .build()
+        .map_err(anyhow::Error::msg)?;
+    let mut tokenizer = Tokenizer::new(bpe);
+    tokenizer
+        .with_normalizer(Some(NormalizerSequence::new(vec![
+            NFC.into(),
+            Replace::new(ReplacePattern::Regex(r"\s+".to_string()), " ")
+                .map_err(anyhow::Error::msg)?
+                .into(),
+            Lowercase.into(),
+        ])))
+        .with_pre_tokenizer(Some(PreTokenizerSequence::new(vec![
+            Split::new(
+                SplitPattern::Regex(CLIP_PATTERN.to_string()),
+                SplitDelimiterBehavior::Removed,
+                true,
+            )
+            .map_err(anyhow::Error::msg)?
+            .into(),
+            ByteLevel::new(false, true, false).into(),
+        ])))
+        .with_post_processor(Some(
+            RobertaProcessing::new(
+                (CLIP_EOS_TOKEN.to_string(), eos_id),
+                (CLIP_BOS_TOKEN.to_string(), bos_id),
+            )
+            .trim_offsets(false)
+            .add_prefix_space(false),
+        ))
+        .with_decoder(Some(DecoderSequence::new(vec![
+            ByteLevelDecoder::default().into(),
+            Replace::new("</w>", " ")
+                .map_err(anyhow::Error::msg)?
+                .into(),
+        ])));
+    tokenizer.add_special_tokens(&[
+        AddedToken::from(CLIP_BOS_TOKEN, true),
+        AddedToken::from(CLIP_EOS_TOKEN, true),
+    ]);
+    Ok(tokenizer)
 }
@@ -2,16 +2,19 @@
 
 use std::{
     collections::HashMap,
+    path::Path,
     sync::Arc,
     thread::{self, JoinHandle},
 };
 
 use crate::{
-    core::{safetensors::MmapedSafetensors, DType, Device, Result, Tensor},
+    core::{
+        pickle::PthTensors, safetensors::MmapedSafetensors, DType, Device, Result, Shape, Tensor,
+    },
     ModelSource,
 };
 use crate::{
-    safetensors::BytesSafetensors,
+    safetensors::{BytesSafetensors, DdufSafetensors},
     varbuilder::{SimpleBackend, VarBuilderArgs},
     FileData, VarBuilder,
 };
@@ -53,7 +56,38 @@ impl TensorLoaderBackend for BytesSafetensorBackend<'_> {
     }
 }
 
+struct PickleBackend(PthTensors);
+
+impl TensorLoaderBackend for PickleBackend {
+    fn get_names(&self) -> Vec<String> {
+        self.0.tensor_infos().keys().cloned().collect::<Vec<_>>()
+    }
+    fn load_name(&self, name: &str, device: &Device, _dtype: Option<DType>) -> Result<Tensor> {
+        let tensor = self.0.get(name)?.ok_or_else(|| {
+            crate::core::Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt()
+        })?;
+        tensor.to_device(device)
+    }
+}
+
+/// Open a PyTorch checkpoint (`.bin`, `.pt`, `.pth` or `.ckpt`) saved in the zip format.
+///
+/// Checkpoints which wrap the weights in a `state_dict` entry, as most `.ckpt` files do, are unwrapped. The
+/// pickle data is interpreted without executing any code, and unsupported op-codes are rejected.
+pub fn open_pth_checkpoint(path: &Path) -> Result<PthTensors> {
+    let tensors = PthTensors::new(path, None)?;
+    if !tensors.tensor_infos().is_empty() {
+        return Ok(tensors);
+    }
+    PthTensors::new(path, Some("state_dict"))
+}
+
 /// Load tensors into a VarBuilder backed by a VarMap using MmapedSafetensors.
+///
+/// PyTorch checkpoints (`.bin`, `.pt`, `.pth` or `.ckpt`) are also supported, see [`open_pth_checkpoint`].
 /// Set `silent` to not show a progress bar.
 ///
 /// # Predicate semantics:
@@ -93,6 +127,103 @@ pub fn from_mmaped_safetensors<'a>(
     ))
 }
 
+/// Routes each tensor name to the lazily-read file which contains it.
+struct LazySafetensorsBackend {
+    backends: Vec<Box<dyn SimpleBackend>>,
+    routing: HashMap<String, usize>,
+}
+
+impl LazySafetensorsBackend {
+    fn backend(&self, name: &str) -> Result<&dyn SimpleBackend> {
+        let index = self.routing.get(name).ok_or_else(|| {
+            crate::core::Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt()
+        })?;
+        Ok(self.backends[*index].as_ref())
+    }
+}
+
+impl SimpleBackend for LazySafetensorsBackend {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::nn::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        self.backend(name)?.get(s, name, h, dtype, dev)
+    }
+
+    fn get_unchecked(&self, name: &str, dtype: DType, dev: &Device) -> Result<Tensor> {
+        self.backend(name)?.get_unchecked(name, dtype, dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        self.routing.contains_key(name)
+    }
+}
+
+/// Create a VarBuilder which reads tensors from the memory-mapped safetensors files (or DDUF entries) each
+/// time they are requested, instead of loading all of them up front like [`from_mmaped_safetensors`].
+///
+/// Tensors are materialized on `device` when retrieved, so the resident memory is only that of the
+/// tensors which are currently held by the caller.
+pub fn from_mmaped_safetensors_lazy(
+    paths: Vec<FileData>,
+    dtype: Option<DType>,
+    device: &Device,
+    src: Arc<ModelSource>,
+) -> Result<VarBuilder<'static>> {
+    let mut backends: Vec<Box<dyn SimpleBackend>> = Vec::new();
+    let mut routing = HashMap::new();
+
+    for path in paths {
+        match path.extension().and_then(|x| x.to_str()) {
+            Some("safetensors") => (),
+            other => {
+                crate::bail!("Unexpected extension `{other:?}`, lazy loading requires safetensors.")
+            }
+        }
+        let (names, backend): (Vec<String>, Box<dyn SimpleBackend>) = match path {
+            FileData::Dduf {
+                name: _,
+                start,
+                end,
+            } => {
+                let st = DdufSafetensors::new(src.clone(), start, end)?;
+                let names = st.tensors().into_iter().map(|(name, _)| name).collect();
+                (names, Box::new(st))
+            }
+            FileData::DdufOwned { name: _, data } => {
+                let st = crate::core::safetensors::BufferedSafetensors::new(data)?;
+                let names = st.tensors().into_iter().map(|(name, _)| name).collect();
+                (names, Box::new(st))
+            }
+            FileData::Path(path) => {
+                let st = unsafe { MmapedSafetensors::new(path)? };
+                let names = st.tensors().into_iter().map(|(name, _)| name).collect();
+                (names, Box::new(st))
+            }
+            FileData::Header { .. } => {
+                crate::bail!("Only the header of {path:?} was read, its tensors cannot be loaded.")
+            }
+        };
+        for name in names {
+            routing.insert(name, backends.len());
+        }
+        backends.push(backend);
+    }
+
+    Ok(VarBuilder::from_backend(
+        Box::new(LazySafetensorsBackend { backends, routing }),
+        dtype.unwrap_or(DType::BF16),
+        device.clone(),
+    ))
+}
+
 trait LoadTensors {
     fn load_tensors_from_path(
         &self,
@@ -121,6 +252,18 @@ trait LoadTensors {
                 FileData::Path(path) => {Box::new(SafetensorBackend(unsafe {
                     crate::core::safetensors::MmapedSafetensors::new(path)?
                 }))}
+                FileData::Header { .. } => {
+                    crate::bail!("Only the header of {path:?} was read, its tensors cannot be loaded.")
+                }
+            },
+            "bin" | "pt" | "pth" | "ckpt" => match path {
+                FileData::Path(path) => Box::new(PickleBackend(open_pth_checkpoint(path)?)),
+                FileData::Dduf { .. } | FileData::DdufOwned { .. } => {
+                    crate::bail!("DDUF files may only contain safetensors weights, found {path:?}.")
+                }
+                FileData::Header { .. } => {
+                    crate::bail!("Only the header of {path:?} was read, its tensors cannot be loaded.")
+                }
             },
             other => crate::bail!("Unexpected extension `{other}`, this should have been handles by `get_model_paths`."),
         };
@@ -5,7 +5,7 @@
 //! ```rust,no_run
 //! use std::time::Instant;
 //!
-//! use diffusion_rs_core::{DiffusionGenerationParams, ModelSource, ModelDType, Offloading, Pipeline, TokenSource};
+//! use diffusion_rs_core::{DeviceSpec, DiffusionGenerationParams, ModelSource, ModelDType, Offloading, Pipeline, PlacementMap, TokenSource};
 //!
 //! let pipeline = Pipeline::load(
 //!     ModelSource::dduf("FLUX.1-dev-Q4-bnb.dduf")?,
@@ -13,7 +13,10 @@
 //!     TokenSource::CacheToken,
 //!     None,
 //!     None,
+//!     DeviceSpec::Auto,
 //!     &ModelDType::Auto,
+//!     &PlacementMap::new(),
+//!     false,
 //! )?;
 //!
 //! let start = Instant::now();
@@ -25,6 +28,15 @@
 //!         width: 1280,
 //!         num_steps: 50,
 //!         guidance_scale: 3.5,
+//!         negative_prompt: None,
+//!         reference_images: Vec::new(),
+//!         image: None,
+//!         mask_image: None,
+//!         control_image: None,
+//!         controlnet_conditions: Vec::new(),
+//!         ip_adapter_images: Vec::new(),
+//!         redux_image: None,
+//!         max_sequence_length: None,
 //!     },
 //! )?;
 //!
@@ -40,6 +52,18 @@ mod models;
 mod pipelines;
 mod util;
 
-pub use diffusion_rs_common::{ModelSource, TokenSource};
-pub use pipelines::{DiffusionGenerationParams, Offloading, Pipeline};
-pub use util::{ModelDType, TryIntoDType};
+pub use diffusion_rs_common::{write_dduf, ModelSource, TokenSource};
+pub use models::{
+    clip_image_to_tensor, ClipTextConfig, ClipTextModelWithProjection, ClipTextOutput,
+    ClipTextTransformer, ClipVisionConfig, ClipVisionOutput, ClipVisionTransformer,
+};
+pub use pipelines::{
+    register_loader, registered_loaders, ComponentClass, ComponentElem, ComponentKind,
+    ComponentName, ComponentPlacement, ComponentPlan, ComponentReport, ComponentResidence,
+    ControlNetCondition, ControlPreprocessor, DdufEntryReport, DiffusionGenerationParams,
+    FileReport, InspectCheck, InspectReport, IpAdapterImage, LoadPlan, Loader, ModelIndex,
+    ModelPipeline, Offloading, Pipeline, PlacementMap, PlanParams, PromptCacheStats,
+    PromptEmbeddings, Prompts, ReduxImage, ResolvedPlacement, SaveFormat, WeightsReport,
+    DEFAULT_DISK_OFFLOADING_BUDGET,
+};
+pub use util::{DeviceSpec, ModelDType, TryIntoDType};
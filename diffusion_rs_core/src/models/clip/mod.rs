@@ -1,3 +1,6 @@
 mod text;
+mod vision;
 
-pub use text::{ClipTextConfig, ClipTextTransformer};
+pub(crate) use text::{Activation, ClipEncoder, ClipEncoderConfig};
+pub use text::{ClipTextConfig, ClipTextModelWithProjection, ClipTextOutput, ClipTextTransformer};
+pub use vision::{clip_image_to_tensor, ClipVisionConfig, ClipVisionOutput, ClipVisionTransformer};
@@ -8,12 +8,18 @@ use serde::Deserialize;
 pub enum Activation {
     #[serde(rename = "quick_gelu")]
     QuickGelu,
+    #[serde(rename = "gelu")]
+    Gelu,
+    #[serde(rename = "gelu_pytorch_tanh")]
+    GeluPytorchTanh,
 }
 
 impl Module for Activation {
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         match self {
             Activation::QuickGelu => xs * sigmoid(&(xs * 1.702f64)?)?,
+            Activation::Gelu => xs.gelu_erf(),
+            Activation::GeluPytorchTanh => xs.gelu(),
         }
     }
 }
@@ -21,6 +27,8 @@ impl Module for Activation {
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClipTextConfig {
     pub vocab_size: usize,
+    /// Width of the transformer, which is `projection_dim` if absent as in older configs.
+    pub hidden_size: Option<usize>,
     pub projection_dim: usize,
     pub hidden_act: Activation,
     pub intermediate_size: usize,
@@ -29,6 +37,34 @@ pub struct ClipTextConfig {
     pub num_attention_heads: usize,
 }
 
+impl ClipTextConfig {
+    pub fn hidden_size(&self) -> usize {
+        self.hidden_size.unwrap_or(self.projection_dim)
+    }
+
+    fn encoder_config(&self) -> ClipEncoderConfig {
+        ClipEncoderConfig {
+            hidden_size: self.hidden_size(),
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            hidden_act: self.hidden_act,
+            layer_norm_eps: 1e-5,
+        }
+    }
+}
+
+/// The dimensions of the transformer encoder shared by the CLIP text and vision towers, and SigLIP.
+#[derive(Debug, Clone)]
+pub(crate) struct ClipEncoderConfig {
+    pub(crate) hidden_size: usize,
+    pub(crate) intermediate_size: usize,
+    pub(crate) num_hidden_layers: usize,
+    pub(crate) num_attention_heads: usize,
+    pub(crate) hidden_act: Activation,
+    pub(crate) layer_norm_eps: f64,
+}
+
 // ClipTextEmbeddings mostly based on the existing implementation in the stable diffision model.
 // TODO rewrite to be more similar to https://github.com/huggingface/transformers/blob/f6fa0f0bf0796ac66f201f23bdb8585de1609add/src/transformers/models/clip/modeling_clip.py#L142
 #[derive(Clone, Debug)]
@@ -42,12 +78,12 @@ impl ClipTextEmbeddings {
     fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
         let token_embedding = diffusion_rs_common::embedding(
             c.vocab_size,
-            c.projection_dim,
+            c.hidden_size(),
             vs.pp("token_embedding"),
         )?;
         let position_embedding = diffusion_rs_common::embedding(
             c.max_position_embeddings,
-            c.projection_dim,
+            c.hidden_size(),
             vs.pp("position_embedding"),
         )?;
         let position_ids =
@@ -82,8 +118,8 @@ struct ClipAttention {
 }
 
 impl ClipAttention {
-    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
-        let projection_dim = c.projection_dim;
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
+        let projection_dim = c.hidden_size;
         let num_attention_heads = c.num_attention_heads;
         let k_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("k_proj"))?;
         let v_proj = diffusion_rs_common::linear(projection_dim, projection_dim, vs.pp("v_proj"))?;
@@ -160,9 +196,9 @@ struct ClipMlp {
 }
 
 impl ClipMlp {
-    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
-        let fc1 = diffusion_rs_common::linear(c.projection_dim, c.intermediate_size, vs.pp("fc1"))?;
-        let fc2 = diffusion_rs_common::linear(c.intermediate_size, c.projection_dim, vs.pp("fc2"))?;
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
+        let fc1 = diffusion_rs_common::linear(c.hidden_size, c.intermediate_size, vs.pp("fc1"))?;
+        let fc2 = diffusion_rs_common::linear(c.intermediate_size, c.hidden_size, vs.pp("fc2"))?;
 
         Ok(ClipMlp {
             fc1,
@@ -188,13 +224,13 @@ struct ClipEncoderLayer {
 }
 
 impl ClipEncoderLayer {
-    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
         let self_attn = ClipAttention::new(vs.pp("self_attn"), c)?;
         let layer_norm1 =
-            diffusion_rs_common::layer_norm(c.projection_dim, 1e-5, vs.pp("layer_norm1"))?;
+            diffusion_rs_common::layer_norm(c.hidden_size, c.layer_norm_eps, vs.pp("layer_norm1"))?;
         let mlp = ClipMlp::new(vs.pp("mlp"), c)?;
         let layer_norm2 =
-            diffusion_rs_common::layer_norm(c.projection_dim, 1e-5, vs.pp("layer_norm2"))?;
+            diffusion_rs_common::layer_norm(c.hidden_size, c.layer_norm_eps, vs.pp("layer_norm2"))?;
 
         Ok(ClipEncoderLayer {
             self_attn,
@@ -218,12 +254,12 @@ impl ClipEncoderLayer {
 }
 
 #[derive(Clone, Debug)]
-struct ClipEncoder {
+pub(crate) struct ClipEncoder {
     layers: Vec<ClipEncoderLayer>,
 }
 
 impl ClipEncoder {
-    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipEncoderConfig) -> Result<Self> {
         let vs = vs.pp("layers");
         let mut layers: Vec<ClipEncoderLayer> = Vec::new();
         for index in 0..c.num_hidden_layers {
@@ -240,6 +276,36 @@ impl ClipEncoder {
         }
         Ok(xs)
     }
+
+    /// Run the layers, returning the input followed by the output of each layer.
+    pub fn forward_with_hidden_states(
+        &self,
+        xs: &Tensor,
+        causal_attention_mask: Option<&Tensor>,
+    ) -> Result<Vec<Tensor>> {
+        let mut hidden_states = vec![xs.clone()];
+        for layer in self.layers.iter() {
+            let xs = layer.forward(hidden_states.last().unwrap(), causal_attention_mask)?;
+            hidden_states.push(xs);
+        }
+        Ok(hidden_states)
+    }
+}
+
+/// The outputs of [`ClipTextTransformer::forward_with_hidden_states`].
+#[derive(Clone, Debug)]
+pub struct ClipTextOutput {
+    /// Output of the last encoder layer after the final layer norm, of shape `(batch, seq_len, hidden_size)`.
+    pub last_hidden_state: Tensor,
+    /// The last hidden state at the end of text token, of shape `(batch, hidden_size)`.
+    pub pooled_output: Tensor,
+    /// The input of the encoder followed by the output of each of its layers, without the final layer norm,
+    /// as the `hidden_states` of `transformers`. The penultimate layer is
+    /// `hidden_states[hidden_states.len() - 2]`.
+    pub hidden_states: Vec<Tensor>,
+    /// The pooled output projected into the joint image-text embedding space, of shape
+    /// `(batch, projection_dim)`, for a [`ClipTextModelWithProjection`].
+    pub text_embeds: Option<Tensor>,
 }
 
 /// A CLIP transformer based model.
@@ -248,19 +314,21 @@ pub struct ClipTextTransformer {
     embeddings: ClipTextEmbeddings,
     encoder: ClipEncoder,
     final_layer_norm: diffusion_rs_common::nn::LayerNorm,
+    max_position_embeddings: usize,
     device: Device,
 }
 
 impl ClipTextTransformer {
     pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
         let embeddings = ClipTextEmbeddings::new(vs.pp("embeddings"), c)?;
-        let encoder = ClipEncoder::new(vs.pp("encoder"), c)?;
+        let encoder = ClipEncoder::new(vs.pp("encoder"), &c.encoder_config())?;
         let final_layer_norm =
-            diffusion_rs_common::layer_norm(c.projection_dim, 1e-5, vs.pp("final_layer_norm"))?;
+            diffusion_rs_common::layer_norm(c.hidden_size(), 1e-5, vs.pp("final_layer_norm"))?;
         Ok(ClipTextTransformer {
             embeddings,
             encoder,
             final_layer_norm,
+            max_position_embeddings: c.max_position_embeddings,
             device: vs.device().clone(),
         })
     }
@@ -269,6 +337,11 @@ impl ClipTextTransformer {
         &self.device
     }
 
+    /// Maximum number of tokens of the input sequences, including the start and end of text tokens.
+    pub fn max_position_embeddings(&self) -> usize {
+        self.max_position_embeddings
+    }
+
     // TODO: rewrrite to newer version
     fn build_causal_attention_mask(
         bsz: usize,
@@ -291,6 +364,20 @@ impl ClipTextTransformer {
         mask.broadcast_as((bsz, 1, seq_len, seq_len))
     }
 
+    /// Select the hidden state of each sequence at its end of text token, which has the largest id.
+    fn pool(hidden_state: &Tensor, input_ids: &Tensor) -> Result<Tensor> {
+        let sequence_max_indices = input_ids.argmax(D::Minus1)?.to_dtype(DType::I64)?;
+
+        let mut indices = Vec::new();
+        for (batch_idx, &seq_idx) in sequence_max_indices.to_vec1::<i64>()?.iter().enumerate() {
+            let index = hidden_state
+                .i((batch_idx, seq_idx as usize))?
+                .unsqueeze(0)?;
+            indices.push(index);
+        }
+        Tensor::cat(&indices, 0)
+    }
+
     pub fn forward_with_mask(&self, input_ids: &Tensor, mask_after: usize) -> Result<Tensor> {
         let (bsz, seq_len) = input_ids.dims2()?;
         let input_ids = self.embeddings.forward(input_ids)?;
@@ -301,18 +388,105 @@ impl ClipTextTransformer {
             .forward(&input_ids, Some(&causal_attention_mask))?;
         self.final_layer_norm.forward(&input_ids)
     }
+
+    /// Like [`ClipTextTransformer::forward_with_mask`], returning the hidden states of every layer along with
+    /// the pooled output.
+    pub fn forward_with_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        mask_after: usize,
+    ) -> Result<ClipTextOutput> {
+        let (bsz, seq_len) = input_ids.dims2()?;
+        let xs = self.embeddings.forward(input_ids)?;
+        let causal_attention_mask =
+            Self::build_causal_attention_mask(bsz, seq_len, mask_after, xs.device())?;
+        let hidden_states = self
+            .encoder
+            .forward_with_hidden_states(&xs, Some(&causal_attention_mask))?;
+        let last_hidden_state = self
+            .final_layer_norm
+            .forward(hidden_states.last().unwrap())?;
+        let pooled_output = Self::pool(&last_hidden_state, input_ids)?;
+        Ok(ClipTextOutput {
+            last_hidden_state,
+            pooled_output,
+            hidden_states,
+            text_embeds: None,
+        })
+    }
+
+    /// The output of the layer `clip_skip` layers before the last one, with the final layer norm, as used
+    /// for "clip skip". A `clip_skip` of 0 gives the last hidden state.
+    pub fn forward_with_clip_skip(&self, input_ids: &Tensor, clip_skip: usize) -> Result<Tensor> {
+        let hidden_states = self
+            .forward_with_hidden_states(input_ids, usize::MAX)?
+            .hidden_states;
+        // The first hidden state is the input of the encoder.
+        if clip_skip + 1 >= hidden_states.len() {
+            diffusion_rs_common::bail!(
+                "Cannot skip {clip_skip} layers of a CLIP text encoder with {} layers.",
+                hidden_states.len() - 1
+            );
+        }
+        self.final_layer_norm
+            .forward(&hidden_states[hidden_states.len() - 1 - clip_skip])
+    }
 }
 
 impl Module for ClipTextTransformer {
     fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
         let output = self.forward_with_mask(input_ids, usize::MAX)?;
-        let sequence_max_indices = input_ids.argmax(D::Minus1)?.to_dtype(DType::I64)?;
+        Self::pool(&output, input_ids)
+    }
+}
 
-        let mut indices = Vec::new();
-        for (batch_idx, &seq_idx) in sequence_max_indices.to_vec1::<i64>()?.iter().enumerate() {
-            let index = output.i((batch_idx, seq_idx as usize))?.unsqueeze(0)?;
-            indices.push(index);
-        }
-        Tensor::cat(&indices, 0)
+/// A CLIP text model with the projection into the joint image-text embedding space, as the HF
+/// `CLIPTextModelWithProjection`.
+#[derive(Clone, Debug)]
+pub struct ClipTextModelWithProjection {
+    text_model: ClipTextTransformer,
+    text_projection: diffusion_rs_common::nn::Linear,
+}
+
+impl ClipTextModelWithProjection {
+    /// Load the weights of a `CLIPTextModelWithProjection` or a `CLIPModel`, without a prefix.
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipTextConfig) -> Result<Self> {
+        let text_model = ClipTextTransformer::new(vs.pp("text_model"), c)?;
+        let text_projection = diffusion_rs_common::linear_no_bias(
+            c.hidden_size(),
+            c.projection_dim,
+            vs.pp("text_projection"),
+        )?;
+        Ok(Self {
+            text_model,
+            text_projection,
+        })
+    }
+
+    /// The text transformer, without the projection.
+    pub fn text_model(&self) -> &ClipTextTransformer {
+        &self.text_model
+    }
+
+    /// Like [`ClipTextTransformer::forward_with_hidden_states`], also returning the projected pooled output
+    /// as `text_embeds`.
+    pub fn forward_with_hidden_states(
+        &self,
+        input_ids: &Tensor,
+        mask_after: usize,
+    ) -> Result<ClipTextOutput> {
+        let mut output = self
+            .text_model
+            .forward_with_hidden_states(input_ids, mask_after)?;
+        output.text_embeds = Some(self.text_projection.forward(&output.pooled_output)?);
+        Ok(output)
+    }
+}
+
+impl Module for ClipTextModelWithProjection {
+    /// Compute the projected text embeddings of `(batch, seq_len)` token ids.
+    fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
+        self.text_projection
+            .forward(&self.text_model.forward(input_ids)?)
     }
 }
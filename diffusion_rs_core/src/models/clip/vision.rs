@@ -0,0 +1,315 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use diffusion_rs_common::core::{DType, Device, IndexOp, Result, Tensor};
+use diffusion_rs_common::nn::{Conv2dConfig, Module};
+use image::{imageops::FilterType, DynamicImage};
+use serde::Deserialize;
+
+use super::text::{Activation, ClipEncoder, ClipEncoderConfig};
+
+/// Per-channel mean and standard deviation of the images CLIP was trained on.
+const CLIP_IMAGE_MEAN: [f32; 3] = [0.481_454_66, 0.457_827_5, 0.408_210_73];
+const CLIP_IMAGE_STD: [f32; 3] = [0.268_629_54, 0.261_302_6, 0.275_777_1];
+
+fn default_hidden_size() -> usize {
+    768
+}
+
+fn default_intermediate_size() -> usize {
+    3072
+}
+
+fn default_projection_dim() -> usize {
+    512
+}
+
+fn default_num_layers() -> usize {
+    12
+}
+
+fn default_num_attention_heads() -> usize {
+    12
+}
+
+fn default_num_channels() -> usize {
+    3
+}
+
+fn default_image_size() -> usize {
+    224
+}
+
+fn default_patch_size() -> usize {
+    32
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::QuickGelu
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-5
+}
+
+/// The config of a HF `CLIPVisionModelWithProjection`. The defaults are the ones of `transformers`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClipVisionConfig {
+    #[serde(default = "default_hidden_size")]
+    pub hidden_size: usize,
+    #[serde(default = "default_intermediate_size")]
+    pub intermediate_size: usize,
+    #[serde(default = "default_projection_dim")]
+    pub projection_dim: usize,
+    #[serde(default = "default_num_layers")]
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_num_attention_heads")]
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    #[serde(default = "default_image_size")]
+    pub image_size: usize,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClipVisionConfigFile {
+    /// The `config.json` of a full `CLIPModel`.
+    Clip {
+        vision_config: ClipVisionConfig,
+    },
+    Vision(ClipVisionConfig),
+}
+
+impl ClipVisionConfig {
+    /// Parse the `config.json` of a `CLIPVisionModelWithProjection`, or of a full `CLIPModel` whose vision
+    /// tower is used.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        Ok(match serde_json::from_str(json)? {
+            ClipVisionConfigFile::Clip { vision_config } => vision_config,
+            ClipVisionConfigFile::Vision(config) => config,
+        })
+    }
+
+    fn num_positions(&self) -> usize {
+        (self.image_size / self.patch_size).pow(2) + 1
+    }
+
+    fn encoder_config(&self) -> ClipEncoderConfig {
+        ClipEncoderConfig {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            hidden_act: self.hidden_act,
+            layer_norm_eps: self.layer_norm_eps,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ClipVisionEmbeddings {
+    patch_embedding: diffusion_rs_common::nn::Conv2d,
+    class_embedding: Tensor,
+    position_embedding: diffusion_rs_common::nn::Embedding,
+    position_ids: Tensor,
+}
+
+impl ClipVisionEmbeddings {
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        let patch_embedding = diffusion_rs_common::conv2d_no_bias(
+            c.num_channels,
+            c.hidden_size,
+            c.patch_size,
+            Conv2dConfig {
+                stride: c.patch_size,
+                ..Default::default()
+            },
+            vs.pp("patch_embedding"),
+        )?;
+        let class_embedding = vs.get(c.hidden_size, "class_embedding")?;
+        let position_embedding = diffusion_rs_common::embedding(
+            c.num_positions(),
+            c.hidden_size,
+            vs.pp("position_embedding"),
+        )?;
+        let position_ids =
+            Tensor::arange(0u32, c.num_positions() as u32, vs.device())?.unsqueeze(0)?;
+        Ok(Self {
+            patch_embedding,
+            class_embedding,
+            position_embedding,
+            position_ids,
+        })
+    }
+}
+
+impl Module for ClipVisionEmbeddings {
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let bsz = pixel_values.dim(0)?;
+        let patch_embeds = self
+            .patch_embedding
+            .forward(pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let hidden_size = self.class_embedding.dim(0)?;
+        let class_embeds = self
+            .class_embedding
+            .reshape((1, 1, hidden_size))?
+            .broadcast_as((bsz, 1, hidden_size))?
+            .to_dtype(patch_embeds.dtype())?;
+        let embeddings = Tensor::cat(&[&class_embeds, &patch_embeds], 1)?;
+        let position_embedding = self.position_embedding.forward(&self.position_ids)?;
+        embeddings.broadcast_add(&position_embedding)
+    }
+}
+
+/// The outputs of [`ClipVisionTransformer::forward_with_hidden_states`].
+#[derive(Clone, Debug)]
+pub struct ClipVisionOutput {
+    /// Output of the last encoder layer, of shape `(batch, num_positions, hidden_size)`.
+    pub last_hidden_state: Tensor,
+    /// The class token of the last hidden state after the final layer norm, of shape `(batch, hidden_size)`.
+    pub pooled_output: Tensor,
+    /// The pooled output projected into the joint image-text embedding space, of shape
+    /// `(batch, projection_dim)`.
+    pub image_embeds: Tensor,
+    /// The input of the encoder followed by the output of each of its layers, as the `hidden_states` of
+    /// `transformers`. The penultimate layer is `hidden_states[hidden_states.len() - 2]`.
+    pub hidden_states: Vec<Tensor>,
+}
+
+/// Resize an image so that its shortest side is `size`, center-crop it to `size`x`size` and normalize it
+/// into a `(1, 3, size, size)` tensor, as the `CLIPImageProcessor` of `transformers` does.
+pub fn clip_image_to_tensor(
+    image: &DynamicImage,
+    size: usize,
+    device: &Device,
+    dtype: DType,
+) -> Result<Tensor> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let scale = size as f64 / width.min(height) as f64;
+    let resized_width = ((width as f64 * scale).round() as usize).max(size);
+    let resized_height = ((height as f64 * scale).round() as usize).max(size);
+    let image = image
+        .resize_exact(
+            resized_width as u32,
+            resized_height as u32,
+            FilterType::CatmullRom,
+        )
+        .crop_imm(
+            ((resized_width - size) / 2) as u32,
+            ((resized_height - size) / 2) as u32,
+            size as u32,
+            size as u32,
+        )
+        .to_rgb8();
+    let mean = Tensor::new(&CLIP_IMAGE_MEAN, device)?.reshape((1, 3, 1, 1))?;
+    let std = Tensor::new(&CLIP_IMAGE_STD, device)?.reshape((1, 3, 1, 1))?;
+    Tensor::from_vec(image.into_raw(), (size, size, 3), device)?
+        .permute((2, 0, 1))?
+        .unsqueeze(0)?
+        .to_dtype(DType::F32)?
+        .affine(1. / 255., 0.)?
+        .broadcast_sub(&mean)?
+        .broadcast_div(&std)?
+        .to_dtype(dtype)
+}
+
+/// The vision tower of CLIP, with the projection into the joint image-text embedding space.
+#[derive(Clone, Debug)]
+pub struct ClipVisionTransformer {
+    embeddings: ClipVisionEmbeddings,
+    pre_layrnorm: diffusion_rs_common::nn::LayerNorm,
+    encoder: ClipEncoder,
+    post_layernorm: diffusion_rs_common::nn::LayerNorm,
+    visual_projection: diffusion_rs_common::nn::Linear,
+    image_size: usize,
+    device: Device,
+    dtype: DType,
+}
+
+impl ClipVisionTransformer {
+    /// Load the weights of a `CLIPVisionModelWithProjection` or a `CLIPModel`, without a prefix.
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &ClipVisionConfig) -> Result<Self> {
+        let vision_vs = vs.pp("vision_model");
+        let embeddings = ClipVisionEmbeddings::new(vision_vs.pp("embeddings"), c)?;
+        let pre_layrnorm = diffusion_rs_common::layer_norm(
+            c.hidden_size,
+            c.layer_norm_eps,
+            vision_vs.pp("pre_layrnorm"),
+        )?;
+        let encoder = ClipEncoder::new(vision_vs.pp("encoder"), &c.encoder_config())?;
+        let post_layernorm = diffusion_rs_common::layer_norm(
+            c.hidden_size,
+            c.layer_norm_eps,
+            vision_vs.pp("post_layernorm"),
+        )?;
+        let visual_projection = diffusion_rs_common::linear_no_bias(
+            c.hidden_size,
+            c.projection_dim,
+            vs.pp("visual_projection"),
+        )?;
+        Ok(Self {
+            embeddings,
+            pre_layrnorm,
+            encoder,
+            post_layernorm,
+            visual_projection,
+            image_size: c.image_size,
+            device: vs.device().clone(),
+            dtype: vs.dtype(),
+        })
+    }
+
+    /// Side of the square images this model expects.
+    pub fn image_size(&self) -> usize {
+        self.image_size
+    }
+
+    /// Preprocess images with [`clip_image_to_tensor`] into a batch of pixel values on the device and in the
+    /// dtype of the model.
+    pub fn preprocess(&self, images: &[DynamicImage]) -> Result<Tensor> {
+        let pixel_values = images
+            .iter()
+            .map(|image| clip_image_to_tensor(image, self.image_size, &self.device, self.dtype))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::cat(&pixel_values, 0)
+    }
+
+    /// Run the model on `(batch, channels, image_size, image_size)` normalized pixel values, returning the
+    /// hidden states of every layer along with the pooled and projected embeddings.
+    pub fn forward_with_hidden_states(&self, pixel_values: &Tensor) -> Result<ClipVisionOutput> {
+        let xs = self.embeddings.forward(pixel_values)?;
+        let xs = self.pre_layrnorm.forward(&xs)?;
+        let hidden_states = self.encoder.forward_with_hidden_states(&xs, None)?;
+        let last_hidden_state = hidden_states.last().unwrap().clone();
+        let pooled_output = self
+            .post_layernorm
+            .forward(&last_hidden_state.i((.., 0, ..))?)?;
+        let image_embeds = self.visual_projection.forward(&pooled_output)?;
+        Ok(ClipVisionOutput {
+            last_hidden_state,
+            pooled_output,
+            image_embeds,
+            hidden_states,
+        })
+    }
+}
+
+impl Module for ClipVisionTransformer {
+    /// Compute the projected image embeddings of `(batch, channels, image_size, image_size)` normalized
+    /// pixel values.
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let xs = self.embeddings.forward(pixel_values)?;
+        let xs = self.pre_layrnorm.forward(&xs)?;
+        let xs = self.encoder.forward(&xs, None)?;
+        let pooled = self.post_layernorm.forward(&xs.i((.., 0, ..))?)?;
+        self.visual_projection.forward(&pooled)
+    }
+}
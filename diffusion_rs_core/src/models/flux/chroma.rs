@@ -0,0 +1,420 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use diffusion_rs_backend::{QuantMethod, QuantizedConfig};
+use diffusion_rs_common::core::{DType, Device, IndexOp, Result, Tensor};
+use diffusion_rs_common::nn::{layer_norm::RmsNormNonQuantized, LayerNorm, RmsNorm};
+use diffusion_rs_common::{NiceProgressBar, VarBuilder};
+use serde::Deserialize;
+
+use crate::models::{QuantizedModel, QuantizedModelLayer};
+
+use super::model::{
+    default_attention_head_dim, default_axes_dims_rope, default_mlp_ratio, default_patch_size,
+    default_rope_theta, layer_norm, timestep_embedding, Config, DoubleStreamBlock, EmbedNd,
+    MlpEmbedder, ModulationOut, SingleStreamBlock,
+};
+use super::offload::DiskOffloadedBlocks;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChromaConfig {
+    pub in_channels: usize,
+    pub joint_attention_dim: usize,
+    pub num_attention_heads: usize,
+    #[serde(default = "default_attention_head_dim")]
+    pub attention_head_dim: usize,
+    #[serde(default = "default_axes_dims_rope")]
+    pub axes_dims_rope: Vec<usize>,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    pub num_layers: usize,
+    pub num_single_layers: usize,
+    /// Width of the approximator input, half of it for the timestep and half for the modulation index.
+    pub approximator_num_channels: usize,
+    pub approximator_hidden_dim: usize,
+    pub approximator_layers: usize,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+impl ChromaConfig {
+    /// The config of the FLUX blocks.
+    pub(crate) fn flux_config(&self) -> Config {
+        Config {
+            in_channels: self.in_channels,
+            out_channels: None,
+            pooled_projection_dim: 0,
+            joint_attention_dim: self.joint_attention_dim,
+            num_attention_heads: self.num_attention_heads,
+            attention_head_dim: self.attention_head_dim,
+            axes_dims_rope: self.axes_dims_rope.clone(),
+            patch_size: self.patch_size,
+            rope_theta: default_rope_theta(),
+            mlp_ratio: default_mlp_ratio(),
+            num_layers: self.num_layers,
+            num_single_layers: self.num_single_layers,
+            guidance_embeds: false,
+            quantization_config: self.quantization_config.clone(),
+        }
+    }
+
+    /// Number of modulation vectors: shift, scale and gate for the single stream blocks, twice as many for
+    /// each stream of the double stream blocks, and the shift and scale of the final layer.
+    pub(crate) fn num_modulations(&self) -> usize {
+        3 * self.num_single_layers + 2 * 6 * self.num_layers + 2
+    }
+
+    /// Check that the dimensions of the config are consistent.
+    pub fn validate(&self) -> Result<()> {
+        self.flux_config().validate()?;
+        // The timestep and the modulation index are embedded in a quarter and a half of the channels.
+        if self.approximator_num_channels == 0 || !self.approximator_num_channels.is_multiple_of(8)
+        {
+            diffusion_rs_common::bail!(
+                "expected `approximator_num_channels` to be a non-zero multiple of 8, got {}",
+                self.approximator_num_channels
+            )
+        }
+        Ok(())
+    }
+}
+
+/// MLP which computes the modulation vectors of every block from the timestep, replacing the per-block
+/// modulation layers of FLUX.
+#[derive(Debug, Clone)]
+struct Approximator {
+    in_proj: Arc<dyn QuantMethod>,
+    layers: Vec<MlpEmbedder>,
+    norms: Vec<RmsNorm<RmsNormNonQuantized>>,
+    out_proj: Arc<dyn QuantMethod>,
+}
+
+impl Approximator {
+    fn new(cfg: &ChromaConfig, vb: VarBuilder) -> Result<Self> {
+        let flux_cfg = cfg.flux_config();
+        let hidden_dim = cfg.approximator_hidden_dim;
+        let in_proj = diffusion_rs_backend::linear(
+            cfg.approximator_num_channels,
+            hidden_dim,
+            &cfg.quantization_config,
+            vb.pp("in_proj"),
+        )?;
+        let mut layers = Vec::with_capacity(cfg.approximator_layers);
+        let mut norms = Vec::with_capacity(cfg.approximator_layers);
+        for idx in 0..cfg.approximator_layers {
+            layers.push(MlpEmbedder::new(
+                hidden_dim,
+                hidden_dim,
+                &flux_cfg,
+                vb.pp("layers").pp(idx),
+            )?);
+            let weight = vb.pp("norms").pp(idx).get(hidden_dim, "weight")?;
+            norms.push(RmsNorm::<RmsNormNonQuantized>::new(weight, 1e-6));
+        }
+        let out_proj = diffusion_rs_backend::linear(
+            hidden_dim,
+            flux_cfg.hidden_size(),
+            &cfg.quantization_config,
+            vb.pp("out_proj"),
+        )?;
+        Ok(Self {
+            in_proj,
+            layers,
+            norms,
+            out_proj,
+        })
+    }
+}
+
+impl diffusion_rs_common::core::Module for Approximator {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let mut xs = self.in_proj.forward_autocast(xs)?;
+        for (layer, norm) in self.layers.iter().zip(&self.norms) {
+            xs = (&xs + xs.apply(norm)?.apply(layer)?)?;
+        }
+        self.out_proj.forward_autocast(&xs)
+    }
+}
+
+/// The Chroma transformer: FLUX double and single stream blocks without CLIP conditioning, whose
+/// modulations are all computed from the timestep by an [`Approximator`].
+#[derive(Debug, Clone)]
+pub struct Chroma {
+    img_in: Arc<dyn QuantMethod>,
+    txt_in: Arc<dyn QuantMethod>,
+    approximator: Approximator,
+    /// Embedding of the index of each modulation vector, of shape `(num_modulations, channels / 2)`.
+    mod_proj: Tensor,
+    approximator_num_channels: usize,
+    pe_embedder: EmbedNd,
+    double_blocks: Vec<DoubleStreamBlock>,
+    single_blocks: Vec<SingleStreamBlock>,
+    offloaded_blocks: Option<Arc<DiskOffloadedBlocks>>,
+    norm_out: LayerNorm,
+    proj_out: Arc<dyn QuantMethod>,
+    num_layers: usize,
+    num_single_layers: usize,
+}
+
+impl Chroma {
+    pub fn new(cfg: &ChromaConfig, vb: VarBuilder) -> Result<Self> {
+        cfg.validate()?;
+        let flux_cfg = cfg.flux_config();
+        let mut double_blocks = Vec::with_capacity(cfg.num_layers);
+        let vb_d = vb.pp("transformer_blocks");
+        for idx in NiceProgressBar::<_, 'r'>(0..cfg.num_layers, "Loading double stream blocks") {
+            double_blocks.push(DoubleStreamBlock::new_pruned(&flux_cfg, vb_d.pp(idx))?);
+        }
+        let mut single_blocks = Vec::with_capacity(cfg.num_single_layers);
+        let vb_s = vb.pp("single_transformer_blocks");
+        for idx in
+            NiceProgressBar::<_, 'r'>(0..cfg.num_single_layers, "Loading single stream blocks")
+        {
+            single_blocks.push(SingleStreamBlock::new_pruned(&flux_cfg, vb_s.pp(idx))?);
+        }
+        Self::new_with_blocks(cfg, vb, double_blocks, single_blocks, None)
+    }
+
+    /// Load the model, but keep the double and single stream blocks on disk, see
+    /// [`Flux::new_disk_offloaded`](super::FluxModel::new_disk_offloaded).
+    pub fn new_disk_offloaded(
+        cfg: &ChromaConfig,
+        vb: VarBuilder<'static>,
+        memory_budget: usize,
+    ) -> Result<Self> {
+        cfg.validate()?;
+        let offloaded_blocks =
+            DiskOffloadedBlocks::new(&cfg.flux_config(), vb.clone(), false, memory_budget);
+        Self::new_with_blocks(cfg, vb, vec![], vec![], Some(Arc::new(offloaded_blocks)))
+    }
+
+    fn new_with_blocks(
+        cfg: &ChromaConfig,
+        vb: VarBuilder,
+        double_blocks: Vec<DoubleStreamBlock>,
+        single_blocks: Vec<SingleStreamBlock>,
+        offloaded_blocks: Option<Arc<DiskOffloadedBlocks>>,
+    ) -> Result<Self> {
+        let hidden_size = cfg.flux_config().hidden_size();
+        let img_in = diffusion_rs_backend::linear(
+            cfg.in_channels,
+            hidden_size,
+            &cfg.quantization_config,
+            vb.pp("x_embedder"),
+        )?;
+        let txt_in = diffusion_rs_backend::linear(
+            cfg.joint_attention_dim,
+            hidden_size,
+            &cfg.quantization_config,
+            vb.pp("context_embedder"),
+        )?;
+        let approximator = Approximator::new(cfg, vb.pp("distilled_guidance_layer"))?;
+        let indices = Tensor::arange(0u32, cfg.num_modulations() as u32, vb.device())?
+            .to_dtype(DType::F32)?;
+        let mod_proj = timestep_embedding(&indices, cfg.approximator_num_channels / 2, DType::F32)?;
+        let pe_embedder = EmbedNd::new(
+            cfg.attention_head_dim,
+            default_rope_theta(),
+            cfg.axes_dims_rope.clone(),
+        );
+        let norm_out = layer_norm(hidden_size, vb.pp("norm_out"))?;
+        let proj_out = diffusion_rs_backend::linear(
+            hidden_size,
+            cfg.patch_size * cfg.patch_size * cfg.in_channels,
+            &cfg.quantization_config,
+            vb.pp("proj_out"),
+        )?;
+
+        Ok(Self {
+            img_in,
+            txt_in,
+            approximator,
+            mod_proj,
+            approximator_num_channels: cfg.approximator_num_channels,
+            pe_embedder,
+            double_blocks,
+            single_blocks,
+            offloaded_blocks,
+            norm_out,
+            proj_out,
+            num_layers: cfg.num_layers,
+            num_single_layers: cfg.num_single_layers,
+        })
+    }
+
+    /// Compute the modulation vectors of every block, of shape `(batch, num_modulations, hidden_size)`.
+    fn modulations(&self, timesteps: &Tensor, dtype: DType) -> Result<Tensor> {
+        let bs = timesteps.dim(0)?;
+        let num_modulations = self.mod_proj.dim(0)?;
+        let channels = self.approximator_num_channels / 4;
+        let timesteps_proj = timestep_embedding(timesteps, channels, DType::F32)?;
+        // The guidance is distilled away and always embedded as 0.
+        let guidance_proj = timestep_embedding(&timesteps.zeros_like()?, channels, DType::F32)?;
+        let timestep_guidance = Tensor::cat(&[timesteps_proj, guidance_proj], 1)?
+            .unsqueeze(1)?
+            .repeat((1, num_modulations, 1))?;
+        let mod_proj = self.mod_proj.unsqueeze(0)?.repeat((bs, 1, 1))?;
+        Tensor::cat(&[timestep_guidance, mod_proj], 2)?
+            .to_dtype(dtype)?
+            .apply(&self.approximator)
+    }
+
+    /// - `timesteps`: in `[0, 1]`, of shape `(batch,)`.
+    /// - `txt_mask`: `1` for the text tokens which are attended to and `0` for the others, of shape
+    ///   `(batch, txt_len)`. Image tokens are always attended to.
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &self,
+        img: &Tensor,
+        img_ids: &Tensor,
+        txt: &Tensor,
+        txt_ids: &Tensor,
+        timesteps: &Tensor,
+        txt_mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
+        if txt.rank() != 3 {
+            diffusion_rs_common::bail!("unexpected shape for txt {:?}", txt.shape())
+        }
+        if img.rank() != 3 {
+            diffusion_rs_common::bail!("unexpected shape for img {:?}", img.shape())
+        }
+        let dtype = img.dtype();
+        let pe = {
+            let ids = Tensor::cat(&[txt_ids, img_ids], 1)?;
+            ids.apply(&self.pe_embedder)?
+        };
+        // Additive attention mask over the keys, of shape `(batch, 1, 1, txt_len + img_len)`.
+        let mask = match txt_mask {
+            Some(txt_mask) => {
+                let (bs, img_len) = (img.dim(0)?, img.dim(1)?);
+                let keep = Tensor::cat(
+                    &[
+                        txt_mask.to_dtype(DType::U8)?,
+                        Tensor::ones((bs, img_len), DType::U8, img.device())?,
+                    ],
+                    1,
+                )?;
+                let zeros = Tensor::zeros(keep.shape(), DType::F32, img.device())?;
+                let neg_inf = Tensor::full(f32::NEG_INFINITY, keep.shape(), img.device())?;
+                Some(
+                    keep.where_cond(&zeros, &neg_inf)?
+                        .unsqueeze(1)?
+                        .unsqueeze(1)?,
+                )
+            }
+            None => None,
+        };
+        let mut txt = self.txt_in.forward_autocast(txt)?;
+        let mut img = self.img_in.forward_autocast(img)?;
+        let mods = self.modulations(timesteps, dtype)?;
+
+        let img_offset = 3 * self.num_single_layers;
+        let txt_offset = img_offset + 6 * self.num_layers;
+        let double_mods = |offset: usize| -> Result<(ModulationOut, ModulationOut)> {
+            Ok((
+                ModulationOut::from_vectors(&mods.narrow(1, offset, 3)?)?,
+                ModulationOut::from_vectors(&mods.narrow(1, offset + 3, 3)?)?,
+            ))
+        };
+        let single_mods = |idx: usize| ModulationOut::from_vectors(&mods.narrow(1, 3 * idx, 3)?);
+
+        // Double blocks
+        for idx in 0..self.num_layers {
+            let img_mods = double_mods(img_offset + 6 * idx)?;
+            let txt_mods = double_mods(txt_offset + 6 * idx)?;
+            (img, txt) = match &self.offloaded_blocks {
+                Some(blocks) => blocks.double_block(idx)?.forward_modulated(
+                    &img,
+                    &txt,
+                    img_mods,
+                    txt_mods,
+                    &pe,
+                    mask.as_ref(),
+                    &[],
+                )?,
+                None => self.double_blocks[idx].forward_modulated(
+                    &img,
+                    &txt,
+                    img_mods,
+                    txt_mods,
+                    &pe,
+                    mask.as_ref(),
+                    &[],
+                )?,
+            };
+        }
+        // Single blocks
+        let mut img = Tensor::cat(&[&txt, &img], 1)?;
+        for idx in 0..self.num_single_layers {
+            img = match &self.offloaded_blocks {
+                Some(blocks) => blocks.single_block(idx)?.forward_modulated(
+                    &img,
+                    single_mods(idx)?,
+                    &pe,
+                    mask.as_ref(),
+                )?,
+                None => self.single_blocks[idx].forward_modulated(
+                    &img,
+                    single_mods(idx)?,
+                    &pe,
+                    mask.as_ref(),
+                )?,
+            };
+        }
+        let img = img.i((.., txt.dim(1)?..))?;
+
+        let num_modulations = mods.dim(1)?;
+        let shift = mods.narrow(1, num_modulations - 2, 1)?;
+        let scale = mods.narrow(1, num_modulations - 1, 1)?;
+        let img = img
+            .apply(&self.norm_out)?
+            .broadcast_mul(&(scale + 1.0)?)?
+            .broadcast_add(&shift)?;
+        self.proj_out.forward_autocast(&img)
+    }
+}
+
+impl QuantizedModel for Chroma {
+    fn match_devices_all_layers(&mut self, dev: &Device) -> Result<()> {
+        self.mod_proj = self.mod_proj.to_device(dev)?;
+        self.norm_out = self.norm_out.to_device(dev)?;
+        for norm in &mut self.approximator.norms {
+            *norm = norm.to_device(dev)?;
+        }
+        for block in &mut self.double_blocks {
+            block.match_devices(dev)?;
+        }
+        for block in &mut self.single_blocks {
+            block.match_devices(dev)?;
+        }
+        Ok(())
+    }
+
+    fn aggregate_layers(&mut self) -> Result<Vec<QuantizedModelLayer<'_>>> {
+        let mut layers = Vec::new();
+
+        {
+            let mut pre_layer_ct = vec![
+                &mut self.txt_in,
+                &mut self.img_in,
+                &mut self.approximator.in_proj,
+                &mut self.approximator.out_proj,
+            ];
+            for layer in &mut self.approximator.layers {
+                pre_layer_ct.push(&mut layer.in_layer);
+                pre_layer_ct.push(&mut layer.out_layer);
+            }
+            layers.push(QuantizedModelLayer(pre_layer_ct));
+        }
+
+        layers.push(QuantizedModelLayer(vec![&mut self.proj_out]));
+
+        for block in &mut self.double_blocks {
+            layers.push(block.layers());
+        }
+        for block in &mut self.single_blocks {
+            layers.push(block.layers());
+        }
+        Ok(layers)
+    }
+}
@@ -0,0 +1,368 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::sync::Arc;
+
+use diffusion_rs_backend::{QuantMethod, QuantizedConfig};
+use diffusion_rs_common::core::{Device, Result, Tensor};
+use diffusion_rs_common::nn::Embedding;
+use diffusion_rs_common::{NiceProgressBar, VarBuilder};
+use serde::Deserialize;
+
+use crate::models::{QuantizedModel, QuantizedModelLayer};
+
+use super::model::{
+    default_attention_head_dim, default_axes_dims_rope, default_mlp_ratio, default_patch_size,
+    default_rope_theta, timestep_embedding, Config, DoubleStreamBlock, EmbedNd, MlpEmbedder,
+    SingleStreamBlock,
+};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ControlNetConfig {
+    pub in_channels: usize,
+    pub pooled_projection_dim: usize,
+    pub joint_attention_dim: usize,
+    pub num_attention_heads: usize,
+    #[serde(default = "default_attention_head_dim")]
+    pub attention_head_dim: usize,
+    #[serde(default = "default_axes_dims_rope")]
+    pub axes_dims_rope: Vec<usize>,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    pub num_layers: usize,
+    pub num_single_layers: usize,
+    pub guidance_embeds: bool,
+    /// Number of control modes of a Union ControlNet, which embeds the mode as an extra text token.
+    pub num_mode: Option<usize>,
+    /// Channels of the conditioning embedding network, which is only used by ControlNets taking the control
+    /// image in pixel space.
+    pub conditioning_embedding_channels: Option<usize>,
+    pub quantization_config: Option<QuantizedConfig>,
+}
+
+impl ControlNetConfig {
+    /// The config of the FLUX blocks.
+    pub(crate) fn flux_config(&self) -> Config {
+        Config {
+            in_channels: self.in_channels,
+            out_channels: None,
+            pooled_projection_dim: self.pooled_projection_dim,
+            joint_attention_dim: self.joint_attention_dim,
+            num_attention_heads: self.num_attention_heads,
+            attention_head_dim: self.attention_head_dim,
+            axes_dims_rope: self.axes_dims_rope.clone(),
+            patch_size: self.patch_size,
+            rope_theta: default_rope_theta(),
+            mlp_ratio: default_mlp_ratio(),
+            num_layers: self.num_layers,
+            num_single_layers: self.num_single_layers,
+            guidance_embeds: self.guidance_embeds,
+            quantization_config: self.quantization_config.clone(),
+        }
+    }
+
+    pub(crate) fn hidden_size(&self) -> usize {
+        self.flux_config().hidden_size()
+    }
+
+    /// Check that the dimensions of the config are consistent.
+    pub fn validate(&self) -> Result<()> {
+        self.flux_config().validate()?;
+        // The control image is VAE-encoded and packed by the pipeline.
+        if let Some(channels) = self.conditioning_embedding_channels {
+            diffusion_rs_common::bail!(
+                "ControlNets with a pixel space conditioning embedding ({channels} channels) are not supported"
+            )
+        }
+        if self.num_mode == Some(0) {
+            diffusion_rs_common::bail!("expected a non-zero `num_mode`")
+        }
+        Ok(())
+    }
+}
+
+/// Outputs of the ControlNets, added to the outputs of the double and single stream blocks of
+/// [`Flux`](super::FluxModel). When there are fewer residuals than blocks, each one is added to consecutive
+/// blocks.
+#[derive(Debug, Clone)]
+pub struct ControlNetResiduals {
+    pub double: Vec<Tensor>,
+    pub single: Vec<Tensor>,
+}
+
+impl ControlNetResiduals {
+    /// Sum the residuals of several ControlNets with the same number of blocks.
+    pub fn add(&self, other: &Self) -> Result<Self> {
+        if self.double.len() != other.double.len() || self.single.len() != other.single.len() {
+            diffusion_rs_common::bail!(
+                "cannot combine ControlNets with different numbers of blocks: {}+{} and {}+{}",
+                self.double.len(),
+                self.single.len(),
+                other.double.len(),
+                other.single.len()
+            )
+        }
+        let add = |a: &[Tensor], b: &[Tensor]| {
+            a.iter()
+                .zip(b)
+                .map(|(a, b)| a + b)
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Self {
+            double: add(&self.double, &other.double)?,
+            single: add(&self.single, &other.single)?,
+        })
+    }
+
+    /// The residual of the block `idx` out of `num_blocks`.
+    pub(crate) fn block_residual(
+        residuals: &[Tensor],
+        idx: usize,
+        num_blocks: usize,
+    ) -> Option<&Tensor> {
+        if residuals.is_empty() {
+            return None;
+        }
+        let interval = num_blocks.div_ceil(residuals.len());
+        residuals.get(idx / interval)
+    }
+}
+
+/// A FLUX ControlNet: a shallow copy of the FLUX transformer which takes the packed control image latents
+/// and returns residuals for the blocks of the main transformer.
+#[derive(Debug, Clone)]
+pub struct ControlNet {
+    img_in: Arc<dyn QuantMethod>,
+    controlnet_x_embedder: Arc<dyn QuantMethod>,
+    txt_in: Arc<dyn QuantMethod>,
+    time_in: MlpEmbedder,
+    vector_in: MlpEmbedder,
+    guidance_in: Option<MlpEmbedder>,
+    mode_embedder: Option<Embedding>,
+    pe_embedder: EmbedNd,
+    double_blocks: Vec<DoubleStreamBlock>,
+    single_blocks: Vec<SingleStreamBlock>,
+    controlnet_blocks: Vec<Arc<dyn QuantMethod>>,
+    controlnet_single_blocks: Vec<Arc<dyn QuantMethod>>,
+}
+
+impl ControlNet {
+    pub fn new(cfg: &ControlNetConfig, vb: VarBuilder) -> Result<Self> {
+        cfg.validate()?;
+        let flux_cfg = cfg.flux_config();
+        let hidden_size = cfg.hidden_size();
+        let linear = |in_dim: usize, vb: VarBuilder| {
+            diffusion_rs_backend::linear(in_dim, hidden_size, &cfg.quantization_config, vb)
+        };
+
+        let mut double_blocks = Vec::with_capacity(cfg.num_layers);
+        let mut controlnet_blocks = Vec::with_capacity(cfg.num_layers);
+        for idx in NiceProgressBar::<_, 'r'>(0..cfg.num_layers, "Loading ControlNet blocks") {
+            double_blocks.push(DoubleStreamBlock::new(
+                &flux_cfg,
+                vb.pp("transformer_blocks").pp(idx),
+            )?);
+            controlnet_blocks.push(linear(hidden_size, vb.pp("controlnet_blocks").pp(idx))?);
+        }
+        let mut single_blocks = Vec::with_capacity(cfg.num_single_layers);
+        let mut controlnet_single_blocks = Vec::with_capacity(cfg.num_single_layers);
+        for idx in
+            NiceProgressBar::<_, 'r'>(0..cfg.num_single_layers, "Loading ControlNet single blocks")
+        {
+            single_blocks.push(SingleStreamBlock::new(
+                &flux_cfg,
+                vb.pp("single_transformer_blocks").pp(idx),
+            )?);
+            controlnet_single_blocks.push(linear(
+                hidden_size,
+                vb.pp("controlnet_single_blocks").pp(idx),
+            )?);
+        }
+
+        let guidance_in = if cfg.guidance_embeds {
+            Some(MlpEmbedder::new(
+                256,
+                hidden_size,
+                &flux_cfg,
+                vb.pp("time_text_embed.guidance_embedder"),
+            )?)
+        } else {
+            None
+        };
+        let mode_embedder = match cfg.num_mode {
+            Some(num_mode) => Some(diffusion_rs_common::embedding(
+                num_mode,
+                hidden_size,
+                vb.pp("controlnet_mode_embedder"),
+            )?),
+            None => None,
+        };
+
+        Ok(Self {
+            img_in: linear(cfg.in_channels, vb.pp("x_embedder"))?,
+            controlnet_x_embedder: linear(cfg.in_channels, vb.pp("controlnet_x_embedder"))?,
+            txt_in: linear(cfg.joint_attention_dim, vb.pp("context_embedder"))?,
+            time_in: MlpEmbedder::new(
+                256,
+                hidden_size,
+                &flux_cfg,
+                vb.pp("time_text_embed.timestep_embedder"),
+            )?,
+            vector_in: MlpEmbedder::new(
+                cfg.pooled_projection_dim,
+                hidden_size,
+                &flux_cfg,
+                vb.pp("time_text_embed.text_embedder"),
+            )?,
+            guidance_in,
+            mode_embedder,
+            pe_embedder: EmbedNd::new(
+                cfg.attention_head_dim,
+                default_rope_theta(),
+                cfg.axes_dims_rope.clone(),
+            ),
+            double_blocks,
+            single_blocks,
+            controlnet_blocks,
+            controlnet_single_blocks,
+        })
+    }
+
+    /// Compute the residuals of the blocks, scaled by `scale`. The inputs are those of
+    /// [`Flux::forward`](super::FluxModel::forward), plus:
+    /// - `cond`: the packed latents of the control image, shaped like `img`.
+    /// - `mode`: the control mode of each batch item, of shape `(batch,)`, for Union ControlNets.
+    #[allow(clippy::too_many_arguments)]
+    pub fn forward(
+        &self,
+        img: &Tensor,
+        img_ids: &Tensor,
+        txt: &Tensor,
+        txt_ids: &Tensor,
+        timesteps: &Tensor,
+        y: &Tensor,
+        guidance: Option<&Tensor>,
+        cond: &Tensor,
+        mode: Option<&Tensor>,
+        scale: f64,
+    ) -> Result<ControlNetResiduals> {
+        if txt.rank() != 3 {
+            diffusion_rs_common::bail!("unexpected shape for txt {:?}", txt.shape())
+        }
+        if img.rank() != 3 {
+            diffusion_rs_common::bail!("unexpected shape for img {:?}", img.shape())
+        }
+        let dtype = img.dtype();
+        let mut txt = self.txt_in.forward_autocast(txt)?;
+        // The control mode is embedded as a text token in front of the prompt.
+        let txt_ids = match (&self.mode_embedder, mode) {
+            (Some(mode_embedder), Some(mode)) => {
+                let mode = mode
+                    .apply(mode_embedder)?
+                    .unsqueeze(1)?
+                    .to_dtype(txt.dtype())?;
+                txt = Tensor::cat(&[&mode, &txt], 1)?;
+                Tensor::cat(&[&txt_ids.narrow(1, 0, 1)?, txt_ids], 1)?
+            }
+            (Some(_), None) => {
+                diffusion_rs_common::bail!("a control mode is required by Union ControlNets")
+            }
+            (None, Some(_)) => {
+                diffusion_rs_common::bail!("control modes are only supported by Union ControlNets")
+            }
+            (None, None) => txt_ids.clone(),
+        };
+        let pe = {
+            let ids = Tensor::cat(&[&txt_ids, img_ids], 1)?;
+            ids.apply(&self.pe_embedder)?
+        };
+        let mut img = (self.img_in.forward_autocast(img)?
+            + self.controlnet_x_embedder.forward_autocast(cond)?)?;
+        let vec_ = timestep_embedding(timesteps, 256, dtype)?.apply(&self.time_in)?;
+        let vec_ = match (self.guidance_in.as_ref(), guidance) {
+            (Some(g_in), Some(guidance)) => {
+                (vec_ + timestep_embedding(guidance, 256, dtype)?.apply(g_in))?
+            }
+            _ => vec_,
+        };
+        let vec_ = (vec_ + y.apply(&self.vector_in))?;
+
+        let mut double = Vec::with_capacity(self.double_blocks.len());
+        for (block, controlnet_block) in self.double_blocks.iter().zip(&self.controlnet_blocks) {
+            (img, txt) = block.forward(&img, &txt, &vec_, &pe, &[])?;
+            double.push((controlnet_block.forward_autocast(&img)? * scale)?);
+        }
+        let txt_len = txt.dim(1)?;
+        let mut img = Tensor::cat(&[&txt, &img], 1)?;
+        let mut single = Vec::with_capacity(self.single_blocks.len());
+        for (block, controlnet_block) in self
+            .single_blocks
+            .iter()
+            .zip(&self.controlnet_single_blocks)
+        {
+            img = block.forward(&img, &vec_, &pe)?;
+            let img = img.narrow(1, txt_len, img.dim(1)? - txt_len)?;
+            single.push((controlnet_block.forward_autocast(&img)? * scale)?);
+        }
+        Ok(ControlNetResiduals { double, single })
+    }
+}
+
+impl QuantizedModel for ControlNet {
+    fn match_devices_all_layers(&mut self, dev: &Device) -> Result<()> {
+        if let Some(mode_embedder) = &mut self.mode_embedder {
+            *mode_embedder = Embedding::new(
+                mode_embedder.embeddings().to_device(dev)?,
+                mode_embedder.hidden_size(),
+            );
+        }
+        for block in &mut self.double_blocks {
+            block.match_devices(dev)?;
+        }
+        for block in &mut self.single_blocks {
+            block.match_devices(dev)?;
+        }
+        Ok(())
+    }
+
+    fn aggregate_layers(&mut self) -> Result<Vec<QuantizedModelLayer<'_>>> {
+        let mut layers = Vec::new();
+
+        {
+            let mut pre_layer_ct = vec![
+                &mut self.txt_in,
+                &mut self.img_in,
+                &mut self.controlnet_x_embedder,
+                &mut self.time_in.in_layer,
+                &mut self.time_in.out_layer,
+                &mut self.vector_in.in_layer,
+                &mut self.vector_in.out_layer,
+            ];
+
+            if let Some(layer) = &mut self.guidance_in {
+                pre_layer_ct.push(&mut layer.in_layer);
+                pre_layer_ct.push(&mut layer.out_layer);
+            }
+            layers.push(QuantizedModelLayer(pre_layer_ct));
+        }
+
+        for (block, controlnet_block) in self
+            .double_blocks
+            .iter_mut()
+            .zip(&mut self.controlnet_blocks)
+        {
+            let mut layer = block.layers();
+            layer.0.push(controlnet_block);
+            layers.push(layer);
+        }
+        for (block, controlnet_block) in self
+            .single_blocks
+            .iter_mut()
+            .zip(&mut self.controlnet_single_blocks)
+        {
+            let mut layer = block.layers();
+            layer.0.push(controlnet_block);
+            layers.push(layer);
+        }
+        Ok(layers)
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+
+use diffusion_rs_backend::QuantMethod;
+use diffusion_rs_common::core::{Device, Result, Tensor};
+use diffusion_rs_common::nn::{LayerNorm, Linear, Module};
+use diffusion_rs_common::VarBuilder;
+
+use super::model::{scaled_dot_product_attention, Config};
+
+/// The key and value projections of the image tokens in the image stream of a double stream block.
+#[derive(Debug, Clone)]
+pub(crate) struct IpAttention {
+    pub(super) to_k_ip: Arc<dyn QuantMethod>,
+    pub(super) to_v_ip: Arc<dyn QuantMethod>,
+}
+
+impl IpAttention {
+    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        let to_k_ip = diffusion_rs_backend::linear(
+            cfg.joint_attention_dim,
+            cfg.hidden_size(),
+            &None,
+            vb.pp("ip_adapter_double_stream_k_proj"),
+        )?;
+        let to_v_ip = diffusion_rs_backend::linear(
+            cfg.joint_attention_dim,
+            cfg.hidden_size(),
+            &None,
+            vb.pp("ip_adapter_double_stream_v_proj"),
+        )?;
+        Ok(Self { to_k_ip, to_v_ip })
+    }
+
+    /// Attend from the normalized image queries `q`, of shape `(batch, heads, seq_len, head_dim)` and before
+    /// the rotary embeddings, to the image tokens. Returns the attention output of shape
+    /// `(batch, seq_len, hidden_size)`, without the output projection.
+    pub(super) fn forward(&self, q: &Tensor, tokens: &Tensor) -> Result<Tensor> {
+        let (b, num_heads, _, _) = q.dims4()?;
+        let (_, n, c) = tokens.dims3()?;
+        let tokens = tokens.broadcast_as((b, n, c))?.contiguous()?;
+        let k = self
+            .to_k_ip
+            .forward_autocast(&tokens)?
+            .reshape((b, n, num_heads, ()))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        let v = self
+            .to_v_ip
+            .forward_autocast(&tokens)?
+            .reshape((b, n, num_heads, ()))?
+            .transpose(1, 2)?
+            .contiguous()?;
+        scaled_dot_product_attention(&q.contiguous()?, &k, &v, None)?
+            .transpose(1, 2)?
+            .flatten_from(2)
+    }
+}
+
+/// An XLabs IP-Adapter for FLUX: the projection of CLIP image embeddings into image tokens in the space of
+/// the T5 embeddings, and the projections of the image tokens into keys and values for the double stream
+/// blocks.
+#[derive(Debug, Clone)]
+pub struct IpAdapter {
+    proj: Linear,
+    norm: LayerNorm,
+    num_tokens: usize,
+    context_dim: usize,
+    attentions: Vec<Option<IpAttention>>,
+}
+
+impl IpAdapter {
+    /// Load the adapter from the XLabs weights layout, with the image projection in `ip_adapter_proj_model`
+    /// on `proj_device`, and the projections of each double stream block in
+    /// `double_blocks.<i>.processor`.
+    pub fn new(cfg: &Config, vb: VarBuilder, proj_device: &Device) -> Result<Self> {
+        let vb_proj = vb
+            .pp("ip_adapter_proj_model")
+            .set_device(proj_device.clone());
+        let (proj_dim, clip_dim) = vb_proj.pp("proj").get_unchecked("weight")?.dims2()?;
+        let context_dim = cfg.joint_attention_dim;
+        if proj_dim % context_dim != 0 {
+            diffusion_rs_common::bail!(
+                "The IP-Adapter projects into {proj_dim} dimensions, which are not tokens of {context_dim} dimensions."
+            );
+        }
+        let proj = diffusion_rs_common::linear(clip_dim, proj_dim, vb_proj.pp("proj"))?;
+        let norm = diffusion_rs_common::layer_norm(context_dim, 1e-5, vb_proj.pp("norm"))?;
+
+        let vb_blocks = vb.pp("double_blocks");
+        let attentions = (0..cfg.num_layers)
+            .map(|idx| {
+                let vb = vb_blocks.pp(idx).pp("processor");
+                vb.contains_tensor("ip_adapter_double_stream_k_proj.weight")
+                    .then(|| IpAttention::new(cfg, vb))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if attentions.iter().all(Option::is_none) {
+            diffusion_rs_common::bail!(
+                "The IP-Adapter has no projections for the double stream blocks."
+            );
+        }
+        Ok(Self {
+            proj,
+            norm,
+            num_tokens: proj_dim / context_dim,
+            context_dim,
+            attentions,
+        })
+    }
+
+    /// Dimension of the CLIP image embeddings this adapter expects.
+    pub fn clip_embedding_dim(&self) -> Result<usize> {
+        self.proj.weight().dim(1)
+    }
+
+    /// Project `(batch, clip_dim)` image embeddings into `(batch, num_tokens, context_dim)` image tokens.
+    pub fn image_tokens(&self, image_embeds: &Tensor) -> Result<Tensor> {
+        let b = image_embeds.dim(0)?;
+        self.proj
+            .forward(image_embeds)?
+            .reshape((b, self.num_tokens, self.context_dim))?
+            .apply(&self.norm)
+    }
+
+    /// Take the projections of the double stream blocks, to be added to the transformer.
+    pub(crate) fn take_attentions(&mut self) -> Vec<Option<IpAttention>> {
+        std::mem::take(&mut self.attentions)
+    }
+}
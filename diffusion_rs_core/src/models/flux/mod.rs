@@ -1,3 +1,12 @@
+mod chroma;
+mod controlnet;
+mod ip_adapter;
 mod model;
+mod offload;
+mod redux;
 
+pub use chroma::{Chroma as ChromaModel, ChromaConfig};
+pub use controlnet::{ControlNet as ControlNetModel, ControlNetConfig, ControlNetResiduals};
+pub use ip_adapter::IpAdapter as IpAdapterModel;
 pub use model::{Config as FluxConfig, Flux as FluxModel};
+pub use redux::{ReduxConfig, ReduxImageEncoder};
@@ -13,33 +13,142 @@ use tracing::{span, Span};
 
 use crate::models::{QuantizedModel, QuantizedModelLayer};
 
-const MLP_RATIO: f64 = 4.;
-const HIDDEN_SIZE: usize = 3072;
-const AXES_DIM: &[usize] = &[16, 56, 56];
-const THETA: usize = 10000;
+use super::controlnet::ControlNetResiduals;
+use super::ip_adapter::IpAttention;
+use super::offload::DiskOffloadedBlocks;
+
+pub(super) fn default_attention_head_dim() -> usize {
+    128
+}
+
+pub(super) fn default_axes_dims_rope() -> Vec<usize> {
+    vec![16, 56, 56]
+}
+
+pub(super) fn default_patch_size() -> usize {
+    1
+}
+
+pub(super) fn default_rope_theta() -> usize {
+    10000
+}
+
+pub(super) fn default_mlp_ratio() -> f64 {
+    4.
+}
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     pub in_channels: usize,
+    /// Channels of the predicted latents, defaults to `in_channels`. The conditioning variants such as
+    /// FLUX.1 Fill take the conditioning latents as extra input channels.
+    #[serde(default)]
+    pub out_channels: Option<usize>,
     pub pooled_projection_dim: usize,
     pub joint_attention_dim: usize,
     pub num_attention_heads: usize,
+    #[serde(default = "default_attention_head_dim")]
+    pub attention_head_dim: usize,
+    /// Dimensions of the rotary embedding of the text, height and width position ids.
+    #[serde(default = "default_axes_dims_rope")]
+    pub axes_dims_rope: Vec<usize>,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    #[serde(default = "default_rope_theta")]
+    pub rope_theta: usize,
+    #[serde(default = "default_mlp_ratio")]
+    pub mlp_ratio: f64,
     pub num_layers: usize,
     pub num_single_layers: usize,
     pub guidance_embeds: bool,
     pub quantization_config: Option<QuantizedConfig>,
 }
 
-fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
+impl Config {
+    pub(crate) fn out_channels(&self) -> usize {
+        self.out_channels.unwrap_or(self.in_channels)
+    }
+
+    pub(crate) fn hidden_size(&self) -> usize {
+        self.num_attention_heads * self.attention_head_dim
+    }
+
+    pub(crate) fn mlp_hidden_size(&self) -> usize {
+        (self.hidden_size() as f64 * self.mlp_ratio) as usize
+    }
+
+    /// Check that the dimensions of the config are consistent.
+    pub fn validate(&self) -> Result<()> {
+        if self.num_attention_heads == 0 || self.attention_head_dim == 0 {
+            diffusion_rs_common::bail!(
+                "expected a non-zero `num_attention_heads` and `attention_head_dim`, got {} and {}",
+                self.num_attention_heads,
+                self.attention_head_dim
+            )
+        }
+        // The position ids have a text, a height and a width axis.
+        if self.axes_dims_rope.len() != 3 {
+            diffusion_rs_common::bail!("expected 3 `axes_dims_rope`, got {:?}", self.axes_dims_rope)
+        }
+        if self.axes_dims_rope.iter().any(|dim| dim % 2 == 1) {
+            diffusion_rs_common::bail!(
+                "expected even `axes_dims_rope`, got {:?}",
+                self.axes_dims_rope
+            )
+        }
+        if self.axes_dims_rope.iter().sum::<usize>() != self.attention_head_dim {
+            diffusion_rs_common::bail!(
+                "`axes_dims_rope` {:?} must sum to `attention_head_dim` {}",
+                self.axes_dims_rope,
+                self.attention_head_dim
+            )
+        }
+        // Latents are packed in 2x2 patches by the pipeline, so the model itself does not patchify.
+        if self.patch_size != 1 {
+            diffusion_rs_common::bail!("unsupported `patch_size` {}, expected 1", self.patch_size)
+        }
+        if self.rope_theta == 0 {
+            diffusion_rs_common::bail!("expected a non-zero `rope_theta`")
+        }
+        let mlp_hidden_size = self.hidden_size() as f64 * self.mlp_ratio;
+        if mlp_hidden_size <= 0. || mlp_hidden_size.fract() != 0. {
+            diffusion_rs_common::bail!(
+                "`mlp_ratio` {} must give a whole MLP size for the hidden size {}",
+                self.mlp_ratio,
+                self.hidden_size()
+            )
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn layer_norm(dim: usize, vb: VarBuilder) -> Result<LayerNorm> {
     let ws = Tensor::ones(dim, vb.dtype(), vb.device())?;
     // Hack: use bias as 0s to take advantage of the fast kernel
     let bs = ws.zeros_like()?;
     Ok(LayerNorm::new(ws, bs, 1e-6))
 }
 
-fn scaled_dot_product_attention(q: &Tensor, k: &Tensor, v: &Tensor) -> Result<Tensor> {
+/// Attention with an optional additive `mask`, which is broadcast to the attention scores.
+pub(super) fn scaled_dot_product_attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
     let dim = q.dim(D::Minus1)?;
     let scale_factor = 1.0 / (dim as f64).sqrt();
+    if let Some(mask) = mask {
+        let attn_weights = (q
+            .to_dtype(DType::F32)?
+            .matmul(&k.to_dtype(DType::F32)?.t()?)?
+            * scale_factor)?
+            .broadcast_add(&mask.to_dtype(DType::F32)?)?;
+        let attn_weights = diffusion_rs_common::nn::ops::softmax_last_dim(&attn_weights)?;
+        return attn_weights
+            .matmul(&v.to_dtype(DType::F32)?)?
+            .to_dtype(q.dtype());
+    }
     diffusion_rs_backend::ops::sdpa(
         &q.to_dtype(DType::F32)?,
         &k.to_dtype(DType::F32)?,
@@ -94,14 +203,20 @@ fn apply_rope(x: &Tensor, freq_cis: &Tensor) -> Result<Tensor> {
     (fr0.broadcast_mul(&x0)? + fr1.broadcast_mul(&x1)?)?.reshape(dims.to_vec())
 }
 
-fn attention(q: &Tensor, k: &Tensor, v: &Tensor, pe: &Tensor) -> Result<Tensor> {
+fn attention(
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    pe: &Tensor,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
     let q = apply_rope(q, pe)?.contiguous()?;
     let k = apply_rope(k, pe)?.contiguous()?;
-    let x = scaled_dot_product_attention(&q, &k, v)?;
+    let x = scaled_dot_product_attention(&q, &k, v, mask)?;
     x.transpose(1, 2)?.flatten_from(2)
 }
 
-fn timestep_embedding(t: &Tensor, dim: usize, dtype: DType) -> Result<Tensor> {
+pub(super) fn timestep_embedding(t: &Tensor, dim: usize, dtype: DType) -> Result<Tensor> {
     const TIME_FACTOR: f64 = 1000.;
     const MAX_PERIOD: f64 = 10000.;
     if dim % 2 == 1 {
@@ -130,7 +245,7 @@ pub struct EmbedNd {
 }
 
 impl EmbedNd {
-    fn new(dim: usize, theta: usize, axes_dim: Vec<usize>) -> Self {
+    pub(super) fn new(dim: usize, theta: usize, axes_dim: Vec<usize>) -> Self {
         Self {
             dim,
             theta,
@@ -158,12 +273,12 @@ impl diffusion_rs_common::core::Module for EmbedNd {
 
 #[derive(Debug, Clone)]
 pub struct MlpEmbedder {
-    in_layer: Arc<dyn QuantMethod>,
-    out_layer: Arc<dyn QuantMethod>,
+    pub(super) in_layer: Arc<dyn QuantMethod>,
+    pub(super) out_layer: Arc<dyn QuantMethod>,
 }
 
 impl MlpEmbedder {
-    fn new(in_sz: usize, h_sz: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    pub(super) fn new(in_sz: usize, h_sz: usize, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let in_layer =
             diffusion_rs_backend::linear(in_sz, h_sz, &cfg.quantization_config, vb.pp("linear_1"))?;
         let out_layer =
@@ -208,13 +323,23 @@ impl QkNorm {
     }
 }
 
-struct ModulationOut {
+/// Shift, scale and gate of a modulated layer, each of shape `(batch, 1, hidden_size)`.
+pub(crate) struct ModulationOut {
     shift: Tensor,
     scale: Tensor,
     gate: Tensor,
 }
 
 impl ModulationOut {
+    /// Split `(batch, 3, hidden_size)` modulation vectors into the shift, scale and gate.
+    pub(crate) fn from_vectors(vectors: &Tensor) -> Result<Self> {
+        Ok(Self {
+            shift: vectors.narrow(1, 0, 1)?,
+            scale: vectors.narrow(1, 1, 1)?,
+            gate: vectors.narrow(1, 2, 1)?,
+        })
+    }
+
     fn scale_shift(&self, xs: &Tensor) -> Result<Tensor> {
         xs.broadcast_mul(&(&self.scale + 1.)?)?
             .broadcast_add(&self.shift)
@@ -306,6 +431,8 @@ pub struct SelfAttention {
     v: Arc<dyn QuantMethod>,
     norm: QkNorm,
     proj: Arc<dyn QuantMethod>,
+    /// The projections of the image tokens of an IP-Adapter, for the image stream of double stream blocks.
+    ip: Option<IpAttention>,
     num_attention_heads: usize,
     qkv: Span,
     fwd: Span,
@@ -390,6 +517,7 @@ impl SelfAttention {
             v,
             norm,
             proj,
+            ip: None,
             num_attention_heads,
             qkv: span!(tracing::Level::TRACE, "flux-selfattn-qkv"),
             fwd: span!(tracing::Level::TRACE, "flux-selfattn-fwd"),
@@ -430,7 +558,8 @@ impl SelfAttention {
     fn forward(&self, xs: &Tensor, pe: &Tensor) -> Result<Tensor> {
         let _span = self.fwd.enter();
         let (q, k, v) = self.qkv(xs)?;
-        self.proj.forward_autocast(&attention(&q, &k, &v, pe)?)
+        self.proj
+            .forward_autocast(&attention(&q, &k, &v, pe, None)?)
     }
 }
 
@@ -465,12 +594,13 @@ impl diffusion_rs_common::core::Module for Mlp {
 
 #[derive(Debug, Clone)]
 pub struct DoubleStreamBlock {
-    img_mod: Modulation2,
+    /// `None` when the modulations are supplied by the caller, see [`DoubleStreamBlock::new_pruned`].
+    img_mod: Option<Modulation2>,
     img_norm1: LayerNorm,
     img_attn: SelfAttention,
     img_norm2: LayerNorm,
     img_mlp: Mlp,
-    txt_mod: Modulation2,
+    txt_mod: Option<Modulation2>,
     txt_norm1: LayerNorm,
     txt_attn: SelfAttention,
     txt_norm2: LayerNorm,
@@ -478,10 +608,21 @@ pub struct DoubleStreamBlock {
 }
 
 impl DoubleStreamBlock {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let h_sz = HIDDEN_SIZE;
-        let mlp_sz = (h_sz as f64 * MLP_RATIO) as usize;
-        let img_mod = Modulation2::new(h_sz, cfg, vb.pp("norm1"))?;
+    pub(crate) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Self::new_with_modulation(cfg, vb, true)
+    }
+
+    /// A block without modulation layers, for models which compute the modulations of all blocks at once.
+    pub(crate) fn new_pruned(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Self::new_with_modulation(cfg, vb, false)
+    }
+
+    fn new_with_modulation(cfg: &Config, vb: VarBuilder, modulated: bool) -> Result<Self> {
+        let h_sz = cfg.hidden_size();
+        let mlp_sz = cfg.mlp_hidden_size();
+        let img_mod = modulated
+            .then(|| Modulation2::new(h_sz, cfg, vb.pp("norm1")))
+            .transpose()?;
         let img_norm1 = layer_norm(h_sz, vb.pp("img_norm1"))?;
         let img_attn = SelfAttention::new(
             h_sz,
@@ -494,7 +635,9 @@ impl DoubleStreamBlock {
         let img_norm2 = layer_norm(h_sz, vb.pp("img_norm2"))?;
         let img_mlp = Mlp::new(h_sz, mlp_sz, cfg, vb.pp("ff.net"))?;
 
-        let txt_mod = Modulation2::new(h_sz, cfg, vb.pp("norm1_context"))?;
+        let txt_mod = modulated
+            .then(|| Modulation2::new(h_sz, cfg, vb.pp("norm1_context")))
+            .transpose()?;
         let txt_norm1 = layer_norm(h_sz, vb.pp("txt_norm1"))?;
         let txt_attn = SelfAttention::new(
             h_sz,
@@ -520,15 +663,103 @@ impl DoubleStreamBlock {
         })
     }
 
-    fn forward(
+    /// Size of the linear layers of this block.
+    pub(crate) fn size_in_bytes(&self) -> Result<usize> {
+        let mut total = 0;
+        for layer in [
+            &self.img_attn.q,
+            &self.img_attn.k,
+            &self.img_attn.v,
+            &self.img_attn.proj,
+            &self.img_mlp.lin1,
+            &self.img_mlp.lin2,
+            &self.txt_attn.q,
+            &self.txt_attn.k,
+            &self.txt_attn.v,
+            &self.txt_attn.proj,
+            &self.txt_mlp.lin1,
+            &self.txt_mlp.lin2,
+        ]
+        .into_iter()
+        .chain(self.img_mod.as_ref().map(|m| &m.lin))
+        .chain(self.txt_mod.as_ref().map(|m| &m.lin))
+        .chain(
+            self.img_attn
+                .ip
+                .iter()
+                .flat_map(|ip| [&ip.to_k_ip, &ip.to_v_ip]),
+        ) {
+            total += layer.size_in_bytes()?;
+        }
+        Ok(total)
+    }
+
+    /// Move the non-quantized layers to `dev`.
+    pub(crate) fn match_devices(&mut self, dev: &Device) -> Result<()> {
+        self.img_attn.norm = self.img_attn.norm.to_device(dev)?;
+        self.txt_attn.norm = self.txt_attn.norm.to_device(dev)?;
+
+        self.img_norm1 = self.img_norm1.to_device(dev)?;
+        self.img_norm2 = self.img_norm2.to_device(dev)?;
+        self.txt_norm1 = self.txt_norm1.to_device(dev)?;
+        self.txt_norm2 = self.txt_norm2.to_device(dev)?;
+        Ok(())
+    }
+
+    pub(crate) fn layers(&mut self) -> QuantizedModelLayer<'_> {
+        let mut layer_ct = vec![
+            &mut self.img_attn.q,
+            &mut self.img_attn.k,
+            &mut self.img_attn.v,
+            &mut self.img_attn.proj,
+            &mut self.img_mlp.lin1,
+            &mut self.img_mlp.lin2,
+            &mut self.txt_attn.q,
+            &mut self.txt_attn.k,
+            &mut self.txt_attn.v,
+            &mut self.txt_attn.proj,
+            &mut self.txt_mlp.lin1,
+            &mut self.txt_mlp.lin2,
+        ];
+        layer_ct.extend(self.img_mod.as_mut().map(|m| &mut m.lin));
+        layer_ct.extend(self.txt_mod.as_mut().map(|m| &mut m.lin));
+        if let Some(ip) = &mut self.img_attn.ip {
+            layer_ct.extend([&mut ip.to_k_ip, &mut ip.to_v_ip]);
+        }
+        QuantizedModelLayer(layer_ct)
+    }
+
+    /// `ip_tokens` are the image tokens of IP-Adapter reference images and their scales, attended to by
+    /// the image stream if the block has IP-Adapter projections.
+    pub(super) fn forward(
         &self,
         img: &Tensor,
         txt: &Tensor,
         vec_: &Tensor,
         pe: &Tensor,
+        ip_tokens: &[(Tensor, f64)],
+    ) -> Result<(Tensor, Tensor)> {
+        let (Some(img_mod), Some(txt_mod)) = (&self.img_mod, &self.txt_mod) else {
+            diffusion_rs_common::bail!("expected the modulations of a pruned block to be supplied")
+        };
+        let img_mods = img_mod.forward(vec_)?; // shift, scale, gate
+        let txt_mods = txt_mod.forward(vec_)?; // shift, scale, gate
+        self.forward_modulated(img, txt, img_mods, txt_mods, pe, None, ip_tokens)
+    }
+
+    /// Run the block with the given image and text modulations, for the attention and the MLP. `mask` is
+    /// added to the attention scores.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn forward_modulated(
+        &self,
+        img: &Tensor,
+        txt: &Tensor,
+        (img_mod1, img_mod2): (ModulationOut, ModulationOut),
+        (txt_mod1, txt_mod2): (ModulationOut, ModulationOut),
+        pe: &Tensor,
+        mask: Option<&Tensor>,
+        ip_tokens: &[(Tensor, f64)],
     ) -> Result<(Tensor, Tensor)> {
-        let (img_mod1, img_mod2) = self.img_mod.forward(vec_)?; // shift, scale, gate
-        let (txt_mod1, txt_mod2) = self.txt_mod.forward(vec_)?; // shift, scale, gate
         let img_modulated = img.apply(&self.img_norm1)?;
         let img_modulated = img_mod1.scale_shift(&img_modulated)?;
         let (img_q, img_k, img_v) = self.img_attn.qkv(&img_modulated)?;
@@ -537,21 +768,28 @@ impl DoubleStreamBlock {
         let txt_modulated = txt_mod1.scale_shift(&txt_modulated)?;
         let (txt_q, txt_k, txt_v) = self.txt_attn.qkv(&txt_modulated)?;
 
-        let q = Tensor::cat(&[txt_q, img_q], 2)?;
+        let q = Tensor::cat(&[&txt_q, &img_q], 2)?;
         let k = Tensor::cat(&[txt_k, img_k], 2)?;
         let v = Tensor::cat(&[txt_v, img_v], 2)?;
 
-        let attn = attention(&q, &k, &v, pe)?;
+        let attn = attention(&q, &k, &v, pe, mask)?;
         let txt_attn = attn.narrow(1, 0, txt.dim(1)?)?;
         let img_attn = attn.narrow(1, txt.dim(1)?, attn.dim(1)? - txt.dim(1)?)?;
 
-        let img = (img + img_mod1.gate(&self.img_attn.proj.forward_autocast(&img_attn)?))?;
-        let img = (&img
+        let img_attn = self.img_attn.proj.forward_autocast(&img_attn)?;
+        let img = (img + img_mod1.gate(&img_attn)?)?;
+        let mut img = (&img
             + img_mod2.gate(
                 &img_mod2
                     .scale_shift(&img.apply(&self.img_norm2)?)?
                     .apply(&self.img_mlp)?,
             )?)?;
+        // The IP-Adapter attention is added to the image stream ungated, after the MLP residual.
+        if let Some(ip) = &self.img_attn.ip {
+            for (tokens, scale) in ip_tokens {
+                img = (img + (ip.forward(&img_q, tokens)? * *scale)?)?;
+            }
+        }
 
         let txt = (txt + txt_mod1.gate(&self.txt_attn.proj.forward_autocast(&txt_attn)?))?;
         let txt = (&txt
@@ -574,14 +812,24 @@ pub struct SingleStreamBlock {
     linear2: Arc<dyn QuantMethod>,
     norm: QkNorm,
     pre_norm: LayerNorm,
-    modulation: Modulation1,
+    /// `None` when the modulation is supplied by the caller, see [`SingleStreamBlock::new_pruned`].
+    modulation: Option<Modulation1>,
     num_attention_heads: usize,
 }
 
 impl SingleStreamBlock {
-    fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let h_sz = HIDDEN_SIZE;
-        let mlp_sz = (h_sz as f64 * MLP_RATIO) as usize;
+    pub(crate) fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Self::new_with_modulation(cfg, vb, true)
+    }
+
+    /// A block without a modulation layer, for models which compute the modulations of all blocks at once.
+    pub(crate) fn new_pruned(cfg: &Config, vb: VarBuilder) -> Result<Self> {
+        Self::new_with_modulation(cfg, vb, false)
+    }
+
+    fn new_with_modulation(cfg: &Config, vb: VarBuilder, modulated: bool) -> Result<Self> {
+        let h_sz = cfg.hidden_size();
+        let mlp_sz = cfg.mlp_hidden_size();
         let head_dim = h_sz / cfg.num_attention_heads;
 
         let q = diffusion_rs_backend::linear_b(
@@ -621,7 +869,9 @@ impl SingleStreamBlock {
         )?;
         let norm = QkNorm::new(head_dim, vb.pp("attn.norm_q"), vb.pp("attn.norm_k"))?;
         let pre_norm = layer_norm(h_sz, vb.pp("pre_norm"))?;
-        let modulation = Modulation1::new(h_sz, cfg, vb.pp("norm"))?;
+        let modulation = modulated
+            .then(|| Modulation1::new(h_sz, cfg, vb.pp("norm")))
+            .transpose()?;
         Ok(Self {
             q,
             k,
@@ -635,8 +885,52 @@ impl SingleStreamBlock {
         })
     }
 
-    fn forward(&self, xs: &Tensor, vec_: &Tensor, pe: &Tensor) -> Result<Tensor> {
-        let mod_ = self.modulation.forward(vec_)?;
+    /// Size of the linear layers of this block.
+    pub(crate) fn size_in_bytes(&self) -> Result<usize> {
+        let mut total = 0;
+        for layer in [&self.q, &self.k, &self.v, &self.proj_mlp, &self.linear2]
+            .into_iter()
+            .chain(self.modulation.as_ref().map(|m| &m.lin))
+        {
+            total += layer.size_in_bytes()?;
+        }
+        Ok(total)
+    }
+
+    /// Move the non-quantized layers to `dev`.
+    pub(crate) fn match_devices(&mut self, dev: &Device) -> Result<()> {
+        self.norm = self.norm.to_device(dev)?;
+        self.pre_norm = self.pre_norm.to_device(dev)?;
+        Ok(())
+    }
+
+    pub(crate) fn layers(&mut self) -> QuantizedModelLayer<'_> {
+        let mut layer_ct = vec![
+            &mut self.q,
+            &mut self.k,
+            &mut self.v,
+            &mut self.proj_mlp,
+            &mut self.linear2,
+        ];
+        layer_ct.extend(self.modulation.as_mut().map(|m| &mut m.lin));
+        QuantizedModelLayer(layer_ct)
+    }
+
+    pub(super) fn forward(&self, xs: &Tensor, vec_: &Tensor, pe: &Tensor) -> Result<Tensor> {
+        let Some(modulation) = &self.modulation else {
+            diffusion_rs_common::bail!("expected the modulation of a pruned block to be supplied")
+        };
+        self.forward_modulated(xs, modulation.forward(vec_)?, pe, None)
+    }
+
+    /// Run the block with the given modulation. `mask` is added to the attention scores.
+    pub(crate) fn forward_modulated(
+        &self,
+        xs: &Tensor,
+        mod_: ModulationOut,
+        pe: &Tensor,
+        mask: Option<&Tensor>,
+    ) -> Result<Tensor> {
         let x_mod = mod_.scale_shift(&xs.apply(&self.pre_norm)?)?;
         let mut q = self.q.forward_autocast(&x_mod)?;
         let mut k = self.k.forward_autocast(&x_mod)?;
@@ -654,7 +948,7 @@ impl SingleStreamBlock {
         q = q.apply(&self.norm.query_norm)?;
         k = k.apply(&self.norm.key_norm)?;
         let mlp = self.proj_mlp.forward_autocast(&x_mod)?;
-        let attn = attention(&q, &k, &v, pe)?;
+        let attn = attention(&q, &k, &v, pe, mask)?;
         let output = self
             .linear2
             .forward_autocast(&Tensor::cat(&[attn, mlp.gelu()?], 2)?)?;
@@ -715,23 +1009,13 @@ pub struct Flux {
     pe_embedder: EmbedNd,
     double_blocks: Vec<DoubleStreamBlock>,
     single_blocks: Vec<SingleStreamBlock>,
+    offloaded_blocks: Option<Arc<DiskOffloadedBlocks>>,
     final_layer: LastLayer,
 }
 
 impl Flux {
     pub fn new(cfg: &Config, vb: VarBuilder) -> Result<Self> {
-        let img_in = diffusion_rs_backend::linear(
-            cfg.in_channels,
-            HIDDEN_SIZE,
-            &cfg.quantization_config,
-            vb.pp("x_embedder"),
-        )?;
-        let txt_in = diffusion_rs_backend::linear(
-            cfg.joint_attention_dim,
-            HIDDEN_SIZE,
-            &cfg.quantization_config,
-            vb.pp("context_embedder"),
-        )?;
+        cfg.validate()?;
         let mut double_blocks = Vec::with_capacity(cfg.num_layers);
         let vb_d = vb.pp("transformer_blocks");
         for idx in NiceProgressBar::<_, 'r'>(0..cfg.num_layers, "Loading double stream blocks") {
@@ -746,22 +1030,57 @@ impl Flux {
             let sb = SingleStreamBlock::new(cfg, vb_s.pp(idx))?;
             single_blocks.push(sb)
         }
+        Self::new_with_blocks(cfg, vb, double_blocks, single_blocks, None)
+    }
+
+    /// Load the model, but keep the double and single stream blocks on disk. They are read from the lazy
+    /// `vb` one at a time during `forward`, keeping at most `memory_budget` bytes of blocks resident.
+    pub fn new_disk_offloaded(
+        cfg: &Config,
+        vb: VarBuilder<'static>,
+        memory_budget: usize,
+    ) -> Result<Self> {
+        cfg.validate()?;
+        let offloaded_blocks = DiskOffloadedBlocks::new(cfg, vb.clone(), true, memory_budget);
+        Self::new_with_blocks(cfg, vb, vec![], vec![], Some(Arc::new(offloaded_blocks)))
+    }
+
+    fn new_with_blocks(
+        cfg: &Config,
+        vb: VarBuilder,
+        double_blocks: Vec<DoubleStreamBlock>,
+        single_blocks: Vec<SingleStreamBlock>,
+        offloaded_blocks: Option<Arc<DiskOffloadedBlocks>>,
+    ) -> Result<Self> {
+        let hidden_size = cfg.hidden_size();
+        let img_in = diffusion_rs_backend::linear(
+            cfg.in_channels,
+            hidden_size,
+            &cfg.quantization_config,
+            vb.pp("x_embedder"),
+        )?;
+        let txt_in = diffusion_rs_backend::linear(
+            cfg.joint_attention_dim,
+            hidden_size,
+            &cfg.quantization_config,
+            vb.pp("context_embedder"),
+        )?;
         let time_in = MlpEmbedder::new(
             256,
-            HIDDEN_SIZE,
+            hidden_size,
             cfg,
             vb.pp("time_text_embed.timestep_embedder"),
         )?;
         let vector_in = MlpEmbedder::new(
             cfg.pooled_projection_dim,
-            HIDDEN_SIZE,
+            hidden_size,
             cfg,
             vb.pp("time_text_embed.text_embedder"),
         )?;
         let guidance_in = if cfg.guidance_embeds {
             let mlp = MlpEmbedder::new(
                 256,
-                HIDDEN_SIZE,
+                hidden_size,
                 cfg,
                 vb.pp("time_text_embed.guidance_embedder"),
             )?;
@@ -769,9 +1088,12 @@ impl Flux {
         } else {
             None
         };
-        let final_layer = LastLayer::new(HIDDEN_SIZE, 1, cfg.in_channels, cfg, vb)?;
-        let pe_dim = HIDDEN_SIZE / cfg.num_attention_heads;
-        let pe_embedder = EmbedNd::new(pe_dim, THETA, AXES_DIM.to_vec());
+        let final_layer = LastLayer::new(hidden_size, cfg.patch_size, cfg.out_channels(), cfg, vb)?;
+        let pe_embedder = EmbedNd::new(
+            cfg.attention_head_dim,
+            cfg.rope_theta,
+            cfg.axes_dims_rope.clone(),
+        );
 
         Ok(Self {
             img_in,
@@ -782,10 +1104,13 @@ impl Flux {
             pe_embedder,
             double_blocks,
             single_blocks,
+            offloaded_blocks,
             final_layer,
         })
     }
 
+    /// `residuals` of ControlNets are added to the outputs of the double and single stream blocks, and
+    /// `ip_tokens` are the image tokens of IP-Adapter reference images with their scales.
     #[allow(clippy::too_many_arguments)]
     pub fn forward(
         &self,
@@ -796,6 +1121,8 @@ impl Flux {
         timesteps: &Tensor,
         y: &Tensor,
         guidance: Option<&Tensor>,
+        residuals: Option<&ControlNetResiduals>,
+        ip_tokens: &[(Tensor, f64)],
     ) -> Result<Tensor> {
         if txt.rank() != 3 {
             diffusion_rs_common::bail!("unexpected shape for txt {:?}", txt.shape())
@@ -820,21 +1147,74 @@ impl Flux {
         let vec_ = (vec_ + y.apply(&self.vector_in))?;
 
         // Double blocks
-        for block in self.double_blocks.iter() {
-            (img, txt) = block.forward(&img, &txt, &vec_, &pe)?;
+        let num_double_blocks = match &self.offloaded_blocks {
+            Some(blocks) => blocks.num_double_blocks(),
+            None => self.double_blocks.len(),
+        };
+        for idx in 0..num_double_blocks {
+            (img, txt) = match &self.offloaded_blocks {
+                Some(blocks) => blocks
+                    .double_block(idx)?
+                    .forward(&img, &txt, &vec_, &pe, ip_tokens)?,
+                None => self.double_blocks[idx].forward(&img, &txt, &vec_, &pe, ip_tokens)?,
+            };
+            if let Some(residual) = residuals.and_then(|residuals| {
+                ControlNetResiduals::block_residual(&residuals.double, idx, num_double_blocks)
+            }) {
+                img = (img + residual)?;
+            }
         }
         // Single blocks
+        let txt_len = txt.dim(1)?;
         let mut img = Tensor::cat(&[&txt, &img], 1)?;
-        for block in self.single_blocks.iter() {
-            img = block.forward(&img, &vec_, &pe)?;
+        let num_single_blocks = match &self.offloaded_blocks {
+            Some(blocks) => blocks.num_single_blocks(),
+            None => self.single_blocks.len(),
+        };
+        for idx in 0..num_single_blocks {
+            img = match &self.offloaded_blocks {
+                Some(blocks) => blocks.single_block(idx)?.forward(&img, &vec_, &pe)?,
+                None => self.single_blocks[idx].forward(&img, &vec_, &pe)?,
+            };
+            // Only the image tokens receive the residuals.
+            if let Some(residual) = residuals.and_then(|residuals| {
+                ControlNetResiduals::block_residual(&residuals.single, idx, num_single_blocks)
+            }) {
+                img = Tensor::cat(
+                    &[
+                        &img.narrow(1, 0, txt_len)?,
+                        &(img.i((.., txt_len..))? + residual)?,
+                    ],
+                    1,
+                )?;
+            }
         }
-        let img = img.i((.., txt.dim(1)?..))?;
+        let img = img.i((.., txt_len..))?;
         self.final_layer.forward(&img, &vec_)
     }
 
     pub fn is_guidance(&self) -> bool {
         self.guidance_in.is_some()
     }
+
+    /// Add the projections of an IP-Adapter to the double stream blocks, replacing those of a previous
+    /// IP-Adapter.
+    pub(crate) fn set_ip_adapter(&mut self, attentions: Vec<Option<IpAttention>>) -> Result<()> {
+        if self.offloaded_blocks.is_some() {
+            diffusion_rs_common::bail!("IP-Adapters are not supported with disk offloading.");
+        }
+        if attentions.len() != self.double_blocks.len() {
+            diffusion_rs_common::bail!(
+                "The IP-Adapter has projections for {} double stream blocks, but the transformer has {}.",
+                attentions.len(),
+                self.double_blocks.len()
+            );
+        }
+        for (block, ip) in self.double_blocks.iter_mut().zip(attentions) {
+            block.img_attn.ip = ip;
+        }
+        Ok(())
+    }
 }
 
 impl QuantizedModel for Flux {
@@ -846,19 +1226,10 @@ impl QuantizedModel for Flux {
         };
 
         for block in &mut self.double_blocks {
-            block.img_attn.norm = block.img_attn.norm.to_device(dev)?;
-            block.txt_attn.norm = block.txt_attn.norm.to_device(dev)?;
-
-            block.img_norm1 = block.img_norm1.to_device(dev)?;
-            block.img_norm2 = block.img_norm2.to_device(dev)?;
-            block.txt_norm1 = block.txt_norm1.to_device(dev)?;
-            block.txt_norm2 = block.txt_norm2.to_device(dev)?;
+            block.match_devices(dev)?;
         }
-
         for block in &mut self.single_blocks {
-            block.norm = block.norm.to_device(dev)?;
-
-            block.pre_norm = block.pre_norm.to_device(dev)?;
+            block.match_devices(dev)?;
         }
         Ok(())
     }
@@ -892,37 +1263,10 @@ impl QuantizedModel for Flux {
         }
 
         for block in &mut self.double_blocks {
-            let layer_ct = vec![
-                &mut block.img_attn.q,
-                &mut block.img_attn.k,
-                &mut block.img_attn.v,
-                &mut block.img_attn.proj,
-                &mut block.img_mlp.lin1,
-                &mut block.img_mlp.lin2,
-                &mut block.img_mod.lin,
-                &mut block.txt_attn.q,
-                &mut block.txt_attn.k,
-                &mut block.txt_attn.v,
-                &mut block.txt_attn.proj,
-                &mut block.txt_mlp.lin1,
-                &mut block.txt_mlp.lin2,
-                &mut block.txt_mod.lin,
-            ];
-
-            layers.push(QuantizedModelLayer(layer_ct));
+            layers.push(block.layers());
         }
-
         for block in &mut self.single_blocks {
-            let layer_ct = vec![
-                &mut block.q,
-                &mut block.k,
-                &mut block.v,
-                &mut block.modulation.lin,
-                &mut block.proj_mlp,
-                &mut block.linear2,
-            ];
-
-            layers.push(QuantizedModelLayer(layer_ct));
+            layers.push(block.layers());
         }
         Ok(layers)
     }
@@ -0,0 +1,197 @@
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use diffusion_rs_common::core::Result;
+use diffusion_rs_common::VarBuilder;
+use tracing::warn;
+
+use super::model::{Config, DoubleStreamBlock, SingleStreamBlock};
+
+#[derive(Debug, Clone)]
+enum FluxBlock {
+    Double(Arc<DoubleStreamBlock>),
+    Single(Arc<SingleStreamBlock>),
+}
+
+struct ResidentBlock {
+    idx: usize,
+    block: FluxBlock,
+    size_in_bytes: usize,
+}
+
+#[derive(Default)]
+struct ResidentBlocks {
+    /// Ordered from least to most recently used.
+    blocks: VecDeque<ResidentBlock>,
+    size_in_bytes: usize,
+    prefetch: Option<(usize, JoinHandle<Result<ResidentBlock>>)>,
+    warned_budget: bool,
+}
+
+/// Double and single stream blocks which stay memory-mapped on disk and are materialized on demand.
+///
+/// Blocks are indexed with the double stream blocks first, followed by the single stream blocks, matching
+/// the order in which `Flux::forward` uses them. When a block is requested, the following block is loaded
+/// on a background thread so that reading it from disk overlaps with the computation.
+pub(crate) struct DiskOffloadedBlocks {
+    vb: VarBuilder<'static>,
+    cfg: Config,
+    /// Whether the blocks have their own modulation layers, see [`DoubleStreamBlock::new_pruned`].
+    modulated: bool,
+    memory_budget: usize,
+    state: Mutex<ResidentBlocks>,
+}
+
+impl Debug for DiskOffloadedBlocks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DiskOffloadedBlocks")
+            .field("num_layers", &self.cfg.num_layers)
+            .field("num_single_layers", &self.cfg.num_single_layers)
+            .field("memory_budget", &self.memory_budget)
+            .finish()
+    }
+}
+
+fn load_block(
+    cfg: &Config,
+    vb: &VarBuilder<'static>,
+    modulated: bool,
+    idx: usize,
+) -> Result<ResidentBlock> {
+    let (block, size_in_bytes) = if idx < cfg.num_layers {
+        let vb = vb.pp("transformer_blocks").pp(idx);
+        let block = if modulated {
+            DoubleStreamBlock::new(cfg, vb)?
+        } else {
+            DoubleStreamBlock::new_pruned(cfg, vb)?
+        };
+        let size = block.size_in_bytes()?;
+        (FluxBlock::Double(Arc::new(block)), size)
+    } else {
+        let vb = vb.pp("single_transformer_blocks").pp(idx - cfg.num_layers);
+        let block = if modulated {
+            SingleStreamBlock::new(cfg, vb)?
+        } else {
+            SingleStreamBlock::new_pruned(cfg, vb)?
+        };
+        let size = block.size_in_bytes()?;
+        (FluxBlock::Single(Arc::new(block)), size)
+    };
+    Ok(ResidentBlock {
+        idx,
+        block,
+        size_in_bytes,
+    })
+}
+
+fn join_prefetch(handle: JoinHandle<Result<ResidentBlock>>) -> Result<ResidentBlock> {
+    handle.join().map_err(|_| {
+        diffusion_rs_common::core::Error::Msg("Block prefetching thread panicked.".to_string())
+    })?
+}
+
+impl DiskOffloadedBlocks {
+    pub(crate) fn new(
+        cfg: &Config,
+        vb: VarBuilder<'static>,
+        modulated: bool,
+        memory_budget: usize,
+    ) -> Self {
+        Self {
+            vb,
+            cfg: cfg.clone(),
+            modulated,
+            memory_budget,
+            state: Mutex::new(ResidentBlocks::default()),
+        }
+    }
+
+    pub(crate) fn num_double_blocks(&self) -> usize {
+        self.cfg.num_layers
+    }
+
+    pub(crate) fn num_single_blocks(&self) -> usize {
+        self.cfg.num_single_layers
+    }
+
+    pub(crate) fn double_block(&self, idx: usize) -> Result<Arc<DoubleStreamBlock>> {
+        match self.get(idx)? {
+            FluxBlock::Double(block) => Ok(block),
+            FluxBlock::Single(_) => {
+                diffusion_rs_common::bail!("Expected double stream block {idx}")
+            }
+        }
+    }
+
+    pub(crate) fn single_block(&self, idx: usize) -> Result<Arc<SingleStreamBlock>> {
+        match self.get(self.cfg.num_layers + idx)? {
+            FluxBlock::Single(block) => Ok(block),
+            FluxBlock::Double(_) => {
+                diffusion_rs_common::bail!("Expected single stream block {idx}")
+            }
+        }
+    }
+
+    fn get(&self, idx: usize) -> Result<FluxBlock> {
+        let mut state = self.state.lock().expect("Could not lock resident blocks!");
+
+        let block = if let Some(pos) = state.blocks.iter().position(|b| b.idx == idx) {
+            let resident = state.blocks.remove(pos).unwrap();
+            let block = resident.block.clone();
+            state.blocks.push_back(resident);
+            block
+        } else {
+            let resident = match state.prefetch.take() {
+                Some((prefetched, handle)) if prefetched == idx => join_prefetch(handle)?,
+                Some((_, handle)) => {
+                    // Out of order access: the prefetched block is not needed right now.
+                    join_prefetch(handle)?;
+                    load_block(&self.cfg, &self.vb, self.modulated, idx)?
+                }
+                None => load_block(&self.cfg, &self.vb, self.modulated, idx)?,
+            };
+            let block = resident.block.clone();
+            state.size_in_bytes += resident.size_in_bytes;
+            state.blocks.push_back(resident);
+            block
+        };
+
+        // Make room for the current block and the one which will be prefetched, which is assumed to be of a
+        // similar size. The current block always stays resident.
+        let current_size = state.blocks.back().map(|b| b.size_in_bytes).unwrap_or(0);
+        while state.blocks.len() > 1 && state.size_in_bytes + current_size > self.memory_budget {
+            let evicted = state.blocks.pop_front().unwrap();
+            state.size_in_bytes -= evicted.size_in_bytes;
+        }
+        if state.size_in_bytes + current_size > self.memory_budget && !state.warned_budget {
+            warn!(
+                "Disk offloading memory budget of {} MiB is smaller than two transformer blocks ({} MiB), it will be exceeded.",
+                self.memory_budget / (1024 * 1024),
+                (state.size_in_bytes + current_size) / (1024 * 1024)
+            );
+            state.warned_budget = true;
+        }
+
+        let num_blocks = self.cfg.num_layers + self.cfg.num_single_layers;
+        let next = (idx + 1) % num_blocks;
+        let prefetching = matches!(state.prefetch, Some((prefetched, _)) if prefetched == next);
+        if !prefetching && !state.blocks.iter().any(|b| b.idx == next) {
+            if let Some((_, handle)) = state.prefetch.take() {
+                join_prefetch(handle)?;
+            }
+            let vb = self.vb.clone();
+            let cfg = self.cfg.clone();
+            let modulated = self.modulated;
+            state.prefetch = Some((
+                next,
+                thread::spawn(move || load_block(&cfg, &vb, modulated, next)),
+            ));
+        }
+
+        Ok(block)
+    }
+}
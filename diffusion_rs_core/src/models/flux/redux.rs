@@ -0,0 +1,55 @@
+use diffusion_rs_common::core::{Result, Tensor};
+use diffusion_rs_common::nn::{Linear, Module};
+use diffusion_rs_common::VarBuilder;
+use serde::Deserialize;
+
+fn default_redux_dim() -> usize {
+    1152
+}
+
+fn default_txt_in_features() -> usize {
+    4096
+}
+
+/// The config of a diffusers `ReduxImageEncoder`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReduxConfig {
+    /// Dimension of the SigLIP image features.
+    #[serde(default = "default_redux_dim")]
+    pub redux_dim: usize,
+    /// Dimension of the T5 embeddings the image tokens are appended to.
+    #[serde(default = "default_txt_in_features")]
+    pub txt_in_features: usize,
+}
+
+/// The FLUX.1 Redux prior, which projects SigLIP image features into image tokens in the space of the T5
+/// embeddings.
+#[derive(Debug, Clone)]
+pub struct ReduxImageEncoder {
+    redux_up: Linear,
+    redux_down: Linear,
+}
+
+impl ReduxImageEncoder {
+    pub fn new(cfg: &ReduxConfig, vb: VarBuilder) -> Result<Self> {
+        let redux_up =
+            diffusion_rs_common::linear(cfg.redux_dim, cfg.txt_in_features * 3, vb.pp("redux_up"))?;
+        let redux_down = diffusion_rs_common::linear(
+            cfg.txt_in_features * 3,
+            cfg.txt_in_features,
+            vb.pp("redux_down"),
+        )?;
+        Ok(Self {
+            redux_up,
+            redux_down,
+        })
+    }
+}
+
+impl Module for ReduxImageEncoder {
+    /// Project `(batch, num_patches, redux_dim)` image features into `(batch, num_patches, txt_in_features)`
+    /// image tokens.
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.redux_down.forward(&self.redux_up.forward(xs)?.silu()?)
+    }
+}
@@ -1,17 +1,25 @@
 mod clip;
 mod flux;
+mod siglip;
 mod t5;
 mod vaes;
 
 use std::sync::Arc;
 
-pub use clip::{ClipTextConfig, ClipTextTransformer};
+pub use clip::{
+    clip_image_to_tensor, ClipTextConfig, ClipTextModelWithProjection, ClipTextOutput,
+    ClipTextTransformer, ClipVisionConfig, ClipVisionOutput, ClipVisionTransformer,
+};
 use diffusion_rs_backend::QuantMethod;
 use diffusion_rs_common::core::{Device, Result};
-pub use flux::{FluxConfig, FluxModel};
+pub use flux::{
+    ChromaConfig, ChromaModel, ControlNetConfig, ControlNetModel, ControlNetResiduals, FluxConfig,
+    FluxModel, IpAdapterModel, ReduxConfig, ReduxImageEncoder,
+};
+pub use siglip::{SiglipVisionConfig, SiglipVisionTransformer};
 pub use t5::{T5Config, T5EncoderModel};
 
-pub(crate) use vaes::{dispatch_load_vae_model, VAEModel};
+pub(crate) use vaes::{check_vae_config, dispatch_load_vae_model, VAEModel};
 
 #[derive(Debug)]
 pub struct QuantizedModelLayer<'a>(pub Vec<&'a mut Arc<dyn QuantMethod>>);
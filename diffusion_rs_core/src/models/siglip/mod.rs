@@ -0,0 +1,192 @@
+#![allow(clippy::cast_possible_truncation)]
+
+use diffusion_rs_common::core::{Result, Tensor};
+use diffusion_rs_common::nn::{Conv2dConfig, Module};
+use serde::Deserialize;
+
+use super::clip::{Activation, ClipEncoder, ClipEncoderConfig};
+
+fn default_hidden_size() -> usize {
+    768
+}
+
+fn default_intermediate_size() -> usize {
+    3072
+}
+
+fn default_num_layers() -> usize {
+    12
+}
+
+fn default_num_attention_heads() -> usize {
+    12
+}
+
+fn default_num_channels() -> usize {
+    3
+}
+
+fn default_image_size() -> usize {
+    224
+}
+
+fn default_patch_size() -> usize {
+    16
+}
+
+fn default_hidden_act() -> Activation {
+    Activation::GeluPytorchTanh
+}
+
+fn default_layer_norm_eps() -> f64 {
+    1e-6
+}
+
+/// The config of a HF `SiglipVisionModel`. The defaults are the ones of `transformers`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SiglipVisionConfig {
+    #[serde(default = "default_hidden_size")]
+    pub hidden_size: usize,
+    #[serde(default = "default_intermediate_size")]
+    pub intermediate_size: usize,
+    #[serde(default = "default_num_layers")]
+    pub num_hidden_layers: usize,
+    #[serde(default = "default_num_attention_heads")]
+    pub num_attention_heads: usize,
+    #[serde(default = "default_num_channels")]
+    pub num_channels: usize,
+    #[serde(default = "default_image_size")]
+    pub image_size: usize,
+    #[serde(default = "default_patch_size")]
+    pub patch_size: usize,
+    #[serde(default = "default_hidden_act")]
+    pub hidden_act: Activation,
+    #[serde(default = "default_layer_norm_eps")]
+    pub layer_norm_eps: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SiglipVisionConfigFile {
+    /// The `config.json` of a full `SiglipModel`.
+    Siglip {
+        vision_config: SiglipVisionConfig,
+    },
+    Vision(SiglipVisionConfig),
+}
+
+impl SiglipVisionConfig {
+    /// Parse the `config.json` of a `SiglipVisionModel`, or of a full `SiglipModel` whose vision tower is
+    /// used.
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        Ok(match serde_json::from_str(json)? {
+            SiglipVisionConfigFile::Siglip { vision_config } => vision_config,
+            SiglipVisionConfigFile::Vision(config) => config,
+        })
+    }
+
+    fn num_patches(&self) -> usize {
+        (self.image_size / self.patch_size).pow(2)
+    }
+
+    fn encoder_config(&self) -> ClipEncoderConfig {
+        ClipEncoderConfig {
+            hidden_size: self.hidden_size,
+            intermediate_size: self.intermediate_size,
+            num_hidden_layers: self.num_hidden_layers,
+            num_attention_heads: self.num_attention_heads,
+            hidden_act: self.hidden_act,
+            layer_norm_eps: self.layer_norm_eps,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SiglipVisionEmbeddings {
+    patch_embedding: diffusion_rs_common::nn::Conv2d,
+    position_embedding: diffusion_rs_common::nn::Embedding,
+    position_ids: Tensor,
+}
+
+impl SiglipVisionEmbeddings {
+    fn new(vs: diffusion_rs_common::VarBuilder, c: &SiglipVisionConfig) -> Result<Self> {
+        let patch_embedding = diffusion_rs_common::conv2d(
+            c.num_channels,
+            c.hidden_size,
+            c.patch_size,
+            Conv2dConfig {
+                stride: c.patch_size,
+                ..Default::default()
+            },
+            vs.pp("patch_embedding"),
+        )?;
+        let position_embedding = diffusion_rs_common::embedding(
+            c.num_patches(),
+            c.hidden_size,
+            vs.pp("position_embedding"),
+        )?;
+        let position_ids =
+            Tensor::arange(0u32, c.num_patches() as u32, vs.device())?.unsqueeze(0)?;
+        Ok(Self {
+            patch_embedding,
+            position_embedding,
+            position_ids,
+        })
+    }
+}
+
+impl Module for SiglipVisionEmbeddings {
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let patch_embeds = self
+            .patch_embedding
+            .forward(pixel_values)?
+            .flatten_from(2)?
+            .transpose(1, 2)?;
+        let position_embedding = self.position_embedding.forward(&self.position_ids)?;
+        patch_embeds.broadcast_add(&position_embedding)
+    }
+}
+
+/// The vision tower of SigLIP, without the attention pooling head.
+#[derive(Clone, Debug)]
+pub struct SiglipVisionTransformer {
+    embeddings: SiglipVisionEmbeddings,
+    encoder: ClipEncoder,
+    post_layernorm: diffusion_rs_common::nn::LayerNorm,
+    image_size: usize,
+}
+
+impl SiglipVisionTransformer {
+    /// Load the weights of a `SiglipVisionModel` or a `SiglipModel`, without a prefix.
+    pub fn new(vs: diffusion_rs_common::VarBuilder, c: &SiglipVisionConfig) -> Result<Self> {
+        let vs = vs.pp("vision_model");
+        let embeddings = SiglipVisionEmbeddings::new(vs.pp("embeddings"), c)?;
+        let encoder = ClipEncoder::new(vs.pp("encoder"), &c.encoder_config())?;
+        let post_layernorm = diffusion_rs_common::layer_norm(
+            c.hidden_size,
+            c.layer_norm_eps,
+            vs.pp("post_layernorm"),
+        )?;
+        Ok(Self {
+            embeddings,
+            encoder,
+            post_layernorm,
+            image_size: c.image_size,
+        })
+    }
+
+    /// Side of the square images this model expects.
+    pub fn image_size(&self) -> usize {
+        self.image_size
+    }
+}
+
+impl Module for SiglipVisionTransformer {
+    /// Compute the last hidden states, of shape `(batch, num_patches, hidden_size)`, of
+    /// `(batch, channels, image_size, image_size)` pixel values normalized to `[-1, 1]`.
+    fn forward(&self, pixel_values: &Tensor) -> Result<Tensor> {
+        let xs = self.embeddings.forward(pixel_values)?;
+        let xs = self.encoder.forward(&xs, None)?;
+        self.post_layernorm.forward(&xs)
+    }
+}
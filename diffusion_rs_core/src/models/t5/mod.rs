@@ -319,14 +319,18 @@ impl T5Attention {
         let scores = { q.matmul(&k.t()?)? };
         let scores = match mask {
             None => scores,
-            Some(mask) => masked_fill(
-                &scores,
-                &mask
-                    .unsqueeze(0)?
-                    .unsqueeze(0)?
-                    .repeat((b_sz, self.n_heads))?,
-                f32::NEG_INFINITY,
-            )?,
+            Some(mask) => {
+                // Causal masks are `(q_len, kv_len)`, padding masks are `(b_sz, 1, 1, kv_len)`.
+                let mask = match mask.rank() {
+                    2 => mask.unsqueeze(0)?.unsqueeze(0)?,
+                    _ => mask.clone(),
+                };
+                masked_fill(
+                    &scores,
+                    &mask.broadcast_as(scores.shape())?,
+                    f32::NEG_INFINITY,
+                )?
+            }
         };
 
         let (scores, position_bias) = match position_bias {
@@ -545,6 +549,7 @@ impl T5Block {
         xs: &Tensor,
         position_bias: Option<&Tensor>,
         encoder_hidden_states: Option<&Tensor>,
+        padding_mask: Option<&Tensor>,
     ) -> Result<(Tensor, Option<Tensor>)> {
         // TODO: Cache masks
         let mask = match self.cross_attn.is_some() {
@@ -558,7 +563,7 @@ impl T5Block {
                     Some(get_mask(mask_len, xs.device())?)
                 }
             }
-            false => None,
+            false => padding_mask.cloned(),
         };
         let (mut xs, position_bias) = self.self_attn.forward(xs, position_bias, mask.as_ref())?;
         // Clamp for f16
@@ -614,6 +619,7 @@ impl T5Stack {
         &self,
         input_ids: &Tensor,
         encoder_hidden_states: Option<&Tensor>,
+        padding_mask: Option<&Tensor>,
     ) -> Result<Tensor> {
         let input_embeds = self.shared.as_ref().forward(input_ids)?;
         let mut hidden_states = input_embeds;
@@ -623,6 +629,7 @@ impl T5Stack {
                 &hidden_states,
                 position_bias.as_ref(),
                 encoder_hidden_states,
+                padding_mask,
             )?;
         }
         self.final_layer_norm.forward(&hidden_states)
@@ -649,8 +656,16 @@ impl T5EncoderModel {
         Ok(Self { encoder })
     }
 
+    /// Encode `input_ids`, attending to all of the tokens including the padding.
     pub fn forward(&self, input_ids: &Tensor) -> Result<Tensor> {
-        self.encoder.forward(input_ids, None)
+        self.encoder.forward(input_ids, None, None)
+    }
+
+    /// Encode `input_ids`, only attending to the tokens where `attention_mask`, of shape `(batch, seq_len)`,
+    /// is non-zero.
+    pub fn forward_with_mask(&self, input_ids: &Tensor, attention_mask: &Tensor) -> Result<Tensor> {
+        let padding_mask = attention_mask.eq(0.)?.unsqueeze(1)?.unsqueeze(1)?;
+        self.encoder.forward(input_ids, None, Some(&padding_mask))
     }
 }
 
@@ -53,6 +53,7 @@ pub struct AutoEncoderKl {
     encoder: Encoder,
     decoder: Decoder,
     reg: DiagonalGaussian,
+    mode: DiagonalGaussian,
     quant_conv: Option<Conv2d>,
     post_quant_conv: Option<Conv2d>,
     shift_factor: f64,
@@ -64,6 +65,7 @@ impl AutoEncoderKl {
         let encoder = Encoder::new(&cfg.clone().into(), vb.pp("encoder"))?;
         let decoder = Decoder::new(&cfg.clone().into(), vb.pp("decoder"))?;
         let reg = DiagonalGaussian::new(true, 1)?;
+        let mode = DiagonalGaussian::new(false, 1)?;
         let quant_conv = if cfg.use_quant_conv {
             Some(diffusion_rs_common::conv2d(
                 2 * cfg.latent_channels,
@@ -90,6 +92,7 @@ impl AutoEncoderKl {
             encoder,
             decoder,
             reg,
+            mode,
             scale_factor: cfg.scaling_factor,
             shift_factor: cfg.shift_factor,
             quant_conv,
@@ -98,16 +101,26 @@ impl AutoEncoderKl {
     }
 }
 
-impl VAEModel for AutoEncoderKl {
-    fn encode(&self, xs: &Tensor) -> Result<Tensor> {
+impl AutoEncoderKl {
+    /// The mean and log-variance of the latent distribution, concatenated along the channels.
+    fn moments(&self, xs: &Tensor) -> Result<Tensor> {
         let mut z = xs.apply(&self.encoder)?;
         if let Some(conv) = &self.quant_conv {
             z = z.apply(conv)?;
         }
-        z = z.apply(&self.reg)?;
-        // (z - self.shift_factor)? * self.scale_factor
         Ok(z)
     }
+}
+
+impl VAEModel for AutoEncoderKl {
+    fn encode(&self, xs: &Tensor) -> Result<Tensor> {
+        // (z - self.shift_factor)? * self.scale_factor
+        self.moments(xs)?.apply(&self.reg)
+    }
+
+    fn encode_mode(&self, xs: &Tensor) -> Result<Tensor> {
+        self.moments(xs)?.apply(&self.mode)
+    }
 
     fn decode(&self, xs: &Tensor) -> Result<Tensor> {
         // let xs = ((xs / self.scale_factor)? + self.shift_factor)?;
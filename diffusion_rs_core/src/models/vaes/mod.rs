@@ -18,6 +18,10 @@ pub(crate) trait VAEModel: Send + Sync {
     /// `(x - vae.shift_factor())? * self.scale_factor()`
     fn encode(&self, xs: &Tensor) -> Result<Tensor>;
 
+    /// Like [`VAEModel::encode`], but returns the mode of the latent distribution instead of sampling it, which
+    /// is deterministic.
+    fn encode_mode(&self, xs: &Tensor) -> Result<Tensor>;
+
     /// This function *does not* handle scaling the tensor! If you want to do this, apply the following to the input:
     /// `(x / vae.scale_factor())? + self.shift_factor()`
     fn decode(&self, xs: &Tensor) -> Result<Tensor>;
@@ -33,6 +37,18 @@ struct VaeConfigShim {
     name: String,
 }
 
+/// Check that a VAE config matches the config struct of its `_class_name`.
+pub(crate) fn check_vae_config(cfg_json: &str) -> anyhow::Result<()> {
+    let VaeConfigShim { name } = serde_json::from_str(cfg_json)?;
+    match name.as_str() {
+        "AutoencoderKL" => {
+            serde_json::from_str::<AutencoderKlConfig>(cfg_json)?;
+        }
+        other => anyhow::bail!("Unexpected VAE type `{other:?}`."),
+    }
+    Ok(())
+}
+
 fn load_autoencoder_kl(
     cfg_json: &FileData,
     vb: VarBuilder,
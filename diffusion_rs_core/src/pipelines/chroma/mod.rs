@@ -0,0 +1,453 @@
+use std::sync::Mutex;
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use diffusion_rs_common::core::{DType, Device, Tensor};
+use tokenizers::Tokenizer;
+use tracing::info;
+
+use crate::models::QuantizedModel;
+use crate::{
+    models::{
+        check_vae_config, dispatch_load_vae_model, ChromaConfig, ChromaModel, T5Config,
+        T5EncoderModel, VAEModel,
+    },
+    pipelines::ComponentName,
+};
+use diffusion_rs_common::{from_mmaped_safetensors, from_mmaped_safetensors_lazy, ModelSource};
+
+use super::flux::sampling;
+use super::model_index::{component_file, load_tokenizer};
+use super::sampling::Sampler;
+use super::scheduler::SchedulerConfig;
+use super::{
+    ComponentElem, DiffusionGenerationParams, Loader, ModelIndex, ModelPipeline, Offloading,
+    PromptEmbeddings, Prompts, ResolvedPlacement,
+};
+
+/// Longest T5 prompt supported by Chroma, in tokens.
+const MAX_T5_TOKENS: usize = 512;
+
+pub struct ChromaLoader;
+
+impl Loader for ChromaLoader {
+    fn name(&self) -> &'static str {
+        "chroma"
+    }
+
+    fn required_component_names(&self) -> Vec<ComponentName> {
+        vec![
+            ComponentName::Scheduler,
+            ComponentName::TextEncoder(1),
+            ComponentName::Tokenizer(1),
+            ComponentName::Transformer,
+            ComponentName::Vae,
+        ]
+    }
+
+    fn load_from_components(
+        &self,
+        index: &ModelIndex,
+        mut components: HashMap<ComponentName, ComponentElem>,
+        placement: &ResolvedPlacement,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<Arc<Mutex<dyn ModelPipeline>>> {
+        let scheduler = components.remove(&ComponentName::Scheduler).unwrap();
+        let t5_component = components.remove(&ComponentName::TextEncoder(1)).unwrap();
+        let t5_tok_component = components.remove(&ComponentName::Tokenizer(1)).unwrap();
+        let chroma_component = components.remove(&ComponentName::Transformer).unwrap();
+        let vae_component = components.remove(&ComponentName::Vae).unwrap();
+
+        let t5_placement = ComponentName::TextEncoder(1);
+        let (t5_device, chroma_device) = match offloading_type {
+            Some(Offloading::Full) => (Device::Cpu, Device::Cpu),
+            // Transformer blocks are materialized directly on the device from disk.
+            Some(Offloading::Disk { .. }) => (
+                Device::Cpu,
+                placement.device(&ComponentName::Transformer).clone(),
+            ),
+            None => (
+                placement.device(&t5_placement).clone(),
+                placement.device(&ComponentName::Transformer).clone(),
+            ),
+        };
+
+        let scheduler_config = if let ComponentElem::Config { files } = scheduler {
+            serde_json::from_str::<SchedulerConfig>(
+                &files["scheduler/scheduler_config.json"].read_to_string(&source)?,
+            )?
+        } else {
+            anyhow::bail!("expected scheduler config")
+        };
+        for name in [ComponentName::TextEncoder(1), ComponentName::Transformer] {
+            check_model_class(index, &name)?;
+        }
+        let t5_tokenizer = if let ComponentElem::Other { files } = t5_tok_component {
+            let name = ComponentName::Tokenizer(1);
+            load_tokenizer(&name, index.required_class(&name)?, &files, &source)?
+        } else {
+            anyhow::bail!("incorrect storage of t5 tokenizer")
+        };
+        if !silent {
+            info!("loading T5 model");
+        }
+        let t5_component = if let ComponentElem::Model { weights, config } = t5_component {
+            let cfg: T5Config = serde_json::from_str(&config.read_to_string(&source)?)?;
+            let vb = from_mmaped_safetensors(
+                weights.into_values().collect(),
+                Some(placement.dtype(&t5_placement)),
+                &t5_device,
+                silent,
+                source.clone(),
+            )?;
+            T5EncoderModel::new(vb, &cfg)?
+        } else {
+            anyhow::bail!("incorrect storage of t5 model")
+        };
+        if !silent {
+            info!("loading VAE model");
+        }
+        let vae_component = if let ComponentElem::Model { weights, config } = vae_component {
+            dispatch_load_vae_model(
+                &config,
+                weights.into_values().collect(),
+                placement.device(&ComponentName::Vae),
+                placement.dtype(&ComponentName::Vae),
+                silent,
+                source.clone(),
+            )?
+        } else {
+            anyhow::bail!("incorrect storage of vae model")
+        };
+        if !silent {
+            info!("loading Chroma model");
+        }
+        let chroma_component = if let ComponentElem::Model { weights, config } = chroma_component {
+            let cfg: ChromaConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+            if let Some(Offloading::Disk { memory_budget }) = offloading_type {
+                let vb = from_mmaped_safetensors_lazy(
+                    weights.into_values().collect(),
+                    Some(placement.dtype(&ComponentName::Transformer)),
+                    &chroma_device,
+                    source,
+                )?;
+                ChromaModel::new_disk_offloaded(&cfg, vb, memory_budget)?
+            } else {
+                let vb = from_mmaped_safetensors(
+                    weights.into_values().collect(),
+                    Some(placement.dtype(&ComponentName::Transformer)),
+                    &chroma_device,
+                    silent,
+                    source,
+                )?;
+                ChromaModel::new(&cfg, vb)?
+            }
+        } else {
+            anyhow::bail!("incorrect storage of chroma model")
+        };
+
+        let pipeline = ChromaPipeline {
+            t5_tokenizer: Arc::new(t5_tokenizer),
+            t5_model: t5_component,
+            vae_model: vae_component,
+            chroma_model: chroma_component,
+            scheduler_config,
+            placement: placement.clone(),
+        };
+
+        Ok(Arc::new(Mutex::new(pipeline)))
+    }
+
+    fn check_component(
+        &self,
+        index: &ModelIndex,
+        name: &ComponentName,
+        component: &ComponentElem,
+        source: &ModelSource,
+    ) -> Result<()> {
+        if matches!(component, ComponentElem::Model { .. }) {
+            check_model_class(index, name)?;
+        }
+        match (name, component) {
+            (ComponentName::Scheduler, ComponentElem::Config { files }) => {
+                serde_json::from_str::<SchedulerConfig>(
+                    &component_file(files, name, "scheduler_config.json")?
+                        .read_to_string(source)?,
+                )
+                .context("`scheduler_config.json` does not match `SchedulerConfig`")?;
+            }
+            (ComponentName::Tokenizer(1), ComponentElem::Other { files }) => {
+                load_tokenizer(name, index.required_class(name)?, files, source)?;
+            }
+            (ComponentName::TextEncoder(1), ComponentElem::Model { config, .. }) => {
+                serde_json::from_str::<T5Config>(&config.read_to_string(source)?)
+                    .context("`config.json` does not match `T5Config`")?;
+            }
+            (ComponentName::Transformer, ComponentElem::Model { config, .. }) => {
+                serde_json::from_str::<ChromaConfig>(&config.read_to_string(source)?)
+                    .context("`config.json` does not match `ChromaConfig`")?
+                    .validate()
+                    .context("`config.json` has inconsistent dimensions")?;
+            }
+            (ComponentName::Vae, ComponentElem::Model { config, .. }) => {
+                check_vae_config(&config.read_to_string(source)?)
+                    .context("`config.json` does not match the VAE config")?;
+            }
+            (name, other) => {
+                anyhow::bail!(
+                    "incorrect storage of {name}, found a {} component",
+                    other.kind()
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that the declared class of a Chroma model component is supported. The VAE is dispatched on the
+/// `_class_name` of its config.
+fn check_model_class(index: &ModelIndex, name: &ComponentName) -> Result<()> {
+    let class = index.required_class(name)?;
+    let supported: &[&str] = match name {
+        ComponentName::TextEncoder(1) => &["T5EncoderModel"],
+        ComponentName::Transformer => &["ChromaTransformer2DModel"],
+        _ => return Ok(()),
+    };
+    if supported.contains(&class.class.as_str()) {
+        Ok(())
+    } else {
+        Err(class.unsupported(name, "chroma"))
+    }
+}
+
+pub struct ChromaPipeline {
+    t5_tokenizer: Arc<Tokenizer>,
+    t5_model: T5EncoderModel,
+    vae_model: Arc<dyn VAEModel>,
+    chroma_model: ChromaModel,
+    scheduler_config: SchedulerConfig,
+    placement: ResolvedPlacement,
+}
+
+impl ChromaPipeline {
+    /// Tokenize the prompts and pad them to the longest one plus a padding token. Returns the token ids and
+    /// the attention mask, which keeps the first padding token of each prompt as Chroma was trained with it.
+    fn tokenize_and_pad(
+        prompts: Vec<String>,
+        tokenizer: &Tokenizer,
+        device: &Device,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let unpadded_tokens = tokenizer
+            .encode_batch(prompts, true)
+            .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?
+            .into_iter()
+            .map(|e| e.get_ids().to_vec())
+            .collect::<Vec<_>>();
+        let max_tokens = unpadded_tokens.iter().map(|x| x.len()).max().unwrap() + 1;
+        if max_tokens > MAX_T5_TOKENS {
+            diffusion_rs_common::bail!(
+                "T5 embedding length greater than {MAX_T5_TOKENS}, please shrink the prompt."
+            )
+        }
+        let mut tokens = Vec::new();
+        let mut mask = Vec::new();
+        for mut tokenization in unpadded_tokens {
+            let len = tokenization.len();
+            tokenization.extend(vec![0; max_tokens - len]);
+            tokens.push(tokenization);
+            mask.push(
+                (0..max_tokens)
+                    .map(|i| u8::from(i <= len))
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        Ok((Tensor::new(tokens, device)?, Tensor::new(mask, device)?))
+    }
+
+    /// Encode the prompts with T5, returning the embeddings and the attention mask on the transformer device.
+    fn encode(&self, prompts: Vec<String>) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let t5_device = self.placement.device(&ComponentName::TextEncoder(1));
+        let chroma_device = self.placement.device(&ComponentName::Transformer);
+        let chroma_dtype = self.placement.dtype(&ComponentName::Transformer);
+        let (input_ids, mask) = Self::tokenize_and_pad(prompts, &self.t5_tokenizer, t5_device)?;
+        let embed = self
+            .t5_model
+            .forward_with_mask(&input_ids, &mask)?
+            .to_device(chroma_device)?
+            .to_dtype(chroma_dtype)?;
+        Ok((embed, mask.to_device(chroma_device)?))
+    }
+
+    /// Encode each batch of prompts with T5, which is moved onto its device once for all of them when
+    /// offloading.
+    fn encode_batches(
+        &mut self,
+        batches: Vec<Vec<String>>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Vec<(Tensor, Tensor)>> {
+        let t5_device = self.placement.device(&ComponentName::TextEncoder(1));
+        match offloading_type {
+            Some(Offloading::Full | Offloading::Disk { .. }) => {
+                self.t5_model.to_device(t5_device)?;
+            }
+            None => (),
+        }
+
+        let encoded = batches
+            .into_iter()
+            .map(|prompts| self.encode(prompts))
+            .collect::<diffusion_rs_common::core::Result<Vec<_>>>()?;
+
+        match offloading_type {
+            Some(Offloading::Full | Offloading::Disk { .. }) => {
+                self.t5_model.to_device(&Device::Cpu)?;
+            }
+            None => (),
+        }
+        Ok(encoded)
+    }
+}
+
+impl ModelPipeline for ChromaPipeline {
+    fn encode_prompts(
+        &mut self,
+        prompts: Vec<String>,
+        _max_sequence_length: Option<usize>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<PromptEmbeddings> {
+        let (t5, t5_mask) = self
+            .encode_batches(vec![prompts], offloading_type)?
+            .pop()
+            .unwrap();
+        Ok(PromptEmbeddings {
+            t5,
+            t5_mask,
+            clip_pooled: None,
+        })
+    }
+
+    fn forward(
+        &mut self,
+        prompts: Prompts,
+        params: DiffusionGenerationParams,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        if !params.reference_images.is_empty()
+            || params.image.is_some()
+            || params.mask_image.is_some()
+            || params.control_image.is_some()
+            || !params.controlnet_conditions.is_empty()
+            || !params.ip_adapter_images.is_empty()
+            || params.redux_image.is_some()
+        {
+            diffusion_rs_common::bail!("The Chroma pipeline does not support image conditioning.")
+        }
+
+        // Chroma is not guidance-distilled, the negative prompt is only encoded for real CFG.
+        let do_cfg = params.guidance_scale > 1.;
+        let negative_prompts =
+            |batch_size| vec![params.negative_prompt.clone().unwrap_or_default(); batch_size];
+        let (txt, txt_mask, negative) = match prompts {
+            Prompts::Text(prompts) => {
+                let batch_size = prompts.len();
+                let mut batches = vec![prompts];
+                if do_cfg {
+                    batches.push(negative_prompts(batch_size));
+                }
+                let mut encoded = self.encode_batches(batches, offloading_type)?.into_iter();
+                let (txt, txt_mask) = encoded.next().unwrap();
+                (txt, txt_mask, encoded.next())
+            }
+            Prompts::Embeddings(embeddings) => {
+                let negative = if do_cfg {
+                    self.encode_batches(
+                        vec![negative_prompts(embeddings.batch_size()?)],
+                        offloading_type,
+                    )?
+                    .pop()
+                } else {
+                    None
+                };
+                (embeddings.t5, embeddings.t5_mask, negative)
+            }
+        };
+        let chroma_device = self.placement.device(&ComponentName::Transformer);
+        let chroma_dtype = self.placement.dtype(&ComponentName::Transformer);
+        let txt = txt.to_device(chroma_device)?.to_dtype(chroma_dtype)?;
+        let txt_mask = txt_mask.to_device(chroma_device)?;
+
+        let noise = sampling::get_noise(txt.dim(0)?, params.height, params.width, chroma_device)?
+            .to_dtype(chroma_dtype)?;
+        let (img, img_ids) = sampling::pack(&noise)?;
+        let txt_ids = Tensor::zeros((txt.dim(0)?, txt.dim(1)?, 3), chroma_dtype, chroma_device)?;
+        let mu = sampling::calculate_shift(
+            img.dim(1)?,
+            self.scheduler_config.base_image_seq_len,
+            self.scheduler_config.max_image_seq_len,
+            self.scheduler_config.base_shift,
+            self.scheduler_config.max_shift,
+        );
+        let timesteps = self
+            .scheduler_config
+            .get_timesteps(params.num_steps, Some(mu))?;
+
+        match offloading_type {
+            Some(Offloading::Full) => {
+                self.chroma_model.to_device(chroma_device)?;
+            }
+            Some(Offloading::Disk { .. }) | None => (),
+        }
+
+        let negative_txt_ids = match &negative {
+            Some((negative_txt, _)) => Some(Tensor::zeros(
+                (negative_txt.dim(0)?, negative_txt.dim(1)?, 3),
+                chroma_dtype,
+                chroma_device,
+            )?),
+            None => None,
+        };
+        let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
+            let pred =
+                self.chroma_model
+                    .forward(img, &img_ids, &txt, &txt_ids, t_vec, Some(&txt_mask))?;
+            match (&negative, &negative_txt_ids) {
+                (Some((negative_txt, negative_mask)), Some(negative_txt_ids)) => {
+                    let negative_pred = self.chroma_model.forward(
+                        img,
+                        &img_ids,
+                        negative_txt,
+                        negative_txt_ids,
+                        t_vec,
+                        Some(negative_mask),
+                    )?;
+                    &negative_pred + ((pred - &negative_pred)? * params.guidance_scale)?
+                }
+                _ => Ok(pred),
+            }
+        };
+
+        let sampler = Sampler::new(&self.scheduler_config.scheduler_type);
+        let mut img = sampler.sample(&timesteps, &img, step)?;
+
+        match offloading_type {
+            Some(Offloading::Full) => {
+                self.chroma_model.to_device(&Device::Cpu)?;
+            }
+            Some(Offloading::Disk { .. }) | None => (),
+        }
+
+        img = sampling::unpack(&img, params.height, params.width)?
+            .to_device(self.placement.device(&ComponentName::Vae))?
+            .to_dtype(self.placement.dtype(&ComponentName::Vae))?;
+
+        img = ((img / self.vae_model.scale_factor())? + self.vae_model.shift_factor())?;
+        img = self.vae_model.decode(&img)?;
+
+        img = ((img.clamp(-1f32, 1f32)? + 1.0)? * 127.5)?.to_dtype(DType::U8)?;
+
+        Ok(img)
+    }
+}
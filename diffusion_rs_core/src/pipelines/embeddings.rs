@@ -0,0 +1,89 @@
+use std::{collections::HashMap, path::Path};
+
+use anyhow::{Context, Result};
+use diffusion_rs_common::core::{Device, Tensor};
+
+const T5_KEY: &str = "t5";
+const T5_MASK_KEY: &str = "t5_mask";
+const CLIP_POOLED_KEY: &str = "clip_pooled";
+
+/// The text encoder outputs of a batch of prompts, returned by [`Pipeline::encode_prompts`] and accepted by
+/// [`Pipeline::forward`] in place of the prompts.
+///
+/// [`Pipeline::encode_prompts`]: crate::Pipeline::encode_prompts
+/// [`Pipeline::forward`]: crate::Pipeline::forward
+#[derive(Debug, Clone)]
+pub struct PromptEmbeddings {
+    /// T5 embeddings of shape `(batch, seq_len, hidden_size)`.
+    pub t5: Tensor,
+    /// Attention mask of the T5 embeddings of shape `(batch, seq_len)`, which is 1 for the prompt tokens and 0
+    /// for the padding.
+    pub t5_mask: Tensor,
+    /// Pooled CLIP embeddings of shape `(batch, hidden_size)`, for models with a CLIP text encoder such as
+    /// FLUX.
+    pub clip_pooled: Option<Tensor>,
+}
+
+impl PromptEmbeddings {
+    /// Number of prompts.
+    pub fn batch_size(&self) -> diffusion_rs_common::core::Result<usize> {
+        self.t5.dim(0)
+    }
+
+    /// Save the embeddings to a safetensors file.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut tensors = HashMap::from([
+            (T5_KEY, self.t5.clone()),
+            (T5_MASK_KEY, self.t5_mask.clone()),
+        ]);
+        if let Some(clip_pooled) = &self.clip_pooled {
+            tensors.insert(CLIP_POOLED_KEY, clip_pooled.clone());
+        }
+        Ok(diffusion_rs_common::core::safetensors::save(
+            &tensors, path,
+        )?)
+    }
+
+    /// Load embeddings saved with [`PromptEmbeddings::save`] into CPU memory. They are moved to the device of
+    /// the model when generating.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut tensors = diffusion_rs_common::core::safetensors::load(path, &Device::Cpu)?;
+        let mut take = |key: &str| {
+            tensors
+                .remove(key)
+                .with_context(|| format!("No `{key}` tensor in `{}`.", path.display()))
+        };
+        let t5 = take(T5_KEY)?;
+        let t5_mask = take(T5_MASK_KEY)?;
+        let clip_pooled = take(CLIP_POOLED_KEY).ok();
+        Ok(Self {
+            t5,
+            t5_mask,
+            clip_pooled,
+        })
+    }
+}
+
+/// The prompts of [`Pipeline::forward`]: either text, or embeddings returned by
+/// [`Pipeline::encode_prompts`].
+///
+/// [`Pipeline::encode_prompts`]: crate::Pipeline::encode_prompts
+/// [`Pipeline::forward`]: crate::Pipeline::forward
+#[derive(Debug, Clone)]
+pub enum Prompts {
+    Text(Vec<String>),
+    Embeddings(PromptEmbeddings),
+}
+
+impl From<Vec<String>> for Prompts {
+    fn from(prompts: Vec<String>) -> Self {
+        Self::Text(prompts)
+    }
+}
+
+impl From<PromptEmbeddings> for Prompts {
+    fn from(embeddings: PromptEmbeddings) -> Self {
+        Self::Embeddings(embeddings)
+    }
+}
@@ -1,33 +1,162 @@
+use std::cell::Cell;
 use std::sync::Mutex;
-use std::{cmp::Ordering, collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc};
 
-use anyhow::Result;
-use diffusion_rs_common::core::{DType, Device, Tensor, D};
+use anyhow::{Context, Result};
+use diffusion_rs_common::core::{DType, Device, Tensor};
 use diffusion_rs_common::nn::Module;
+use image::DynamicImage;
+use serde::Deserialize;
 use tokenizers::Tokenizer;
-use tracing::info;
+use tracing::{info, warn};
 
 use crate::models::QuantizedModel;
 use crate::{
     models::{
-        dispatch_load_vae_model, ClipTextConfig, ClipTextTransformer, FluxConfig, FluxModel,
-        T5Config, T5EncoderModel, VAEModel,
+        check_vae_config, dispatch_load_vae_model, ClipTextConfig, ClipTextTransformer,
+        ClipVisionConfig, ClipVisionTransformer, ControlNetConfig, ControlNetModel,
+        ControlNetResiduals, FluxConfig, FluxModel, IpAdapterModel, ReduxConfig, ReduxImageEncoder,
+        SiglipVisionConfig, SiglipVisionTransformer, T5Config, T5EncoderModel, VAEModel,
     },
     pipelines::ComponentName,
 };
-use diffusion_rs_common::{from_mmaped_safetensors, ModelSource};
+use diffusion_rs_common::{
+    from_mmaped_safetensors, from_mmaped_safetensors_lazy, FileData, ModelSource,
+};
 
+use super::model_index::{component_file, load_tokenizer};
+use super::plan::{transformer_activation_bytes, ComponentWeights};
+use super::processing::{image_to_tensor, mask_to_tensor};
+use super::prompt_cache::{PromptCache, PromptCacheKey, PromptCacheStats};
 use super::sampling::Sampler;
 use super::scheduler::SchedulerConfig;
-use super::{ComponentElem, DiffusionGenerationParams, Loader, ModelPipeline, Offloading};
+use super::{
+    ComponentElem, ComponentPlan, ComponentResidence, DiffusionGenerationParams, Loader,
+    ModelIndex, ModelPipeline, Offloading, PlanParams, PromptEmbeddings, Prompts,
+    ResolvedPlacement,
+};
+
+pub(super) mod sampling;
+
+/// The maximum number of tokens T5 was trained with.
+const MAX_T5_TOKENS: usize = 512;
 
-mod sampling;
+/// Resolutions (width, height) FLUX.1 Kontext was trained on. Reference images are resized to the one with the
+/// closest aspect ratio.
+const KONTEXT_RESOLUTIONS: &[(usize, usize)] = &[
+    (672, 1568),
+    (688, 1504),
+    (720, 1456),
+    (752, 1392),
+    (800, 1328),
+    (832, 1248),
+    (880, 1184),
+    (944, 1104),
+    (1024, 1024),
+    (1104, 944),
+    (1184, 880),
+    (1248, 832),
+    (1328, 800),
+    (1392, 752),
+    (1456, 720),
+    (1504, 688),
+    (1568, 672),
+];
 
-pub struct FluxLoader;
+/// The pipelines built on the FLUX transformer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FluxVariant {
+    /// Text-to-image generation.
+    Text,
+    /// Image editing with FLUX.1 Kontext, conditioned on reference images.
+    Kontext,
+    /// Inpainting with FLUX.1 Fill, conditioned on the masked image and the mask.
+    Fill,
+    /// Structural conditioning with FLUX.1 Canny or Depth, conditioned on a control image.
+    Control,
+}
+
+impl FluxVariant {
+    /// Check that the transformer takes the packed latents followed by the packed conditioning of this
+    /// variant: the masked image latents and the 8x8 mask patches for Fill, the control image latents for
+    /// Control.
+    fn check_config(&self, cfg: &FluxConfig) -> Result<()> {
+        let latent_channels = cfg.out_channels();
+        let expected = match self {
+            Self::Text | Self::Kontext => latent_channels,
+            Self::Fill => 2 * latent_channels + 4 * 8 * 8,
+            Self::Control => 2 * latent_channels,
+        };
+        if cfg.in_channels != expected {
+            anyhow::bail!(
+                "The {self:?} variant of FLUX expects a transformer with {expected} input channels for {latent_channels} output channels, got {}.",
+                cfg.in_channels
+            )
+        }
+        Ok(())
+    }
+
+    /// Check that the generation parameters provide the images this variant is conditioned on, and no others.
+    fn check_params(
+        &self,
+        params: &DiffusionGenerationParams,
+    ) -> diffusion_rs_common::core::Result<()> {
+        let provided = [
+            ("reference images", !params.reference_images.is_empty()),
+            ("an image", params.image.is_some()),
+            ("a mask image", params.mask_image.is_some()),
+            ("a control image", params.control_image.is_some()),
+            (
+                "ControlNet conditions",
+                !params.controlnet_conditions.is_empty(),
+            ),
+            ("IP-Adapter images", !params.ip_adapter_images.is_empty()),
+            ("a Redux image", params.redux_image.is_some()),
+        ];
+        let required: &[&str] = match self {
+            Self::Text => &[],
+            Self::Kontext => &["reference images"],
+            Self::Fill => &["an image", "a mask image"],
+            Self::Control => &["a control image"],
+        };
+        let optional: &[&str] = match self {
+            Self::Text => &[
+                "ControlNet conditions",
+                "IP-Adapter images",
+                "a Redux image",
+            ],
+            Self::Kontext | Self::Fill | Self::Control => &["a Redux image"],
+        };
+        for (input, is_provided) in provided {
+            if optional.contains(&input) {
+                continue;
+            }
+            match (required.contains(&input), is_provided) {
+                (true, false) => {
+                    diffusion_rs_common::bail!("The {self:?} variant of FLUX requires {input}.")
+                }
+                (false, true) => diffusion_rs_common::bail!(
+                    "The {self:?} variant of FLUX does not support {input}."
+                ),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct FluxLoader {
+    pub(crate) variant: FluxVariant,
+}
 
 impl Loader for FluxLoader {
     fn name(&self) -> &'static str {
-        "flux"
+        match self.variant {
+            FluxVariant::Text => "flux",
+            FluxVariant::Kontext => "flux-kontext",
+            FluxVariant::Fill => "flux-fill",
+            FluxVariant::Control => "flux-control",
+        }
     }
 
     fn required_component_names(&self) -> Vec<ComponentName> {
@@ -44,9 +173,9 @@ impl Loader for FluxLoader {
 
     fn load_from_components(
         &self,
+        index: &ModelIndex,
         mut components: HashMap<ComponentName, ComponentElem>,
-        device: &Device,
-        dtype: DType,
+        placement: &ResolvedPlacement,
         silent: bool,
         offloading_type: Option<Offloading>,
         source: Arc<ModelSource>,
@@ -59,9 +188,18 @@ impl Loader for FluxLoader {
         let flux_component = components.remove(&ComponentName::Transformer).unwrap();
         let vae_component = components.remove(&ComponentName::Vae).unwrap();
 
-        let t5_flux_device = match offloading_type {
-            Some(Offloading::Full) => Device::Cpu,
-            None => device.clone(),
+        let t5_placement = ComponentName::TextEncoder(2);
+        let (t5_device, flux_device) = match offloading_type {
+            Some(Offloading::Full) => (Device::Cpu, Device::Cpu),
+            // Transformer blocks are materialized directly on the device from disk.
+            Some(Offloading::Disk { .. }) => (
+                Device::Cpu,
+                placement.device(&ComponentName::Transformer).clone(),
+            ),
+            None => (
+                placement.device(&t5_placement).clone(),
+                placement.device(&ComponentName::Transformer).clone(),
+            ),
         };
 
         let scheduler_config = if let ComponentElem::Config { files } = scheduler {
@@ -71,34 +209,35 @@ impl Loader for FluxLoader {
         } else {
             anyhow::bail!("expected scheduler config")
         };
+        for name in [
+            ComponentName::TextEncoder(1),
+            ComponentName::TextEncoder(2),
+            ComponentName::Transformer,
+        ] {
+            check_model_class(index, &name)?;
+        }
         let clip_tokenizer = if let ComponentElem::Other { files } = clip_tok_component {
-            let vocab_file = &files["tokenizer/vocab.json"];
-            let merges_file = &files["tokenizer/merges.txt"];
-
-            diffusion_rs_common::load_bpe_tokenizer(vocab_file, merges_file, &source)?
+            let name = ComponentName::Tokenizer(1);
+            load_tokenizer(&name, index.required_class(&name)?, &files, &source)?
         } else {
             anyhow::bail!("incorrect storage of clip tokenizer")
         };
         let t5_tokenizer = if let ComponentElem::Other { files } = t5_tok_component {
-            Tokenizer::from_bytes(files["tokenizer_2/tokenizer.json"].read_to_string(&source)?)
-                .map_err(anyhow::Error::msg)?
+            let name = ComponentName::Tokenizer(2);
+            load_tokenizer(&name, index.required_class(&name)?, &files, &source)?
         } else {
             anyhow::bail!("incorrect storage of t5 tokenizer")
         };
         if !silent {
             info!("loading CLIP model");
         }
-        let clip_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = clip_component
-        {
+        let clip_component = if let ComponentElem::Model { weights, config } = clip_component {
             let cfg: ClipTextConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
 
             let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                device,
+                weights.into_values().collect(),
+                Some(placement.dtype(&ComponentName::TextEncoder(1))),
+                placement.device(&ComponentName::TextEncoder(1)),
                 silent,
                 source.clone(),
             )?;
@@ -109,16 +248,12 @@ impl Loader for FluxLoader {
         if !silent {
             info!("loading T5 model");
         }
-        let t5_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = t5_component
-        {
+        let t5_component = if let ComponentElem::Model { weights, config } = t5_component {
             let cfg: T5Config = serde_json::from_str(&config.read_to_string(&source)?)?;
             let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                &t5_flux_device,
+                weights.into_values().collect(),
+                Some(placement.dtype(&t5_placement)),
+                &t5_device,
                 silent,
                 source.clone(),
             )?;
@@ -129,16 +264,12 @@ impl Loader for FluxLoader {
         if !silent {
             info!("loading VAE model");
         }
-        let vae_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = vae_component
-        {
+        let vae_component = if let ComponentElem::Model { weights, config } = vae_component {
             dispatch_load_vae_model(
                 &config,
-                safetensors.into_values().collect(),
-                device,
-                dtype,
+                weights.into_values().collect(),
+                placement.device(&ComponentName::Vae),
+                placement.dtype(&ComponentName::Vae),
                 silent,
                 source.clone(),
             )?
@@ -148,23 +279,32 @@ impl Loader for FluxLoader {
         if !silent {
             info!("loading FLUX model");
         }
-        let flux_component = if let ComponentElem::Model {
-            safetensors,
-            config,
-        } = flux_component
-        {
-            let cfg: FluxConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
-            let vb = from_mmaped_safetensors(
-                safetensors.into_values().collect(),
-                Some(dtype),
-                &t5_flux_device,
-                silent,
-                source,
-            )?;
-            FluxModel::new(&cfg, vb)?
-        } else {
-            anyhow::bail!("incorrect storage of flux model")
-        };
+        let (flux_component, flux_config) =
+            if let ComponentElem::Model { weights, config } = flux_component {
+                let cfg: FluxConfig = serde_json::from_str(&config.read_to_string(&source)?)?;
+                self.variant.check_config(&cfg)?;
+                let model = if let Some(Offloading::Disk { memory_budget }) = offloading_type {
+                    let vb = from_mmaped_safetensors_lazy(
+                        weights.into_values().collect(),
+                        Some(placement.dtype(&ComponentName::Transformer)),
+                        &flux_device,
+                        source,
+                    )?;
+                    FluxModel::new_disk_offloaded(&cfg, vb, memory_budget)?
+                } else {
+                    let vb = from_mmaped_safetensors(
+                        weights.into_values().collect(),
+                        Some(placement.dtype(&ComponentName::Transformer)),
+                        &flux_device,
+                        silent,
+                        source,
+                    )?;
+                    FluxModel::new(&cfg, vb)?
+                };
+                (model, cfg)
+            } else {
+                anyhow::bail!("incorrect storage of flux model")
+            };
 
         if !silent {
             info!(
@@ -180,12 +320,262 @@ impl Loader for FluxLoader {
             t5_model: t5_component,
             vae_model: vae_component,
             flux_model: flux_component,
+            flux_config,
+            controlnets: Vec::new(),
+            ip_adapter: None,
+            redux: None,
+            prompt_cache: None,
             scheduler_config,
-            device: device.clone(),
+            placement: placement.clone(),
+            variant: self.variant,
         };
 
         Ok(Arc::new(Mutex::new(pipeline)))
     }
+
+    fn plan_components(
+        &self,
+        components: &HashMap<ComponentName, ComponentElem>,
+        placement: &ResolvedPlacement,
+        offloading_type: Option<Offloading>,
+        params: &PlanParams,
+        source: &ModelSource,
+    ) -> Result<Vec<ComponentPlan>> {
+        let model_component = |name: &ComponentName| match components.get(name) {
+            Some(ComponentElem::Model { weights, config }) => Ok((weights, config)),
+            _ => anyhow::bail!("incorrect storage of {name} model"),
+        };
+        let PlanParams {
+            height,
+            width,
+            batch_size,
+        } = *params;
+
+        let mut plans = Vec::new();
+
+        let name = ComponentName::TextEncoder(1);
+        let (weight_files, config) = model_component(&name)?;
+        let cfg: ClipTextConfig = serde_json::from_str(&config.read_to_string(source)?)?;
+        let dtype = placement.dtype(&name);
+        let weights = ComponentWeights::read(weight_files, config, dtype, source)?;
+        plans.push(ComponentPlan {
+            peak_activation_bytes: transformer_activation_bytes(
+                batch_size,
+                cfg.max_position_embeddings,
+                cfg.hidden_size(),
+                cfg.intermediate_size,
+                cfg.num_attention_heads,
+                dtype,
+                dtype,
+            ),
+            residence: ComponentResidence::Device(placement.device(&name).into()),
+            name,
+            num_parameters: weights.num_parameters,
+            size_in_bytes: weights.size_in_bytes,
+            dtype,
+            quantization: weights.quantization,
+        });
+
+        let (weight_files, config) = model_component(&ComponentName::Transformer)?;
+        let flux_cfg: FluxConfig = serde_json::from_str(&config.read_to_string(source)?)?;
+        flux_cfg.validate()?;
+        self.variant.check_config(&flux_cfg)?;
+        let t5_seq_len = default_max_sequence_length(flux_cfg.guidance_embeds);
+
+        let name = ComponentName::TextEncoder(2);
+        let (t5_weight_files, t5_config) = model_component(&name)?;
+        let cfg: T5Config = serde_json::from_str(&t5_config.read_to_string(source)?)?;
+        let dtype = placement.dtype(&name);
+        let weights = ComponentWeights::read(t5_weight_files, t5_config, dtype, source)?;
+        let device = placement.device(&name).into();
+        plans.push(ComponentPlan {
+            peak_activation_bytes: transformer_activation_bytes(
+                batch_size,
+                t5_seq_len,
+                cfg.d_model,
+                cfg.d_ff,
+                cfg.num_heads,
+                dtype,
+                dtype,
+            ),
+            residence: match offloading_type {
+                Some(Offloading::Full | Offloading::Disk { .. }) => {
+                    ComponentResidence::Offloaded(device)
+                }
+                None => ComponentResidence::Device(device),
+            },
+            name,
+            num_parameters: weights.num_parameters,
+            size_in_bytes: weights.size_in_bytes,
+            dtype,
+            quantization: weights.quantization,
+        });
+
+        let name = ComponentName::Transformer;
+        let dtype = placement.dtype(&name);
+        let weights = ComponentWeights::read(weight_files, config, dtype, source)?;
+        let device = placement.device(&name).into();
+        // Latents are 8x smaller than the image and packed in 2x2 patches.
+        let img_seq_len = height.div_ceil(16) * width.div_ceil(16);
+        plans.push(ComponentPlan {
+            // Attention is computed in F32.
+            peak_activation_bytes: transformer_activation_bytes(
+                batch_size,
+                img_seq_len + t5_seq_len,
+                flux_cfg.hidden_size(),
+                flux_cfg.mlp_hidden_size(),
+                flux_cfg.num_attention_heads,
+                dtype,
+                DType::F32,
+            ),
+            residence: match offloading_type {
+                Some(Offloading::Full) => ComponentResidence::Offloaded(device),
+                Some(Offloading::Disk { memory_budget }) => ComponentResidence::Disk {
+                    device,
+                    memory_budget,
+                },
+                None => ComponentResidence::Device(device),
+            },
+            name,
+            num_parameters: weights.num_parameters,
+            size_in_bytes: weights.size_in_bytes,
+            dtype,
+            quantization: weights.quantization,
+        });
+
+        let name = ComponentName::Vae;
+        let (weight_files, config) = model_component(&name)?;
+        let cfg: VaePlanConfig = serde_json::from_str(&config.read_to_string(source)?)?;
+        let dtype = placement.dtype(&name);
+        let weights = ComponentWeights::read(weight_files, config, dtype, source)?;
+        // Decoding runs the up blocks from the latent resolution, each but the last one upsampling 2x. The
+        // largest feature map is kept alongside its normalized copy and the convolution output.
+        let latent_pixels = height.div_ceil(8) * width.div_ceil(8);
+        let num_blocks = cfg.block_out_channels.len();
+        let largest_feature_map = cfg
+            .block_out_channels
+            .iter()
+            .rev()
+            .enumerate()
+            .map(|(i, channels)| {
+                channels * latent_pixels * 4usize.pow((i + 1).min(num_blocks - 1) as u32)
+            })
+            .max()
+            .unwrap_or(0);
+        let mid_attention = batch_size * latent_pixels * latent_pixels;
+        plans.push(ComponentPlan {
+            peak_activation_bytes: (3 * batch_size * largest_feature_map).max(2 * mid_attention)
+                * dtype.size_in_bytes(),
+            residence: ComponentResidence::Device(placement.device(&name).into()),
+            name,
+            num_parameters: weights.num_parameters,
+            size_in_bytes: weights.size_in_bytes,
+            dtype,
+            quantization: weights.quantization,
+        });
+
+        Ok(plans)
+    }
+
+    fn check_component(
+        &self,
+        index: &ModelIndex,
+        name: &ComponentName,
+        component: &ComponentElem,
+        source: &ModelSource,
+    ) -> Result<()> {
+        if matches!(component, ComponentElem::Model { .. }) {
+            check_model_class(index, name)?;
+        }
+        match (name, component) {
+            (ComponentName::Scheduler, ComponentElem::Config { files }) => {
+                serde_json::from_str::<SchedulerConfig>(
+                    &component_file(files, name, "scheduler_config.json")?
+                        .read_to_string(source)?,
+                )
+                .context("`scheduler_config.json` does not match `SchedulerConfig`")?;
+            }
+            (ComponentName::Tokenizer(1 | 2), ComponentElem::Other { files }) => {
+                load_tokenizer(name, index.required_class(name)?, files, source)?;
+            }
+            (ComponentName::TextEncoder(1), ComponentElem::Model { config, .. }) => {
+                serde_json::from_str::<ClipTextConfig>(&config.read_to_string(source)?)
+                    .context("`config.json` does not match `ClipTextConfig`")?;
+            }
+            (ComponentName::TextEncoder(2), ComponentElem::Model { config, .. }) => {
+                serde_json::from_str::<T5Config>(&config.read_to_string(source)?)
+                    .context("`config.json` does not match `T5Config`")?;
+            }
+            (ComponentName::Transformer, ComponentElem::Model { config, .. }) => {
+                let cfg = serde_json::from_str::<FluxConfig>(&config.read_to_string(source)?)
+                    .context("`config.json` does not match `FluxConfig`")?;
+                cfg.validate()
+                    .context("`config.json` has inconsistent dimensions")?;
+                self.variant.check_config(&cfg)?;
+            }
+            (ComponentName::Vae, ComponentElem::Model { config, .. }) => {
+                check_vae_config(&config.read_to_string(source)?)
+                    .context("`config.json` does not match the VAE config")?;
+            }
+            (name, other) => {
+                anyhow::bail!(
+                    "incorrect storage of {name}, found a {} component",
+                    other.kind()
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Check that the declared class of a FLUX model component is supported. The VAE is dispatched on the
+/// `_class_name` of its config.
+fn check_model_class(index: &ModelIndex, name: &ComponentName) -> Result<()> {
+    let class = index.required_class(name)?;
+    let supported: &[&str] = match name {
+        ComponentName::TextEncoder(1) => &["CLIPTextModel", "CLIPTextModelWithProjection"],
+        ComponentName::TextEncoder(2) => &["T5EncoderModel"],
+        ComponentName::Transformer => &["FluxTransformer2DModel"],
+        _ => return Ok(()),
+    };
+    if supported.contains(&class.class.as_str()) {
+        Ok(())
+    } else {
+        Err(class.unsupported(name, "flux"))
+    }
+}
+
+/// The number of T5 tokens the prompts are padded to by default: 512 for guidance-distilled models such as dev,
+/// and 256 for schnell, as in diffusers.
+fn default_max_sequence_length(guidance_embeds: bool) -> usize {
+    if guidance_embeds {
+        MAX_T5_TOKENS
+    } else {
+        256
+    }
+}
+
+/// The component the image encoders of IP-Adapters and Redux are placed as.
+fn image_encoder_component() -> ComponentName {
+    ComponentName::Other("image_encoder".to_string())
+}
+
+#[derive(Deserialize)]
+struct VaePlanConfig {
+    block_out_channels: Vec<usize>,
+}
+
+/// An IP-Adapter, whose projections were added to the transformer, with the CLIP image encoder of its image
+/// prompts.
+struct LoadedIpAdapter {
+    image_encoder: ClipVisionTransformer,
+    adapter: IpAdapterModel,
+}
+
+/// A FLUX.1 Redux prior, with the SigLIP image encoder of its image prompts.
+struct LoadedRedux {
+    image_encoder: SiglipVisionTransformer,
+    image_embedder: ReduxImageEncoder,
 }
 
 pub struct FluxPipeline {
@@ -195,85 +585,491 @@ pub struct FluxPipeline {
     t5_model: T5EncoderModel,
     vae_model: Arc<dyn VAEModel>,
     flux_model: FluxModel,
+    /// Config of the transformer, which the ControlNets and IP-Adapters must match.
+    flux_config: FluxConfig,
+    controlnets: Vec<ControlNetModel>,
+    ip_adapter: Option<LoadedIpAdapter>,
+    redux: Option<LoadedRedux>,
+    /// Embeddings of the prompts encoded so far, if enabled with [`Pipeline::set_prompt_cache`].
+    ///
+    /// [`Pipeline::set_prompt_cache`]: crate::Pipeline::set_prompt_cache
+    prompt_cache: Option<PromptCache>,
     scheduler_config: SchedulerConfig,
-    device: Device,
+    placement: ResolvedPlacement,
+    variant: FluxVariant,
 }
 
 impl FluxPipeline {
-    fn tokenize_and_pad(
+    /// VAE-encode and pack the reference images of FLUX.1 Kontext, each resized to the trained resolution with
+    /// the closest aspect ratio. Returns the concatenated image tokens repeated over the batch and their
+    /// positions, whose first axis is the index of the reference starting at 1 to tell them apart from the
+    /// generated image.
+    fn encode_references(
+        &self,
+        images: &[DynamicImage],
+        batch_size: usize,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let vae_device = self.placement.device(&ComponentName::Vae);
+        let vae_dtype = self.placement.dtype(&ComponentName::Vae);
+        let flux_device = self.placement.device(&ComponentName::Transformer);
+        let flux_dtype = self.placement.dtype(&ComponentName::Transformer);
+
+        let mut tokens = Vec::new();
+        let mut ids = Vec::new();
+        for (i, image) in images.iter().enumerate() {
+            let aspect_ratio = f64::from(image.width()) / f64::from(image.height());
+            let (width, height) = KONTEXT_RESOLUTIONS
+                .iter()
+                .copied()
+                .min_by(|(w1, h1), (w2, h2)| {
+                    let d1 = (aspect_ratio - *w1 as f64 / *h1 as f64).abs();
+                    let d2 = (aspect_ratio - *w2 as f64 / *h2 as f64).abs();
+                    d1.total_cmp(&d2)
+                })
+                .expect("Kontext resolutions are not empty");
+            let image = image_to_tensor(image, width, height, vae_device, vae_dtype)?;
+            // As in diffusers, the references are encoded to the mode of the latent distribution.
+            let (img, img_ids) = sampling::pack(&self.encode_latents_mode(&image)?)?;
+            let index =
+                Tensor::new(&[(i + 1) as f32, 0., 0.], flux_device)?.to_dtype(flux_dtype)?;
+            tokens.push(img.repeat((batch_size, 1, 1))?);
+            ids.push(img_ids.broadcast_add(&index)?.repeat((batch_size, 1, 1))?);
+        }
+        Ok((Tensor::cat(&tokens, 1)?, Tensor::cat(&ids, 1)?))
+    }
+
+    /// VAE-encode an image tensor into scaled latents on the transformer device, sampled from the latent
+    /// distribution.
+    fn encode_latents(&self, image: &Tensor) -> diffusion_rs_common::core::Result<Tensor> {
+        self.scale_latents(self.vae_model.encode(image)?)
+    }
+
+    /// VAE-encode an image tensor into the scaled mode of the latent distribution on the transformer device.
+    fn encode_latents_mode(&self, image: &Tensor) -> diffusion_rs_common::core::Result<Tensor> {
+        self.scale_latents(self.vae_model.encode_mode(image)?)
+    }
+
+    fn scale_latents(&self, latents: Tensor) -> diffusion_rs_common::core::Result<Tensor> {
+        ((latents - self.vae_model.shift_factor())? * self.vae_model.scale_factor())?
+            .to_device(self.placement.device(&ComponentName::Transformer))?
+            .to_dtype(self.placement.dtype(&ComponentName::Transformer))
+    }
+
+    /// Encode and pack the conditioning latents of the Fill and Control variants, which are concatenated to
+    /// the image tokens along the channels. For Fill, these are the latents of the image with the masked area
+    /// blanked out, followed by the mask split in 8x8 patches.
+    fn encode_condition(
+        &self,
+        params: &DiffusionGenerationParams,
+        batch_size: usize,
+    ) -> diffusion_rs_common::core::Result<Option<Tensor>> {
+        let vae_device = self.placement.device(&ComponentName::Vae);
+        let vae_dtype = self.placement.dtype(&ComponentName::Vae);
+        // The latents are generated at the image size rounded up to whole 2x2 patches.
+        let width = params.width.div_ceil(16) * 16;
+        let height = params.height.div_ceil(16) * 16;
+        let condition = match (
+            self.variant,
+            &params.image,
+            &params.mask_image,
+            &params.control_image,
+        ) {
+            (FluxVariant::Fill, Some(image), Some(mask), _) => {
+                let image = image_to_tensor(image, width, height, vae_device, vae_dtype)?;
+                let mask = mask_to_tensor(mask, width, height, vae_device, vae_dtype)?;
+                let masked_image = image.broadcast_mul(&(1. - &mask)?)?;
+                let (masked_latents, _) = sampling::pack(&self.encode_latents(&masked_image)?)?;
+                let mask = mask
+                    .reshape((1, height / 8, 8, width / 8, 8))?
+                    .permute((0, 2, 4, 1, 3))?
+                    .reshape((1, 8 * 8, height / 8, width / 8))?
+                    .to_device(masked_latents.device())?
+                    .to_dtype(masked_latents.dtype())?;
+                let (mask, _) = sampling::pack(&mask)?;
+                Tensor::cat(&[masked_latents, mask], 2)?
+            }
+            (FluxVariant::Control, _, _, Some(control_image)) => {
+                let control_image =
+                    image_to_tensor(control_image, width, height, vae_device, vae_dtype)?;
+                sampling::pack(&self.encode_latents(&control_image)?)?.0
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(condition.repeat((batch_size, 1, 1))?))
+    }
+
+    /// Encode and pack the control image of each ControlNet condition, and its control mode, repeated over the
+    /// batch.
+    fn encode_controlnet_conditions(
+        &self,
+        params: &DiffusionGenerationParams,
+        batch_size: usize,
+    ) -> diffusion_rs_common::core::Result<Vec<(Tensor, Option<Tensor>)>> {
+        let conditions = &params.controlnet_conditions;
+        if conditions.is_empty() {
+            return Ok(Vec::new());
+        }
+        match self.controlnets.len() {
+            0 => diffusion_rs_common::bail!(
+                "No ControlNet is loaded for the ControlNet conditions, see `Pipeline::load_controlnet`."
+            ),
+            1 => (),
+            n if n != conditions.len() => diffusion_rs_common::bail!(
+                "Expected a ControlNet condition for each of the {n} ControlNets, got {}.",
+                conditions.len()
+            ),
+            _ => (),
+        }
+        let vae_device = self.placement.device(&ComponentName::Vae);
+        let vae_dtype = self.placement.dtype(&ComponentName::Vae);
+        let flux_device = self.placement.device(&ComponentName::Transformer);
+        let width = params.width.div_ceil(16) * 16;
+        let height = params.height.div_ceil(16) * 16;
+        conditions
+            .iter()
+            .map(|condition| {
+                let image =
+                    image_to_tensor(&condition.image, width, height, vae_device, vae_dtype)?;
+                let (cond, _) = sampling::pack(&self.encode_latents(&image)?)?;
+                let mode = match condition.mode {
+                    Some(mode) => Some(Tensor::full(mode as u32, batch_size, flux_device)?),
+                    None => None,
+                };
+                Ok((cond.repeat((batch_size, 1, 1))?, mode))
+            })
+            .collect()
+    }
+
+    /// Encode the IP-Adapter images into image tokens on the transformer device, with their scales.
+    fn encode_ip_adapter_images(
+        &self,
+        params: &DiffusionGenerationParams,
+    ) -> diffusion_rs_common::core::Result<Vec<(Tensor, f64)>> {
+        if params.ip_adapter_images.is_empty() {
+            return Ok(Vec::new());
+        }
+        let Some(ip_adapter) = &self.ip_adapter else {
+            diffusion_rs_common::bail!(
+                "No IP-Adapter is loaded for the IP-Adapter images, see `Pipeline::load_ip_adapter`."
+            )
+        };
+        let flux_device = self.placement.device(&ComponentName::Transformer);
+        let flux_dtype = self.placement.dtype(&ComponentName::Transformer);
+        params
+            .ip_adapter_images
+            .iter()
+            .map(|ip_image| {
+                let pixel_values = ip_adapter
+                    .image_encoder
+                    .preprocess(std::slice::from_ref(&ip_image.image))?;
+                let image_embeds = ip_adapter
+                    .image_encoder
+                    .forward(&pixel_values)?
+                    .to_dtype(flux_dtype)?;
+                let tokens = ip_adapter
+                    .adapter
+                    .image_tokens(&image_embeds)?
+                    .to_device(flux_device)?;
+                Ok((tokens, ip_image.scale))
+            })
+            .collect()
+    }
+
+    /// Encode the Redux image into image tokens scaled by its strength on the transformer device, to be
+    /// appended to the T5 embeddings.
+    fn encode_redux_image(
+        &self,
+        params: &DiffusionGenerationParams,
+    ) -> diffusion_rs_common::core::Result<Option<Tensor>> {
+        let Some(redux_image) = &params.redux_image else {
+            return Ok(None);
+        };
+        let Some(redux) = &self.redux else {
+            diffusion_rs_common::bail!(
+                "No Redux prior is loaded for the Redux image, see `Pipeline::load_redux`."
+            )
+        };
+        let encoder_device = self.placement.device(&image_encoder_component());
+        let encoder_dtype = self.placement.dtype(&image_encoder_component());
+        let image_size = redux.image_encoder.image_size();
+        let pixel_values = image_to_tensor(
+            &redux_image.image,
+            image_size,
+            image_size,
+            encoder_device,
+            encoder_dtype,
+        )?;
+        let tokens = redux
+            .image_encoder
+            .forward(&pixel_values)?
+            .apply(&redux.image_embedder)?;
+        (tokens * redux_image.strength)?
+            .to_device(self.placement.device(&ComponentName::Transformer))?
+            .to_dtype(self.placement.dtype(&ComponentName::Transformer))
+            .map(Some)
+    }
+
+    /// Tokenize the prompts, truncating those longer than `max_len` tokens while keeping their end of
+    /// sequence token, with a warning reporting the dropped text.
+    fn tokenize(
         prompts: Vec<String>,
         tokenizer: &Tokenizer,
+        max_len: usize,
+        model_name: &str,
     ) -> diffusion_rs_common::core::Result<Vec<Vec<u32>>> {
-        let mut t5_tokens = Vec::new();
-        let unpadded_t5_tokens = tokenizer
+        let mut tokens = tokenizer
             .encode_batch(prompts, true)
             .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?
             .into_iter()
             .map(|e| e.get_ids().to_vec())
             .collect::<Vec<_>>();
-        let t5_max_tokens = unpadded_t5_tokens.iter().map(|x| x.len()).max().unwrap();
-        for mut tokenization in unpadded_t5_tokens {
-            tokenization.extend(vec![0; t5_max_tokens - tokenization.len()]);
-            t5_tokens.push(tokenization);
+        for tokenization in &mut tokens {
+            if tokenization.len() <= max_len {
+                continue;
+            }
+            let eos = tokenization.pop().unwrap();
+            let dropped = tokenization.split_off(max_len - 1);
+            tokenization.push(eos);
+            let dropped = tokenizer
+                .decode(&dropped, true)
+                .map_err(|e| diffusion_rs_common::core::Error::Msg(e.to_string()))?;
+            warn!(
+                "The following part of the prompt was truncated because {model_name} can only handle sequences up to {max_len} tokens: {}",
+                dropped.trim()
+            );
         }
+        Ok(tokens)
+    }
 
-        Ok(t5_tokens)
+    /// Pad the token ids with zeros to `len` tokens. Returns the token ids and the attention mask, which is 1
+    /// for the prompt tokens and 0 for the padding.
+    fn pad(
+        tokens: Vec<Vec<u32>>,
+        len: usize,
+        device: &Device,
+    ) -> diffusion_rs_common::core::Result<(Tensor, Tensor)> {
+        let mut mask = Vec::new();
+        let mut padded = Vec::new();
+        for mut tokenization in tokens {
+            let num_tokens = tokenization.len();
+            tokenization.extend(vec![0; len - num_tokens]);
+            padded.push(tokenization);
+            mask.push(
+                (0..len)
+                    .map(|i| u8::from(i < num_tokens))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        Ok((Tensor::new(padded, device)?, Tensor::new(mask, device)?))
     }
-}
 
-impl ModelPipeline for FluxPipeline {
-    fn forward(
+    /// Encode the tokens of the prompts with T5 and CLIP. The T5 tokens are padded to `max_sequence_length`.
+    fn encode_tokens(
         &mut self,
-        prompts: Vec<String>,
-        params: DiffusionGenerationParams,
+        t5_tokens: Vec<Vec<u32>>,
+        clip_tokens: Vec<Vec<u32>>,
+        max_sequence_length: usize,
         offloading_type: Option<Offloading>,
-    ) -> diffusion_rs_common::core::Result<Tensor> {
+    ) -> diffusion_rs_common::core::Result<PromptEmbeddings> {
+        let t5_device = self.placement.device(&ComponentName::TextEncoder(2));
+        let flux_device = self.placement.device(&ComponentName::Transformer);
+        let flux_dtype = self.placement.dtype(&ComponentName::Transformer);
+
         match offloading_type {
-            Some(Offloading::Full) => {
-                self.t5_model.to_device(&self.device)?;
+            Some(Offloading::Full | Offloading::Disk { .. }) => {
+                self.t5_model.to_device(t5_device)?;
+            }
+            None => (),
+        }
+
+        let (t5_input_ids, t5_mask) = Self::pad(t5_tokens, max_sequence_length, t5_device)?;
+
+        // FLUX was trained on T5 embeddings of the padded prompts without an attention mask, as in diffusers
+        // and the reference implementation.
+        let t5 = self
+            .t5_model
+            .forward(&t5_input_ids)?
+            .to_device(flux_device)?
+            .to_dtype(flux_dtype)?;
+
+        match offloading_type {
+            Some(Offloading::Full | Offloading::Disk { .. }) => {
+                self.t5_model.to_device(&Device::Cpu)?;
             }
             None => (),
         }
 
-        let mut t5_input_ids = Tensor::new(
-            Self::tokenize_and_pad(prompts.clone(), &self.t5_tokenizer)?,
-            &self.device,
+        let clip_len = clip_tokens.iter().map(Vec::len).max().unwrap();
+        let (clip_input_ids, _) = Self::pad(clip_tokens, clip_len, self.clip_model.device())?;
+        let clip_pooled = self
+            .clip_model
+            .forward(&clip_input_ids)?
+            .to_device(flux_device)?
+            .to_dtype(flux_dtype)?;
+
+        Ok(PromptEmbeddings {
+            t5,
+            t5_mask: t5_mask.to_device(flux_device)?,
+            clip_pooled: Some(clip_pooled),
+        })
+    }
+}
+
+/// The embeddings of the `i`-th prompt of a batch.
+fn prompt_embeddings_row(
+    embeddings: &PromptEmbeddings,
+    i: usize,
+) -> diffusion_rs_common::core::Result<PromptEmbeddings> {
+    Ok(PromptEmbeddings {
+        t5: embeddings.t5.narrow(0, i, 1)?,
+        t5_mask: embeddings.t5_mask.narrow(0, i, 1)?,
+        clip_pooled: embeddings
+            .clip_pooled
+            .as_ref()
+            .map(|clip_pooled| clip_pooled.narrow(0, i, 1))
+            .transpose()?,
+    })
+}
+
+impl ModelPipeline for FluxPipeline {
+    fn encode_prompts(
+        &mut self,
+        prompts: Vec<String>,
+        max_sequence_length: Option<usize>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<PromptEmbeddings> {
+        let max_sequence_length = max_sequence_length
+            .unwrap_or(default_max_sequence_length(self.flux_model.is_guidance()));
+        if !(1..=MAX_T5_TOKENS).contains(&max_sequence_length) {
+            diffusion_rs_common::bail!(
+                "`max_sequence_length` must be between 1 and {MAX_T5_TOKENS}, got {max_sequence_length}."
+            )
+        }
+
+        let t5_tokens = Self::tokenize(
+            prompts.clone(),
+            &self.t5_tokenizer,
+            max_sequence_length,
+            "T5",
+        )?;
+        let clip_tokens = Self::tokenize(
+            prompts,
+            &self.clip_tokenizer,
+            self.clip_model.max_position_embeddings(),
+            "CLIP",
         )?;
 
-        if !self.flux_model.is_guidance() {
-            match t5_input_ids.dim(1)?.cmp(&256) {
-                Ordering::Greater => {
-                    diffusion_rs_common::bail!("T5 embedding length greater than 256, please shrink the prompt or use the -dev (with guidance distillation) version.")
-                }
-                Ordering::Less | Ordering::Equal => {
-                    t5_input_ids =
-                        t5_input_ids.pad_with_zeros(D::Minus1, 0, 256 - t5_input_ids.dim(1)?)?;
+        let Some(cache) = &mut self.prompt_cache else {
+            return self.encode_tokens(
+                t5_tokens,
+                clip_tokens,
+                max_sequence_length,
+                offloading_type,
+            );
+        };
+
+        // Only the prompts missing from the cache are encoded, and the T5 encoder is not moved to the device
+        // when none are missing.
+        let keys = t5_tokens
+            .iter()
+            .zip(&clip_tokens)
+            .map(|(t5_ids, clip_ids)| {
+                let mut t5_ids = t5_ids.clone();
+                t5_ids.resize(max_sequence_length, 0);
+                PromptCacheKey {
+                    t5_ids,
+                    clip_ids: clip_ids.clone(),
                 }
+            })
+            .collect::<Vec<_>>();
+        let mut rows = keys.iter().map(|key| cache.get(key)).collect::<Vec<_>>();
+        let missing = (0..rows.len())
+            .filter(|i| rows[*i].is_none())
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            let encoded = self.encode_tokens(
+                missing.iter().map(|i| t5_tokens[*i].clone()).collect(),
+                missing.iter().map(|i| clip_tokens[*i].clone()).collect(),
+                max_sequence_length,
+                offloading_type,
+            )?;
+            let cache = self.prompt_cache.as_mut().unwrap();
+            for (j, i) in missing.into_iter().enumerate() {
+                let row = prompt_embeddings_row(&encoded, j)?;
+                cache.insert(keys[i].clone(), &row)?;
+                rows[i] = Some(row);
             }
         }
 
-        let t5_embed = self.t5_model.forward(&t5_input_ids)?;
+        let rows = rows.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+        let flux_device = self.placement.device(&ComponentName::Transformer);
+        let cat = |tensors: Vec<&Tensor>| -> diffusion_rs_common::core::Result<Tensor> {
+            Tensor::cat(&tensors, 0)?.to_device(flux_device)
+        };
+        Ok(PromptEmbeddings {
+            t5: cat(rows.iter().map(|row| &row.t5).collect())?,
+            t5_mask: cat(rows.iter().map(|row| &row.t5_mask).collect())?,
+            clip_pooled: Some(cat(rows
+                .iter()
+                .map(|row| row.clip_pooled.as_ref().unwrap())
+                .collect())?),
+        })
+    }
 
-        match offloading_type {
-            Some(Offloading::Full) => {
-                self.t5_model.to_device(&Device::Cpu)?;
+    fn set_prompt_cache(&mut self, max_bytes: Option<usize>) -> Result<()> {
+        match (max_bytes, &mut self.prompt_cache) {
+            (Some(max_bytes), Some(cache)) => cache.set_max_bytes(max_bytes),
+            (Some(max_bytes), None) => self.prompt_cache = Some(PromptCache::new(max_bytes)),
+            (None, _) => self.prompt_cache = None,
+        }
+        Ok(())
+    }
+
+    fn prompt_cache_stats(&self) -> Option<PromptCacheStats> {
+        self.prompt_cache.as_ref().map(PromptCache::stats)
+    }
+
+    fn forward(
+        &mut self,
+        prompts: Prompts,
+        params: DiffusionGenerationParams,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<Tensor> {
+        self.variant.check_params(&params)?;
+
+        let embeddings = match prompts {
+            Prompts::Text(prompts) => {
+                self.encode_prompts(prompts, params.max_sequence_length, offloading_type)?
             }
-            None => (),
+            Prompts::Embeddings(embeddings) => embeddings,
+        };
+        let flux_device = self.placement.device(&ComponentName::Transformer);
+        let flux_dtype = self.placement.dtype(&ComponentName::Transformer);
+        let Some(clip_embed) = embeddings.clip_pooled else {
+            diffusion_rs_common::bail!("FLUX requires the pooled CLIP embeddings of the prompts.")
+        };
+        if clip_embed.dim(0)? != embeddings.t5.dim(0)? {
+            diffusion_rs_common::bail!(
+                "The prompt embeddings have {} T5 and {} CLIP embeddings.",
+                embeddings.t5.dim(0)?,
+                clip_embed.dim(0)?
+            )
         }
+        let t5_embed = embeddings.t5.to_device(flux_device)?.to_dtype(flux_dtype)?;
+        let clip_embed = clip_embed.to_device(flux_device)?.to_dtype(flux_dtype)?;
 
-        let clip_input_ids = Tensor::new(
-            Self::tokenize_and_pad(prompts, &self.clip_tokenizer)?,
-            self.clip_model.device(),
-        )?;
-        let clip_embed = self.clip_model.forward(&clip_input_ids)?;
+        // The Redux image tokens extend the text sequence, at the same zero positions as the text tokens.
+        let t5_embed = match self.encode_redux_image(&params)? {
+            Some(tokens) => {
+                let tokens = tokens.repeat((t5_embed.dim(0)?, 1, 1))?;
+                Tensor::cat(&[&t5_embed, &tokens], 1)?
+            }
+            None => t5_embed,
+        };
 
-        let mut img = sampling::get_noise(
-            t5_embed.dim(0)?,
-            params.height,
-            params.width,
-            t5_embed.device(),
-        )?
-        .to_dtype(t5_embed.dtype())?;
+        let mut img =
+            sampling::get_noise(t5_embed.dim(0)?, params.height, params.width, flux_device)?
+                .to_dtype(flux_dtype)?;
 
         let state = sampling::State::new(&t5_embed, &clip_embed, &img)?;
         let mu = sampling::calculate_shift(
@@ -292,9 +1088,9 @@ impl ModelPipeline for FluxPipeline {
 
         match offloading_type {
             Some(Offloading::Full) => {
-                self.flux_model.to_device(&self.device)?;
+                self.flux_model.to_device(flux_device)?;
             }
-            None => (),
+            Some(Offloading::Disk { .. }) | None => (),
         }
 
         let guidance = if self.flux_model.is_guidance() {
@@ -302,16 +1098,85 @@ impl ModelPipeline for FluxPipeline {
         } else {
             None
         };
+        // The reference tokens are appended to the generated ones, and their prediction is dropped.
+        let reference = match self.variant {
+            FluxVariant::Kontext => {
+                let (ref_img, ref_ids) = self.encode_references(&params.reference_images, bs)?;
+                Some((ref_img, Tensor::cat(&[&state.img_ids, &ref_ids], 1)?))
+            }
+            FluxVariant::Text | FluxVariant::Fill | FluxVariant::Control => None,
+        };
+        let condition = self.encode_condition(&params, bs)?;
+        let controlnet_conditions = self.encode_controlnet_conditions(&params, bs)?;
+        let ip_tokens = self.encode_ip_adapter_images(&params)?;
+        match offloading_type {
+            Some(Offloading::Full) => {
+                for controlnet in &mut self.controlnets {
+                    controlnet.to_device(flux_device)?;
+                }
+            }
+            Some(Offloading::Disk { .. }) | None => (),
+        }
+        let num_steps = timesteps.len().saturating_sub(1);
+        let step_idx = Cell::new(0);
         let step = |img: &Tensor, t_vec: &Tensor| -> diffusion_rs_common::core::Result<Tensor> {
-            self.flux_model.forward(
-                img,
-                &state.img_ids,
-                &state.txt,
-                &state.txt_ids,
-                t_vec,
-                &state.vec,
-                guidance.as_ref(),
-            )
+            let i = step_idx.replace(step_idx.get() + 1);
+            let mut residuals: Option<ControlNetResiduals> = None;
+            for (k, (condition, (cond, mode))) in params
+                .controlnet_conditions
+                .iter()
+                .zip(&controlnet_conditions)
+                .enumerate()
+            {
+                // The ControlNet is applied to the steps within its range.
+                if (i as f64) < condition.start * num_steps as f64
+                    || ((i + 1) as f64) > condition.end * num_steps as f64
+                {
+                    continue;
+                }
+                let controlnet = &self.controlnets[k.min(self.controlnets.len() - 1)];
+                let controlnet_residuals = controlnet.forward(
+                    img,
+                    &state.img_ids,
+                    &state.txt,
+                    &state.txt_ids,
+                    t_vec,
+                    &state.vec,
+                    guidance.as_ref(),
+                    cond,
+                    mode.as_ref(),
+                    condition.scale,
+                )?;
+                residuals = Some(match residuals {
+                    Some(residuals) => residuals.add(&controlnet_residuals)?,
+                    None => controlnet_residuals,
+                });
+            }
+
+            let mut xs = match &condition {
+                Some(condition) => Tensor::cat(&[img, condition], 2)?,
+                None => img.clone(),
+            };
+            let img_ids = match &reference {
+                Some((ref_img, img_ids)) => {
+                    xs = Tensor::cat(&[&xs, ref_img], 1)?;
+                    img_ids
+                }
+                None => &state.img_ids,
+            };
+            self.flux_model
+                .forward(
+                    &xs,
+                    img_ids,
+                    &state.txt,
+                    &state.txt_ids,
+                    t_vec,
+                    &state.vec,
+                    guidance.as_ref(),
+                    residuals.as_ref(),
+                    &ip_tokens,
+                )?
+                .narrow(1, 0, img.dim(1)?)
         };
 
         let sampler = Sampler::new(&self.scheduler_config.scheduler_type);
@@ -320,11 +1185,16 @@ impl ModelPipeline for FluxPipeline {
         match offloading_type {
             Some(Offloading::Full) => {
                 self.flux_model.to_device(&Device::Cpu)?;
+                for controlnet in &mut self.controlnets {
+                    controlnet.to_device(&Device::Cpu)?;
+                }
             }
-            None => (),
+            Some(Offloading::Disk { .. }) | None => (),
         }
 
-        img = sampling::unpack(&img, params.height, params.width)?;
+        img = sampling::unpack(&img, params.height, params.width)?
+            .to_device(self.placement.device(&ComponentName::Vae))?
+            .to_dtype(self.placement.dtype(&ComponentName::Vae))?;
 
         img = ((img / self.vae_model.scale_factor())? + self.vae_model.shift_factor())?;
         img = self.vae_model.decode(&img)?;
@@ -333,4 +1203,181 @@ impl ModelPipeline for FluxPipeline {
 
         Ok(img)
     }
+
+    fn add_controlnet(
+        &mut self,
+        config: FileData,
+        weights: Vec<FileData>,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+        source: Arc<ModelSource>,
+    ) -> Result<()> {
+        if self.variant != FluxVariant::Text {
+            anyhow::bail!(
+                "The {:?} variant of FLUX does not support ControlNets.",
+                self.variant
+            );
+        }
+        let cfg: ControlNetConfig = serde_json::from_str(&config.read_to_string(&source)?)
+            .context("`config.json` does not match `ControlNetConfig`")?;
+        cfg.validate()
+            .context("`config.json` has inconsistent dimensions")?;
+        if cfg.hidden_size() != self.flux_config.hidden_size() {
+            anyhow::bail!(
+                "The ControlNet has a hidden size of {}, but the FLUX transformer has {}.",
+                cfg.hidden_size(),
+                self.flux_config.hidden_size()
+            );
+        }
+        // With full offloading, the ControlNet is copied to the device along with the transformer.
+        let device = match offloading_type {
+            Some(Offloading::Full) => Device::Cpu,
+            Some(Offloading::Disk { .. }) | None => {
+                self.placement.device(&ComponentName::Transformer).clone()
+            }
+        };
+        let vb = from_mmaped_safetensors(
+            weights,
+            Some(self.placement.dtype(&ComponentName::Transformer)),
+            &device,
+            silent,
+            source,
+        )?;
+        self.controlnets.push(ControlNetModel::new(&cfg, vb)?);
+        if !silent {
+            info!(
+                "loaded ControlNet {} with {} double and {} single blocks{}",
+                self.controlnets.len(),
+                cfg.num_layers,
+                cfg.num_single_layers,
+                match cfg.num_mode {
+                    Some(num_mode) => format!(" and {num_mode} control modes"),
+                    None => String::new(),
+                }
+            );
+        }
+        Ok(())
+    }
+
+    fn add_ip_adapter(
+        &mut self,
+        weights: Vec<FileData>,
+        source: Arc<ModelSource>,
+        image_encoder_config: FileData,
+        image_encoder_weights: Vec<FileData>,
+        image_encoder_source: Arc<ModelSource>,
+        silent: bool,
+        offloading_type: Option<Offloading>,
+    ) -> Result<()> {
+        if self.variant != FluxVariant::Text {
+            anyhow::bail!(
+                "The {:?} variant of FLUX does not support IP-Adapters.",
+                self.variant
+            );
+        }
+        let cfg = ClipVisionConfig::from_json(
+            &image_encoder_config.read_to_string(&image_encoder_source)?,
+        )
+        .context("`config.json` of the image encoder does not match `ClipVisionConfig`")?;
+        let encoder_device = self.placement.device(&image_encoder_component()).clone();
+        let vb = from_mmaped_safetensors(
+            image_encoder_weights,
+            Some(self.placement.dtype(&image_encoder_component())),
+            &encoder_device,
+            silent,
+            image_encoder_source,
+        )?;
+        let image_encoder = ClipVisionTransformer::new(vb, &cfg)?;
+
+        // With full offloading, the projections of the blocks are copied to the device along with the
+        // transformer. The image projection runs next to the image encoder.
+        let device = match offloading_type {
+            Some(Offloading::Full) => Device::Cpu,
+            Some(Offloading::Disk { .. }) | None => {
+                self.placement.device(&ComponentName::Transformer).clone()
+            }
+        };
+        let vb = from_mmaped_safetensors(
+            weights,
+            Some(self.placement.dtype(&ComponentName::Transformer)),
+            &device,
+            silent,
+            source,
+        )?;
+        let mut adapter = IpAdapterModel::new(&self.flux_config, vb, &encoder_device)?;
+        if adapter.clip_embedding_dim()? != cfg.projection_dim {
+            anyhow::bail!(
+                "The IP-Adapter expects image embeddings of {} dimensions, but the image encoder projects into {}.",
+                adapter.clip_embedding_dim()?,
+                cfg.projection_dim
+            );
+        }
+        let attentions = adapter.take_attentions();
+        let num_blocks = attentions.iter().flatten().count();
+        self.flux_model.set_ip_adapter(attentions)?;
+        self.ip_adapter = Some(LoadedIpAdapter {
+            image_encoder,
+            adapter,
+        });
+        if !silent {
+            info!("loaded IP-Adapter for {num_blocks} double stream blocks");
+        }
+        Ok(())
+    }
+
+    fn add_redux(
+        &mut self,
+        image_encoder_config: FileData,
+        image_encoder_weights: Vec<FileData>,
+        image_embedder_config: Option<FileData>,
+        image_embedder_weights: Vec<FileData>,
+        source: Arc<ModelSource>,
+        silent: bool,
+    ) -> Result<()> {
+        let encoder_cfg = SiglipVisionConfig::from_json(
+            &image_encoder_config.read_to_string(&source)?,
+        )
+        .context("`config.json` of the image encoder does not match `SiglipVisionConfig`")?;
+        let embedder_cfg: ReduxConfig = match image_embedder_config {
+            Some(config) => serde_json::from_str(&config.read_to_string(&source)?)
+                .context("`config.json` of the image embedder does not match `ReduxConfig`")?,
+            None => serde_json::from_str("{}")?,
+        };
+        if embedder_cfg.redux_dim != encoder_cfg.hidden_size {
+            anyhow::bail!(
+                "The Redux image embedder expects image features of {} dimensions, but the image encoder has {}.",
+                embedder_cfg.redux_dim,
+                encoder_cfg.hidden_size
+            );
+        }
+        if embedder_cfg.txt_in_features != self.flux_config.joint_attention_dim {
+            anyhow::bail!(
+                "The Redux image embedder projects into {} dimensions, but the transformer takes text embeddings of {}.",
+                embedder_cfg.txt_in_features,
+                self.flux_config.joint_attention_dim
+            );
+        }
+
+        let device = self.placement.device(&image_encoder_component()).clone();
+        let dtype = self.placement.dtype(&image_encoder_component());
+        let vb = from_mmaped_safetensors(
+            image_encoder_weights,
+            Some(dtype),
+            &device,
+            silent,
+            source.clone(),
+        )?;
+        let image_encoder = SiglipVisionTransformer::new(vb, &encoder_cfg)?;
+        let vb =
+            from_mmaped_safetensors(image_embedder_weights, Some(dtype), &device, silent, source)?;
+        let image_embedder = ReduxImageEncoder::new(&embedder_cfg, vb)?;
+        self.redux = Some(LoadedRedux {
+            image_encoder,
+            image_embedder,
+        });
+        if !silent {
+            info!("loaded Redux");
+        }
+        Ok(())
+    }
 }
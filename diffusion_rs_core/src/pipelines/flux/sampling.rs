@@ -24,29 +24,10 @@ pub struct State {
 
 impl State {
     pub fn new(t5_emb: &Tensor, clip_emb: &Tensor, img: &Tensor) -> Result<Self> {
-        let dtype = img.dtype();
-        let (bs, c, h, w) = img.dims4()?;
-        let dev = img.device();
-        let img = img.reshape((bs, c, h / 2, 2, w / 2, 2))?; // (b, c, h, ph, w, pw)
-        let img = img.permute((0, 2, 4, 1, 3, 5))?; // (b, h, w, c, ph, pw)
-        let img = img.reshape((bs, h / 2 * w / 2, c * 4))?;
-        let img_ids = Tensor::stack(
-            &[
-                Tensor::full(0u32, (h / 2, w / 2), dev)?,
-                Tensor::arange(0u32, h as u32 / 2, dev)?
-                    .reshape(((), 1))?
-                    .broadcast_as((h / 2, w / 2))?,
-                Tensor::arange(0u32, w as u32 / 2, dev)?
-                    .reshape((1, ()))?
-                    .broadcast_as((h / 2, w / 2))?,
-            ],
-            2,
-        )?
-        .to_dtype(dtype)?;
-        let img_ids = img_ids.reshape((1, h / 2 * w / 2, 3))?;
-        let img_ids = img_ids.repeat((bs, 1, 1))?;
+        let (img, img_ids) = pack(img)?;
+        let bs = img.dim(0)?;
         let txt = t5_emb.repeat(bs)?;
-        let txt_ids = Tensor::zeros((bs, txt.dim(1)?, 3), dtype, dev)?;
+        let txt_ids = Tensor::zeros((bs, txt.dim(1)?, 3), img.dtype(), img.device())?;
         let vec = clip_emb.repeat(bs)?;
         Ok(Self {
             img,
@@ -58,6 +39,32 @@ impl State {
     }
 }
 
+/// Pack the latents in 2x2 patches, returning the image tokens and their positions.
+pub fn pack(img: &Tensor) -> Result<(Tensor, Tensor)> {
+    let dtype = img.dtype();
+    let (bs, c, h, w) = img.dims4()?;
+    let dev = img.device();
+    let img = img.reshape((bs, c, h / 2, 2, w / 2, 2))?; // (b, c, h, ph, w, pw)
+    let img = img.permute((0, 2, 4, 1, 3, 5))?; // (b, h, w, c, ph, pw)
+    let img = img.reshape((bs, h / 2 * w / 2, c * 4))?;
+    let img_ids = Tensor::stack(
+        &[
+            Tensor::full(0u32, (h / 2, w / 2), dev)?,
+            Tensor::arange(0u32, h as u32 / 2, dev)?
+                .reshape(((), 1))?
+                .broadcast_as((h / 2, w / 2))?,
+            Tensor::arange(0u32, w as u32 / 2, dev)?
+                .reshape((1, ()))?
+                .broadcast_as((h / 2, w / 2))?,
+        ],
+        2,
+    )?
+    .to_dtype(dtype)?;
+    let img_ids = img_ids.reshape((1, h / 2 * w / 2, 3))?;
+    let img_ids = img_ids.repeat((bs, 1, 1))?;
+    Ok((img, img_ids))
+}
+
 pub fn unpack(xs: &Tensor, height: usize, width: usize) -> Result<Tensor> {
     let (b, _h_w, c_ph_pw) = xs.dims3()?;
     let height = (height + 15) / 16;
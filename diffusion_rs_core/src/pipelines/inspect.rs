@@ -0,0 +1,364 @@
+use std::{collections::BTreeMap, fmt::Display, fs, path::Path};
+
+use diffusion_rs_common::{
+    read_dduf_entries, FileData, ModelSource, TokenSource, DDUF_ALIGNMENT, DDUF_ALLOWED_EXTENSIONS,
+};
+use serde::Serialize;
+
+use super::{
+    load_components,
+    plan::{describe_quantization, format_bytes, read_quantization_config, read_tensor_headers},
+    verify_component, ComponentElem, LoadedComponents,
+};
+
+/// Outcome of a single check of [`Pipeline::inspect`](crate::Pipeline::inspect).
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectCheck {
+    pub name: String,
+    pub passed: bool,
+    /// Why the check failed.
+    pub message: Option<String>,
+}
+
+/// An entry of a DDUF file.
+#[derive(Debug, Clone, Serialize)]
+pub struct DdufEntryReport {
+    pub name: String,
+    pub size: u64,
+    /// Offset of the entry data from the start of the file.
+    pub data_start: u64,
+    pub aligned: bool,
+    pub compression: String,
+}
+
+/// A file of a component.
+#[derive(Debug, Clone, Serialize)]
+pub struct FileReport {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Summary of the header of a weights file.
+#[derive(Debug, Clone, Serialize)]
+pub struct WeightsReport {
+    pub file: String,
+    pub num_tensors: usize,
+    /// Number of tensors of each dtype.
+    pub dtypes: BTreeMap<String, usize>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentReport {
+    pub name: String,
+    /// One of `model`, `config` or `other`.
+    pub kind: String,
+    pub files: Vec<FileReport>,
+    pub weights: Vec<WeightsReport>,
+    /// Quantization read from the `quantization_config`, if any.
+    pub quantization: Option<String>,
+}
+
+/// Result of [`Pipeline::inspect`](crate::Pipeline::inspect): the layout of a model and whether it passes
+/// the checks done before loading it.
+#[derive(Debug, Clone, Serialize)]
+pub struct InspectReport {
+    pub source: String,
+    /// Architecture of the model, if `model_index.json` could be read.
+    pub model: Option<String>,
+    /// Entries of the DDUF file, empty for other model sources.
+    pub dduf_entries: Vec<DdufEntryReport>,
+    pub components: Vec<ComponentReport>,
+    pub checks: Vec<InspectCheck>,
+}
+
+impl InspectReport {
+    /// Whether all checks passed.
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+
+    fn check(&mut self, name: impl ToString, result: anyhow::Result<()>) {
+        self.checks.push(InspectCheck {
+            name: name.to_string(),
+            passed: result.is_ok(),
+            message: result.err().map(|e| format!("{e:#}")),
+        });
+    }
+}
+
+impl Display for InspectReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Source: {}", self.source)?;
+        writeln!(f, "Model: {}", self.model.as_deref().unwrap_or("unknown"))?;
+
+        if !self.dduf_entries.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "DDUF entries:")?;
+            writeln!(
+                f,
+                "  {:<48} {:>11} {:>12} {:>7}  compression",
+                "name", "size", "offset", "aligned"
+            )?;
+            for entry in &self.dduf_entries {
+                writeln!(
+                    f,
+                    "  {:<48} {:>11} {:>12} {:>7}  {}",
+                    entry.name,
+                    format_bytes(entry.size as usize),
+                    entry.data_start,
+                    if entry.aligned { "yes" } else { "no" },
+                    entry.compression,
+                )?;
+            }
+        }
+
+        for component in &self.components {
+            writeln!(f)?;
+            write!(f, "{} ({})", component.name, component.kind)?;
+            if let Some(quantization) = &component.quantization {
+                write!(f, ", quantized with {quantization}")?;
+            }
+            writeln!(f)?;
+            for file in &component.files {
+                writeln!(
+                    f,
+                    "  {:<48} {:>11}",
+                    file.name,
+                    format_bytes(file.size as usize)
+                )?;
+            }
+            for weights in &component.weights {
+                let dtypes = weights
+                    .dtypes
+                    .iter()
+                    .map(|(dtype, count)| format!("{count} {dtype}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                writeln!(
+                    f,
+                    "  {}: {} tensors ({dtypes})",
+                    weights.file, weights.num_tensors
+                )?;
+            }
+        }
+
+        writeln!(f)?;
+        writeln!(f, "Checks:")?;
+        for check in &self.checks {
+            let status = if check.passed { "pass" } else { "FAIL" };
+            match &check.message {
+                Some(message) => writeln!(f, "  [{status}] {}: {message}", check.name)?,
+                None => writeln!(f, "  [{status}] {}", check.name)?,
+            }
+        }
+        let failed = self.checks.iter().filter(|check| !check.passed).count();
+        writeln!(f)?;
+        if failed == 0 {
+            write!(f, "PASS: all {} checks passed.", self.checks.len())
+        } else {
+            write!(f, "FAIL: {failed} of {} checks failed.", self.checks.len())
+        }
+    }
+}
+
+fn file_size(file: &FileData) -> anyhow::Result<u64> {
+    Ok(match file {
+        FileData::Path(path) => fs::metadata(path)?.len(),
+        FileData::Dduf { start, end, .. } => (end - start) as u64,
+        FileData::DdufOwned { data, .. } => data.len() as u64,
+        FileData::Header { name, .. } => {
+            anyhow::bail!("Only the header of `{}` was read.", name.display())
+        }
+    })
+}
+
+fn file_name(file: &FileData) -> String {
+    file.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| format!("{file:?}"))
+}
+
+/// Check the structure of a DDUF file against the specification.
+fn check_dduf(report: &mut InspectReport, source: &ModelSource) -> anyhow::Result<()> {
+    let entries = read_dduf_entries(source)?;
+    report.dduf_entries = entries
+        .iter()
+        .map(|entry| DdufEntryReport {
+            name: entry.name.clone(),
+            size: entry.size,
+            data_start: entry.data_start,
+            aligned: entry.is_aligned(),
+            compression: format!("{:?}", entry.compression).to_lowercase(),
+        })
+        .collect();
+
+    let first = entries.first().map(|entry| entry.name.as_str());
+    report.check(
+        "dduf: `model_index.json` is the first entry",
+        match first {
+            Some("model_index.json") => Ok(()),
+            Some(other) => Err(anyhow::anyhow!("the first entry is `{other}`")),
+            None => Err(anyhow::anyhow!("the file has no entries")),
+        },
+    );
+
+    let failing = |predicate: &dyn Fn(&DdufEntryReport) -> bool| -> anyhow::Result<()> {
+        let names = report
+            .dduf_entries
+            .iter()
+            .filter(|entry| !entry.name.ends_with('/') && predicate(entry))
+            .map(|entry| format!("`{}`", entry.name))
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("{}", names.join(", ")))
+        }
+    };
+    let stored = failing(&|entry| entry.compression != "stored");
+    let aligned = failing(&|entry| !entry.aligned);
+    let extensions = failing(&|entry| {
+        !Path::new(&entry.name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| DDUF_ALLOWED_EXTENSIONS.contains(&ext))
+    });
+    let depth = failing(&|entry| entry.name.trim_end_matches('/').matches('/').count() > 1);
+    report.check("dduf: entries are stored uncompressed", stored);
+    report.check(
+        format!("dduf: entries are aligned to {DDUF_ALIGNMENT} bytes"),
+        aligned,
+    );
+    report.check(
+        format!("dduf: entries have one of the extensions {DDUF_ALLOWED_EXTENSIONS:?}"),
+        extensions,
+    );
+    report.check("dduf: entries are at most one directory deep", depth);
+    Ok(())
+}
+
+/// Inspect a model source, see [`Pipeline::inspect`](crate::Pipeline::inspect).
+pub(crate) fn inspect(
+    mut source: ModelSource,
+    silent: bool,
+    token: TokenSource,
+    revision: Option<String>,
+    verify: bool,
+) -> anyhow::Result<InspectReport> {
+    let mut report = InspectReport {
+        source: source.to_string(),
+        model: None,
+        dduf_entries: Vec::new(),
+        components: Vec::new(),
+        checks: Vec::new(),
+    };
+
+    if matches!(source, ModelSource::Dduf { .. }) {
+        if let Err(e) = check_dduf(&mut report, &source) {
+            report.check("dduf: file is a valid zip archive", Err(e));
+        }
+    }
+
+    let LoadedComponents {
+        loader,
+        index,
+        components,
+        ..
+    } = match load_components(&mut source, silent, token, revision, false) {
+        Ok(loaded) => {
+            report.check(
+                "model: `model_index.json` and the components can be read",
+                Ok(()),
+            );
+            loaded
+        }
+        Err(e) => {
+            report.check(
+                "model: `model_index.json` and the components can be read",
+                Err(e),
+            );
+            return Ok(report);
+        }
+    };
+    report.model = Some(loader.name().to_string());
+
+    let mut components = components.into_iter().collect::<Vec<_>>();
+    components.sort_by(|(a, _), (b, _)| a.cmp(b));
+    for (name, component) in components {
+        let mut component_report = ComponentReport {
+            name: name.to_string(),
+            kind: component.kind().to_string(),
+            files: Vec::new(),
+            weights: Vec::new(),
+            quantization: None,
+        };
+        let mut files = match &component {
+            ComponentElem::Model { weights, config } => {
+                let mut weight_files = weights.values().collect::<Vec<_>>();
+                weight_files.sort_by_key(|file| file_name(file));
+
+                let mut result = Ok(());
+                for file in &weight_files {
+                    match read_tensor_headers(file, &source) {
+                        Ok(headers) => {
+                            let mut dtypes = BTreeMap::new();
+                            for header in headers.values() {
+                                *dtypes.entry(header.dtype.as_str().to_string()).or_default() += 1;
+                            }
+                            component_report.weights.push(WeightsReport {
+                                file: file_name(file),
+                                num_tensors: headers.len(),
+                                dtypes,
+                            });
+                        }
+                        Err(e) => {
+                            result = Err(e.context(format!("reading `{}`", file_name(file))));
+                        }
+                    }
+                }
+                report.check(format!("{name}: weight headers are valid"), result);
+
+                match read_quantization_config(config, &source) {
+                    Ok(quantization) => {
+                        component_report.quantization =
+                            quantization.as_ref().map(describe_quantization)
+                    }
+                    Err(e) => {
+                        report.check(format!("{name}: `quantization_config` is valid"), Err(e))
+                    }
+                }
+
+                let mut files = vec![config];
+                files.extend(weight_files);
+                files
+            }
+            ComponentElem::Config { files } | ComponentElem::Other { files } => {
+                files.values().collect()
+            }
+        }
+        .into_iter()
+        .map(|file| {
+            Ok(FileReport {
+                name: file_name(file),
+                size: file_size(file)?,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        component_report.files = files;
+
+        report.check(
+            format!("{name}: configs match the expected structure"),
+            loader.check_component(&index, &name, &component, &source),
+        );
+        if verify {
+            report.check(
+                format!("{name}: files are intact"),
+                verify_component(&name, &component, &source),
+            );
+        }
+        report.components.push(component_report);
+    }
+
+    Ok(report)
+}
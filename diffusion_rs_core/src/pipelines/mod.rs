@@ -1,23 +1,49 @@
+mod chroma;
+mod embeddings;
 mod flux;
+mod inspect;
+mod model_index;
+mod placement;
+mod plan;
+mod preprocess;
+mod processing;
+mod prompt_cache;
+mod registry;
 mod sampling;
+mod save;
 mod scheduler;
 
 use std::{
     collections::HashMap,
     fmt::Display,
+    path::Path,
+    str::FromStr,
     sync::{Arc, Mutex},
 };
 
-use anyhow::Result;
-use diffusion_rs_common::core::{DType, Device, Tensor};
-use flux::FluxLoader;
+use anyhow::{Context, Result};
+use diffusion_rs_common::core::Tensor;
 use image::{DynamicImage, RgbImage};
 use serde::Deserialize;
 
-use diffusion_rs_common::{FileData, FileLoader, ModelSource, NiceProgressBar, TokenSource};
+use diffusion_rs_common::{
+    verify_file, FileData, FileLoader, ModelSource, NiceProgressBar, TokenSource,
+};
 use tracing::info;
 
-use crate::TryIntoDType;
+use crate::{DeviceSpec, TryIntoDType};
+
+pub use embeddings::{PromptEmbeddings, Prompts};
+pub use inspect::{
+    ComponentReport, DdufEntryReport, FileReport, InspectCheck, InspectReport, WeightsReport,
+};
+pub use model_index::{ComponentClass, ComponentKind, ModelIndex};
+pub use placement::{ComponentPlacement, PlacementMap, ResolvedPlacement};
+pub use plan::{ComponentPlan, ComponentResidence, LoadPlan, PlanParams};
+pub use preprocess::ControlPreprocessor;
+pub use prompt_cache::PromptCacheStats;
+pub use registry::{register_loader, registered_loaders};
+pub use save::SaveFormat;
 
 /// Generation parameters.
 #[derive(Debug, Clone)]
@@ -30,22 +56,121 @@ pub struct DiffusionGenerationParams {
     /// Higher guidance scale encourages to generate images that are closely linked to the text `prompt`,
     /// usually at the expense of lower image quality.
     pub guidance_scale: f64,
+    /// Prompt to guide the image away from, for models with real classifier-free guidance such as Chroma.
+    /// Guidance-distilled models such as FLUX ignore it.
+    pub negative_prompt: Option<String>,
+    /// Images to edit or take the subject from, for image-conditioned models such as FLUX.1 Kontext. They are
+    /// resized to a resolution supported by the model, keeping their aspect ratio.
+    pub reference_images: Vec<DynamicImage>,
+    /// Image to inpaint with FLUX.1 Fill, where `mask_image` is white.
+    pub image: Option<DynamicImage>,
+    /// Mask of the area of `image` to inpaint, white where the image is regenerated.
+    pub mask_image: Option<DynamicImage>,
+    /// Structural conditioning such as a Canny edge map or a depth map, for FLUX.1 Canny and Depth.
+    pub control_image: Option<DynamicImage>,
+    /// Conditioning of the ControlNets loaded with [`Pipeline::load_controlnet`], one for each ControlNet in
+    /// the order they were loaded. A single ControlNet is applied to every condition, such as the different
+    /// control modes of a Union ControlNet.
+    pub controlnet_conditions: Vec<ControlNetCondition>,
+    /// Images prompting the IP-Adapter loaded with [`Pipeline::load_ip_adapter`], such as a style
+    /// reference.
+    pub ip_adapter_images: Vec<IpAdapterImage>,
+    /// Image whose content and style are carried over by the FLUX.1 Redux prior loaded with
+    /// [`Pipeline::load_redux`], for image variations.
+    pub redux_image: Option<ReduxImage>,
+    /// Number of T5 tokens the prompts are padded to, and truncated to with a warning, for FLUX. Defaults to
+    /// 512 for guidance-distilled models such as FLUX.1 dev, and 256 for FLUX.1 schnell.
+    pub max_sequence_length: Option<usize>,
+}
+
+/// Conditioning of a ControlNet.
+#[derive(Debug, Clone)]
+pub struct ControlNetCondition {
+    /// Control image, such as a Canny edge map or a depth map.
+    pub image: DynamicImage,
+    /// Scale of the residuals of the ControlNet.
+    pub scale: f64,
+    /// Fraction of the denoising steps after which the ControlNet is applied.
+    pub start: f64,
+    /// Fraction of the denoising steps after which the ControlNet is no longer applied.
+    pub end: f64,
+    /// Control mode of a Union ControlNet, such as canny or depth. The modes are listed in the model card of
+    /// the ControlNet.
+    pub mode: Option<usize>,
+}
+
+impl ControlNetCondition {
+    /// A condition applied with a scale of 1 over all the denoising steps.
+    pub fn new(image: DynamicImage) -> Self {
+        Self {
+            image,
+            scale: 1.,
+            start: 0.,
+            end: 1.,
+            mode: None,
+        }
+    }
+}
+
+/// An image prompt of an IP-Adapter.
+#[derive(Debug, Clone)]
+pub struct IpAdapterImage {
+    pub image: DynamicImage,
+    /// Scale of the attention to the image tokens.
+    pub scale: f64,
+}
+
+impl IpAdapterImage {
+    /// An image prompt with a scale of 1.
+    pub fn new(image: DynamicImage) -> Self {
+        Self { image, scale: 1. }
+    }
+}
+
+/// An image prompt of the FLUX.1 Redux prior.
+#[derive(Debug, Clone)]
+pub struct ReduxImage {
+    pub image: DynamicImage,
+    /// Scale of the image tokens appended to the text embeddings.
+    pub strength: f64,
+}
+
+impl ReduxImage {
+    /// An image prompt with a strength of 1.
+    pub fn new(image: DynamicImage) -> Self {
+        Self {
+            image,
+            strength: 1.,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub(crate) enum ComponentElem {
+/// The files of a pipeline component, keyed by their path in the model repository.
+#[derive(Debug, Clone)]
+pub enum ComponentElem {
+    /// A model with weights (safetensors or PyTorch checkpoints) and a `config.json`.
     Model {
-        safetensors: HashMap<String, FileData>,
+        weights: HashMap<String, FileData>,
         config: FileData,
     },
-    Config {
-        files: HashMap<String, FileData>,
-    },
-    Other {
-        files: HashMap<String, FileData>,
-    },
+    /// Only JSON files, such as a scheduler config.
+    Config { files: HashMap<String, FileData> },
+    /// Any other files, such as a tokenizer vocabulary.
+    Other { files: HashMap<String, FileData> },
+}
+
+impl ComponentElem {
+    /// One of `model`, `config` or `other`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Model { .. } => "model",
+            Self::Config { .. } => "config",
+            Self::Other { .. } => "other",
+        }
+    }
 }
 
+/// A component of a pipeline, named after its directory in the model repository.
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ComponentName {
     Scheduler,
@@ -53,6 +178,8 @@ pub enum ComponentName {
     Tokenizer(usize),
     Transformer,
     Vae,
+    /// Any other component directory, for pipelines added with [`register_loader`].
+    Other(String),
 }
 
 impl Display for ComponentName {
@@ -65,51 +192,500 @@ impl Display for ComponentName {
             Self::TextEncoder(x) => write!(f, "text_encoder_{x}"),
             Self::Tokenizer(1) => write!(f, "tokenizer"),
             Self::Tokenizer(x) => write!(f, "tokenizer_{x}"),
+            Self::Other(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+impl From<&str> for ComponentName {
+    /// The component stored in the directory `name`.
+    fn from(name: &str) -> Self {
+        match name {
+            "scheduler" => Self::Scheduler,
+            "transformer" => Self::Transformer,
+            "vae" => Self::Vae,
+            "text_encoder" => Self::TextEncoder(1),
+            "tokenizer" => Self::Tokenizer(1),
+            other => match other.rsplit_once('_') {
+                Some(("text_encoder", x)) if x.parse::<usize>().is_ok_and(|x| x > 1) => {
+                    Self::TextEncoder(x.parse().unwrap())
+                }
+                Some(("tokenizer", x)) if x.parse::<usize>().is_ok_and(|x| x > 1) => {
+                    Self::Tokenizer(x.parse().unwrap())
+                }
+                _ => Self::Other(other.to_string()),
+            },
         }
     }
 }
 
+/// Default memory budget for resident transformer blocks with [`Offloading::Disk`]: 4 GiB, capped to the
+/// address space on 32-bit targets.
+pub const DEFAULT_DISK_OFFLOADING_BUDGET: usize = {
+    let budget: u64 = 4 * 1024 * 1024 * 1024;
+    if budget > usize::MAX as u64 {
+        usize::MAX
+    } else {
+        budget as usize
+    }
+};
+
 /// Offloading setting during loading.
 ///
 /// - Full: offload the largest components of the model to CPU memory and copy them into VRAM as necessary.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+/// - Disk: keep the transformer blocks memory-mapped on disk and materialize them one at a time during the
+///   denoising loop. At most `memory_budget` bytes of blocks are kept resident, and the next block is prefetched.
+///
+/// When parsing from a string, this accepts `full`, `disk`, or `disk:<budget in MiB>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Offloading {
     Full,
+    Disk { memory_budget: usize },
+}
+
+impl FromStr for Offloading {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "full" => Ok(Self::Full),
+            None if s == "disk" => Ok(Self::Disk {
+                memory_budget: DEFAULT_DISK_OFFLOADING_BUDGET,
+            }),
+            Some(("disk", budget)) => {
+                let budget_mb = budget
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid disk offloading budget `{budget}`: {e}"))?;
+                let memory_budget = budget_mb.checked_mul(1024 * 1024).ok_or_else(|| {
+                    format!("Disk offloading budget of {budget_mb} MiB is too large")
+                })?;
+                Ok(Self::Disk { memory_budget })
+            }
+            _ => Err(format!(
+                "Invalid offloading `{s}`, expected one of `full`, `disk`, `disk:<budget in MiB>`"
+            )),
+        }
+    }
 }
 
-pub(crate) trait Loader {
+impl Display for Offloading {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Full => write!(f, "full"),
+            Self::Disk { memory_budget } => write!(f, "disk:{}", memory_budget / (1024 * 1024)),
+        }
+    }
+}
+
+/// Loads a pipeline from the components of a model, see [`register_loader`].
+///
+/// The component files are gathered by [`Pipeline::load`] from a DDUF file or the Hugging Face Hub, and the
+/// device and dtype of each component are resolved in the [`ResolvedPlacement`].
+pub trait Loader: Send + Sync {
+    /// Short name of the architecture, used in logs and reports.
     fn name(&self) -> &'static str;
+    /// The components which must be declared in `model_index.json` and are always read.
     fn required_component_names(&self) -> Vec<ComponentName>;
+    /// The components which are read if they are declared in `model_index.json` and not null. Other
+    /// components of the model are ignored.
+    fn optional_component_names(&self) -> Vec<ComponentName> {
+        Vec::new()
+    }
+    /// How the files of a component are gathered, from the class it declares in `model_index.json`. By
+    /// default, only the classes of the built-in pipelines are supported, see [`ComponentKind::of`].
+    fn component_kind(
+        &self,
+        name: &ComponentName,
+        class: &ComponentClass,
+    ) -> Result<ComponentKind> {
+        ComponentKind::of(name, class)
+    }
     fn load_from_components(
         &self,
+        index: &ModelIndex,
         components: HashMap<ComponentName, ComponentElem>,
-        device: &Device,
-        dtype: DType,
+        placement: &ResolvedPlacement,
         silent: bool,
         offloading_type: Option<Offloading>,
         source: Arc<ModelSource>,
     ) -> Result<Arc<Mutex<dyn ModelPipeline>>>;
+    /// Estimate the memory usage of each model component without loading any weights.
+    fn plan_components(
+        &self,
+        components: &HashMap<ComponentName, ComponentElem>,
+        placement: &ResolvedPlacement,
+        offloading_type: Option<Offloading>,
+        params: &PlanParams,
+        source: &ModelSource,
+    ) -> Result<Vec<ComponentPlan>> {
+        let _ = (components, placement, offloading_type, params, source);
+        anyhow::bail!("Planning is not supported for {} models.", self.name())
+    }
+    /// Parse the configs and tokenizers of a component into the structs used to load it, without loading
+    /// any weights. By default, no checks are done.
+    fn check_component(
+        &self,
+        index: &ModelIndex,
+        name: &ComponentName,
+        component: &ComponentElem,
+        source: &ModelSource,
+    ) -> Result<()> {
+        let _ = (index, name, component, source);
+        Ok(())
+    }
 }
 
+/// A loaded pipeline which generates images, returned by [`Loader::load_from_components`].
 pub trait ModelPipeline: Send + Sync {
+    /// Generate a batch of images with values in `0..=255`, with shape `(batch, 3, height, width)`.
     fn forward(
         &mut self,
-        prompts: Vec<String>,
+        prompts: Prompts,
         params: DiffusionGenerationParams,
         offloading_type: Option<Offloading>,
     ) -> diffusion_rs_common::core::Result<Tensor>;
+
+    /// Encode a batch of prompts with the text encoders, see [`Pipeline::encode_prompts`].
+    fn encode_prompts(
+        &mut self,
+        prompts: Vec<String>,
+        max_sequence_length: Option<usize>,
+        offloading_type: Option<Offloading>,
+    ) -> diffusion_rs_common::core::Result<PromptEmbeddings>;
+
+    /// Enable, resize or disable the prompt-embedding cache, see [`Pipeline::set_prompt_cache`].
+    fn set_prompt_cache(&mut self, max_bytes: Option<usize>) -> Result<()> {
+        match max_bytes {
+            Some(_) => anyhow::bail!("This pipeline does not support prompt caching."),
+            None => Ok(()),
+        }
+    }
+
+    /// Counters of the prompt-embedding cache, if it is enabled.
+    fn prompt_cache_stats(&self) -> Option<PromptCacheStats> {
+        None
+    }
+
+    /// Add a ControlNet from its `config.json` and weights, see [`Pipeline::load_controlnet`].
+    fn add_controlnet(
+        &mut self,
+        _config: FileData,
+        _weights: Vec<FileData>,
+        _silent: bool,
+        _offloading_type: Option<Offloading>,
+        _source: Arc<ModelSource>,
+    ) -> Result<()> {
+        anyhow::bail!("This pipeline does not support ControlNets.")
+    }
+
+    /// Add an IP-Adapter from its weights and the `config.json` and weights of its CLIP image encoder, see
+    /// [`Pipeline::load_ip_adapter`].
+    #[allow(clippy::too_many_arguments)]
+    fn add_ip_adapter(
+        &mut self,
+        _weights: Vec<FileData>,
+        _source: Arc<ModelSource>,
+        _image_encoder_config: FileData,
+        _image_encoder_weights: Vec<FileData>,
+        _image_encoder_source: Arc<ModelSource>,
+        _silent: bool,
+        _offloading_type: Option<Offloading>,
+    ) -> Result<()> {
+        anyhow::bail!("This pipeline does not support IP-Adapters.")
+    }
+
+    /// Add a FLUX.1 Redux prior from the `config.json` and weights of its SigLIP image encoder and of its
+    /// image embedder, see [`Pipeline::load_redux`].
+    #[allow(clippy::too_many_arguments)]
+    fn add_redux(
+        &mut self,
+        _image_encoder_config: FileData,
+        _image_encoder_weights: Vec<FileData>,
+        _image_embedder_config: Option<FileData>,
+        _image_embedder_weights: Vec<FileData>,
+        _source: Arc<ModelSource>,
+        _silent: bool,
+    ) -> Result<()> {
+        anyhow::bail!("This pipeline does not support Redux.")
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
-struct ModelIndex {
-    #[serde(rename = "_class_name")]
-    name: String,
+struct WeightIndex {
+    weight_map: HashMap<String, String>,
+}
+
+/// Find the PyTorch checkpoint files of a component. For sharded checkpoints, only the shards listed in the
+/// `*.bin.index.json` file are used.
+fn pickle_weight_files(
+    loader: &mut FileLoader,
+    dir: &str,
+    files: &[String],
+    from_transformer: bool,
+) -> Result<Vec<String>> {
+    if let Some(index) = files.iter().find(|file| file.ends_with(".bin.index.json")) {
+        let WeightIndex { weight_map } = serde_json::from_str(
+            &loader
+                .read_file_copied(index, from_transformer)?
+                .read_to_string_owned()?,
+        )?;
+        let mut shards = weight_map
+            .into_values()
+            .map(|shard| format!("{dir}{shard}"))
+            .collect::<Vec<_>>();
+        shards.sort();
+        shards.dedup();
+        return Ok(shards);
+    }
+
+    Ok(files
+        .iter()
+        .filter(|file| {
+            [".bin", ".pt", ".pth", ".ckpt"]
+                .iter()
+                .any(|ext| file.ends_with(ext))
+        })
+        .cloned()
+        .collect())
+}
+
+/// Read a standalone model, such as a ControlNet, with its `config.json` if present and the safetensors
+/// weights at the root of `source`. `what` names the model in errors.
+fn read_standalone_model(
+    source: &mut ModelSource,
+    silent: bool,
+    token: TokenSource,
+    revision: Option<String>,
+    what: &str,
+) -> Result<(Option<FileData>, Vec<FileData>)> {
+    let mut loader = FileLoader::from_model_source(source, silent, token, revision)?;
+    let files = loader.list_files()?;
+    read_model_dir(&mut loader, &files, "", what)
+}
+
+/// Read the `config.json` if present and the safetensors weights directly in `dir` (empty or ending with `/`),
+/// out of the `files` of `loader`. `what` names the model in errors.
+fn read_model_dir(
+    loader: &mut FileLoader,
+    files: &[String],
+    dir: &str,
+    what: &str,
+) -> Result<(Option<FileData>, Vec<FileData>)> {
+    let weight_files = files
+        .iter()
+        .filter(|file| {
+            file.strip_prefix(dir)
+                .is_some_and(|name| !name.contains('/') && name.ends_with(".safetensors"))
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    if weight_files.is_empty() {
+        anyhow::bail!("No safetensors weights found for the {what}.");
+    }
+    let mut weights = Vec::new();
+    for file in weight_files {
+        weights.push(loader.read_file(&file, false)?);
+    }
+    let config_file = format!("{dir}config.json");
+    let config = if files.contains(&config_file) {
+        Some(loader.read_file(&config_file, false)?)
+    } else {
+        None
+    };
+    Ok((config, weights))
+}
+
+/// The components of a model, gathered by [`load_components`].
+struct LoadedComponents {
+    loader: Arc<dyn Loader>,
+    /// The contents of `model_index.json`, as read.
+    model_index: String,
+    index: ModelIndex,
+    components: HashMap<ComponentName, ComponentElem>,
+}
+
+/// Read `model_index.json` and gather the files of each component used by the model's loader. The files of
+/// each component are gathered according to the [`Loader::component_kind`] of the class it declares in
+/// `model_index.json`.
+///
+/// With `headers_only`, the weights are not downloaded: only the headers of safetensors files are read, and
+/// PyTorch checkpoints must already be in the cache.
+fn load_components(
+    source: &mut ModelSource,
+    silent: bool,
+    token: TokenSource,
+    revision: Option<String>,
+    headers_only: bool,
+) -> Result<LoadedComponents> {
+    let mut components = HashMap::new();
+    let mut loader = FileLoader::from_model_source(source, silent, token, revision)?;
+    let files = loader.list_files()?;
+    let transformer_files = loader.list_transformer_files()?;
+
+    if !files.contains(&"model_index.json".to_string()) {
+        anyhow::bail!("Expected `model_index.json` file present.");
+    }
+
+    let model_index = loader
+        .read_file_copied("model_index.json", false)?
+        .read_to_string_owned()?;
+    let index = ModelIndex::parse(&model_index)?;
+
+    let model_loader = registry::get_loader(&index.class_name)?;
+
+    info!("model architecture is: {}", model_loader.name());
+
+    let required = model_loader.required_component_names();
+    let optional = model_loader.optional_component_names();
+    for (name, class) in &index.components {
+        if !required.contains(name) && !optional.contains(name) {
+            match class {
+                Some(class) => info!(
+                    "skipping component `{name}` ({class}), it is not used by the {} pipeline",
+                    model_loader.name()
+                ),
+                None => info!("skipping null component `{name}`"),
+            }
+        }
+    }
+
+    let mut used = Vec::new();
+    for component in required {
+        let class = index.required_class(&component).with_context(|| {
+            format!(
+                "The {} pipeline requires the component `{component}`",
+                model_loader.name()
+            )
+        })?;
+        used.push((component, class.clone()));
+    }
+    for component in optional {
+        if let Some(class) = index.class(&component) {
+            used.push((component, class.clone()));
+        }
+    }
+
+    for (component, class) in NiceProgressBar::<_, 'g'>(used.into_iter(), "Loading components") {
+        let kind = model_loader.component_kind(&component, &class)?;
+        let (files, from_transformer, dir) =
+            if component == ComponentName::Transformer && transformer_files.is_some() {
+                (transformer_files.clone().unwrap(), true, "".to_string())
+            } else {
+                (files.clone(), false, format!("{component}/"))
+            };
+        let files_for_component = files
+            .iter()
+            .filter(|file| file.starts_with(&dir))
+            .filter(|file| !file.ends_with('/'))
+            .cloned()
+            .collect::<Vec<_>>();
+        if files_for_component.is_empty() {
+            anyhow::bail!("No files found in `{dir}` for component `{component}` ({class}).");
+        }
+
+        // 1) Model: models contain .safetensors (or PyTorch checkpoints) and a config.json
+        // 2) Config: only the .json files, such as the scheduler config
+        // 3) Other: all files, such as the tokenizer vocabulary
+        let component_elem = match kind {
+            ComponentKind::Model => {
+                let weight_files = if files_for_component
+                    .iter()
+                    .any(|file| file.ends_with(".safetensors"))
+                {
+                    files_for_component
+                        .iter()
+                        .filter(|file| file.ends_with(".safetensors"))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                } else {
+                    pickle_weight_files(&mut loader, &dir, &files_for_component, from_transformer)?
+                };
+                if weight_files.is_empty() {
+                    anyhow::bail!(
+                        "No weights found in `{dir}` for component `{component}` ({class})."
+                    );
+                }
+                let mut weights = HashMap::new();
+                for file in weight_files {
+                    let data = if !headers_only {
+                        loader.read_file(&file, from_transformer)?
+                    } else if file.ends_with(".safetensors") {
+                        loader.read_safetensors_header(&file, from_transformer)?
+                    } else {
+                        let Some(data) = loader.read_cached_file(&file, from_transformer)? else {
+                            anyhow::bail!(
+                                "`{file}` is a PyTorch checkpoint which is not in the cache, its tensors cannot be read without downloading it."
+                            );
+                        };
+                        data
+                    };
+                    weights.insert(file, data);
+                }
+                ComponentElem::Model {
+                    weights,
+                    config: loader.read_file(&format!("{dir}config.json"), from_transformer)?,
+                }
+            }
+            ComponentKind::Config => {
+                let mut files = HashMap::new();
+                for file in files_for_component
+                    .iter()
+                    .filter(|file| file.ends_with(".json"))
+                {
+                    files.insert(file.clone(), loader.read_file(file, from_transformer)?);
+                }
+                ComponentElem::Config { files }
+            }
+            ComponentKind::Other => {
+                let mut files = HashMap::new();
+                for file in files_for_component {
+                    files.insert(file.clone(), loader.read_file(&file, from_transformer)?);
+                }
+                ComponentElem::Other { files }
+            }
+        };
+        components.insert(component, component_elem);
+    }
+
+    Ok(LoadedComponents {
+        loader: model_loader,
+        model_index,
+        index,
+        components,
+    })
+}
+
+/// Verify the integrity of every file of a component, see [`verify_file`].
+fn verify_component(
+    name: &ComponentName,
+    component: &ComponentElem,
+    source: &ModelSource,
+) -> Result<()> {
+    let files = match component {
+        ComponentElem::Model { weights, config } => {
+            weights.values().chain([config]).collect::<Vec<_>>()
+        }
+        ComponentElem::Config { files } | ComponentElem::Other { files } => {
+            files.values().collect()
+        }
+    };
+    for file in files {
+        verify_file(file, source).with_context(|| {
+            let file_name = file.file_name().unwrap_or_default().to_string_lossy();
+            format!("file `{file_name}` of component `{name}` is corrupt")
+        })?;
+    }
+    Ok(())
 }
 
 /// Represents the model and provides methods to load and interact with it.
 pub struct Pipeline {
     model: Arc<Mutex<dyn ModelPipeline>>,
     offloading_type: Option<Offloading>,
+    model_index: String,
+    components: HashMap<ComponentName, ComponentElem>,
+    placement: ResolvedPlacement,
+    source: Arc<ModelSource>,
 }
 
 impl Pipeline {
@@ -117,132 +693,299 @@ impl Pipeline {
     ///
     /// Note:
     /// - `token` and `revision` are only applicable for Hugging Face models.
+    /// - `device` and `dtype` are the defaults, `placement` may override the device and dtype of each component.
+    /// - `verify` checks the integrity of every file before loading it: the CRC32 of DDUF entries, the
+    ///   safetensors headers, and the sha256 of files downloaded from the Hugging Face Hub. This reads all of the
+    ///   model files once more.
+    #[allow(clippy::too_many_arguments)]
     pub fn load(
         mut source: ModelSource,
         silent: bool,
         token: TokenSource,
         revision: Option<String>,
         offloading_type: Option<Offloading>,
+        device: DeviceSpec,
         dtype: &dyn TryIntoDType,
+        placement: &PlacementMap,
+        verify: bool,
     ) -> Result<Self> {
         info!("loading from source: {source}.");
 
-        let mut components = HashMap::new();
-        let model_loader = {
-            let mut loader = FileLoader::from_model_source(&mut source, silent, token, revision)?;
-            let files = loader.list_files()?;
-            let transformer_files = loader.list_transformer_files()?;
-
-            if !files.contains(&"model_index.json".to_string()) {
-                anyhow::bail!("Expected `model_index.json` file present.");
-            }
-
-            let ModelIndex { name } = serde_json::from_str(
-                &loader
-                    .read_file_copied("model_index.json", false)?
-                    .read_to_string_owned()?,
-            )?;
-
-            let model_loader: Box<dyn Loader> = match name.as_str() {
-                "FluxPipeline" => Box::new(FluxLoader),
-                other => anyhow::bail!("Unexpected loader type `{other:?}`."),
-            };
+        let device = device.into_device(silent)?;
 
-            info!("model architecture is: {}", model_loader.name());
+        let LoadedComponents {
+            loader: model_loader,
+            model_index,
+            index,
+            components,
+        } = load_components(&mut source, silent, token, revision, false)?;
 
-            for component in NiceProgressBar::<_, 'g'>(
-                model_loader.required_component_names().into_iter(),
-                "Loading components",
-            ) {
-                let (files, from_transformer, dir) =
-                    if component == ComponentName::Transformer && transformer_files.is_some() {
-                        (transformer_files.clone().unwrap(), true, "".to_string())
-                    } else {
-                        (files.clone(), false, format!("{component}/"))
-                    };
-                let files_for_component = files
-                    .iter()
-                    .filter(|file| file.starts_with(&dir))
-                    .filter(|file| !file.ends_with('/'))
-                    .cloned()
-                    .collect::<Vec<_>>();
-
-                // Try to determine the component's type.
-                // 1) Model: models contain .safetensors and potentially a config.json
-                // 2) Config: general config, a file ends with .json
-                // 3) Other: doesn't have safetensors and is not all json
-                let component_elem = if files_for_component
-                    .iter()
-                    .any(|file| file.ends_with(".safetensors"))
-                {
-                    let mut safetensors = HashMap::new();
-                    for file in files_for_component
-                        .iter()
-                        .filter(|file| file.ends_with(".safetensors"))
-                    {
-                        safetensors.insert(file.clone(), loader.read_file(file, from_transformer)?);
-                    }
-                    ComponentElem::Model {
-                        safetensors,
-                        config: loader.read_file(&format!("{dir}config.json"), from_transformer)?,
-                    }
-                } else if files_for_component
-                    .iter()
-                    .all(|file| file.ends_with(".json"))
-                {
-                    let mut files = HashMap::new();
-                    for file in files_for_component
-                        .iter()
-                        .filter(|file| file.ends_with(".json"))
-                    {
-                        files.insert(file.clone(), loader.read_file(file, from_transformer)?);
-                    }
-                    ComponentElem::Config { files }
-                } else {
-                    let mut files = HashMap::new();
-                    for file in files_for_component {
-                        files.insert(file.clone(), loader.read_file(&file, from_transformer)?);
-                    }
-                    ComponentElem::Other { files }
-                };
-                components.insert(component, component_elem);
+        if verify {
+            for (name, component) in
+                NiceProgressBar::<_, 'g'>(components.iter(), "Verifying components")
+            {
+                verify_component(name, component, &source)?;
             }
-
-            model_loader
-        };
-
-        #[cfg(not(feature = "metal"))]
-        let device = Device::cuda_if_available(0)?;
-        #[cfg(feature = "metal")]
-        let device = Device::new_metal(0)?;
+        }
 
         // NOTE: we can set the device to be just the primary even in the offloading case.
         // This will need to be updated!
-        let dtype = dtype.try_into_dtype(&[&device], silent)?;
+        let placement = placement.resolve(&device, dtype, silent)?;
 
+        let source = Arc::new(source);
         let model = model_loader.load_from_components(
-            components,
-            &device,
-            dtype,
+            &index,
+            components.clone(),
+            &placement,
             silent,
             offloading_type,
-            Arc::new(source),
+            source.clone(),
         )?;
 
         Ok(Self {
             model,
             offloading_type,
+            model_index,
+            components,
+            placement,
+            source,
         })
     }
 
-    /// Generate images based on prompts and generation parameters.
+    /// Load a ControlNet from a standalone diffusers model, with its `config.json` and safetensors weights at
+    /// the root, and add it to the pipeline. The ControlNet is placed like the transformer, and is conditioned
+    /// with [`DiffusionGenerationParams::controlnet_conditions`].
+    pub fn load_controlnet(
+        &self,
+        mut source: ModelSource,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+    ) -> Result<()> {
+        info!("loading ControlNet from source: {source}.");
+
+        let (config, weights) =
+            read_standalone_model(&mut source, silent, token, revision, "ControlNet")?;
+        let Some(config) = config else {
+            anyhow::bail!("Expected a `config.json` file for the ControlNet.");
+        };
+
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .add_controlnet(
+                config,
+                weights,
+                silent,
+                self.offloading_type,
+                Arc::new(source),
+            )
+    }
+
+    /// Load an XLabs IP-Adapter for FLUX from the safetensors weights at the root of `source`, and the CLIP
+    /// image encoder it was trained with from `image_encoder`, such as `openai/clip-vit-large-patch14`. The
+    /// image encoder is placed as the `image_encoder` component, and the adapter is prompted with
+    /// [`DiffusionGenerationParams::ip_adapter_images`]. `revision` is the revision of the adapter.
+    pub fn load_ip_adapter(
+        &self,
+        mut source: ModelSource,
+        mut image_encoder: ModelSource,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+    ) -> Result<()> {
+        info!("loading IP-Adapter from source: {source}, with the image encoder: {image_encoder}.");
+
+        let (_, weights) =
+            read_standalone_model(&mut source, silent, token.clone(), revision, "IP-Adapter")?;
+        let (image_encoder_config, image_encoder_weights) =
+            read_standalone_model(&mut image_encoder, silent, token, None, "image encoder")?;
+        let Some(image_encoder_config) = image_encoder_config else {
+            anyhow::bail!("Expected a `config.json` file for the image encoder.");
+        };
+
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .add_ip_adapter(
+                weights,
+                Arc::new(source),
+                image_encoder_config,
+                image_encoder_weights,
+                Arc::new(image_encoder),
+                silent,
+                self.offloading_type,
+            )
+    }
+
+    /// Load a FLUX.1 Redux prior, such as `black-forest-labs/FLUX.1-Redux-dev`, from the diffusers layout with
+    /// the SigLIP image encoder in `image_encoder/` and the Redux image embedder in `image_embedder/`. The image
+    /// encoder is placed as the `image_encoder` component, and the prior is prompted with
+    /// [`DiffusionGenerationParams::redux_image`].
+    pub fn load_redux(
+        &self,
+        mut source: ModelSource,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+    ) -> Result<()> {
+        info!("loading Redux from source: {source}.");
+
+        let mut loader = FileLoader::from_model_source(&mut source, silent, token, revision)?;
+        let files = loader.list_files()?;
+        let (image_encoder_config, image_encoder_weights) =
+            read_model_dir(&mut loader, &files, "image_encoder/", "Redux image encoder")?;
+        let Some(image_encoder_config) = image_encoder_config else {
+            anyhow::bail!("Expected an `image_encoder/config.json` file for Redux.");
+        };
+        let (image_embedder_config, image_embedder_weights) = read_model_dir(
+            &mut loader,
+            &files,
+            "image_embedder/",
+            "Redux image embedder",
+        )?;
+
+        self.model.lock().expect("Could not lock model!").add_redux(
+            image_encoder_config,
+            image_encoder_weights,
+            image_embedder_config,
+            image_embedder_weights,
+            Arc::new(source),
+            silent,
+        )
+    }
+
+    /// Estimate the memory usage of loading the model and generating images of the size given in `params`,
+    /// without loading any weights. This takes the same arguments as [`Pipeline::load`].
+    ///
+    /// Only the configs and the safetensors headers or PyTorch checkpoint metadata are read. The weights of
+    /// Hugging Face models are not downloaded: only the headers of their safetensors files are fetched, and
+    /// PyTorch checkpoints must already be in the cache.
+    #[allow(clippy::too_many_arguments)]
+    pub fn plan(
+        mut source: ModelSource,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+        offloading_type: Option<Offloading>,
+        device: DeviceSpec,
+        dtype: &dyn TryIntoDType,
+        placement: &PlacementMap,
+        params: PlanParams,
+    ) -> Result<LoadPlan> {
+        info!("planning from source: {source}.");
+
+        let device = device.into_device(silent)?;
+
+        let LoadedComponents {
+            loader: model_loader,
+            components,
+            ..
+        } = load_components(&mut source, silent, token, revision, true)?;
+
+        let placement = placement.resolve(&device, dtype, silent)?;
+
+        let components = model_loader.plan_components(
+            &components,
+            &placement,
+            offloading_type,
+            &params,
+            &source,
+        )?;
+
+        Ok(LoadPlan {
+            model: model_loader.name().to_string(),
+            params,
+            components,
+        })
+    }
+
+    /// Inspect a model without loading any weights: list its components and files, the entries of a DDUF
+    /// file, and the safetensors headers. This also checks the DDUF structure against the specification and
+    /// parses each config into the structs used to load it, reporting every failure instead of the first one.
+    ///
+    /// `token` and `revision` are only applicable for Hugging Face models, which are downloaded into the cache.
+    /// With `verify`, the integrity of every file is also checked as with [`Pipeline::load`].
+    pub fn inspect(
+        source: ModelSource,
+        silent: bool,
+        token: TokenSource,
+        revision: Option<String>,
+        verify: bool,
+    ) -> Result<InspectReport> {
+        inspect::inspect(source, silent, token, revision, verify)
+    }
+
+    /// Save the model as loaded, as a diffusers model directory or a DDUF file which [`Pipeline::load`] reads
+    /// back to the same weights.
+    ///
+    /// - Floating point weights are saved in the dtype of their component, see [`PlacementMap`].
+    /// - Quantized weights, their quantization state (such as the bitsandbytes `absmax` and `quant_map`), and
+    ///   the `quantization_config` of the component configs are saved as they were loaded.
+    /// - PyTorch checkpoints are converted to safetensors. All other files are copied unchanged.
+    ///
+    /// Safetensors weights are copied one tensor at a time, so saving does not need a second copy of the model
+    /// in memory.
+    pub fn save(&self, path: impl AsRef<Path>, format: SaveFormat) -> anyhow::Result<()> {
+        save::save_components(
+            path.as_ref(),
+            format,
+            &self.model_index,
+            &self.components,
+            &self.placement,
+            &self.source,
+        )
+    }
+
+    /// Encode prompts with the text encoders of the model, to generate images from them with
+    /// [`Pipeline::forward`] without encoding them again. `max_sequence_length` is the
+    /// [`DiffusionGenerationParams::max_sequence_length`] to encode them with.
+    pub fn encode_prompts(
+        &self,
+        prompts: Vec<String>,
+        max_sequence_length: Option<usize>,
+    ) -> anyhow::Result<PromptEmbeddings> {
+        let mut model = self.model.lock().expect("Could not lock model!");
+        #[cfg(feature = "metal")]
+        let embeddings = objc::rc::autoreleasepool(|| {
+            model.encode_prompts(prompts, max_sequence_length, self.offloading_type)
+        })?;
+        #[cfg(not(feature = "metal"))]
+        let embeddings =
+            model.encode_prompts(prompts, max_sequence_length, self.offloading_type)?;
+        Ok(embeddings)
+    }
+
+    /// Cache the embeddings of the prompts in memory, up to `max_bytes`, evicting the least recently used
+    /// prompts. Prompts are looked up by their token ids, so a prompt found in the cache is not encoded again,
+    /// and with [`Offloading::Full`] the T5 encoder is not moved to the device when all prompts are found.
+    /// `None` disables the cache and frees its memory. The cache is disabled by default.
+    pub fn set_prompt_cache(&self, max_bytes: Option<usize>) -> Result<()> {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .set_prompt_cache(max_bytes)
+    }
+
+    /// The hit and miss counters of the prompt-embedding cache, if it is enabled with
+    /// [`Pipeline::set_prompt_cache`].
+    pub fn prompt_cache_stats(&self) -> Option<PromptCacheStats> {
+        self.model
+            .lock()
+            .expect("Could not lock model!")
+            .prompt_cache_stats()
+    }
+
+    /// Generate images based on prompts, or their embeddings from [`Pipeline::encode_prompts`], and generation
+    /// parameters.
     ///
     /// If a multiple prompts are specified, they are padded and run together as a batch.
     pub fn forward(
         &self,
-        prompts: Vec<String>,
+        prompts: impl Into<Prompts>,
         params: DiffusionGenerationParams,
     ) -> anyhow::Result<Vec<DynamicImage>> {
+        let prompts = prompts.into();
         let mut model = self.model.lock().expect("Could not lock model!");
         #[cfg(feature = "metal")]
         let img =
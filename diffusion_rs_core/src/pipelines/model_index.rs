@@ -0,0 +1,188 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use anyhow::Result;
+use diffusion_rs_common::{FileData, ModelSource};
+use tokenizers::Tokenizer;
+
+use super::ComponentName;
+
+/// The `[library, class]` pair of a component in `model_index.json`, such as
+/// `["transformers", "CLIPTextModel"]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ComponentClass {
+    pub library: String,
+    pub class: String,
+}
+
+impl Display for ComponentClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.library, self.class)
+    }
+}
+
+impl ComponentClass {
+    /// Error for a component whose class is not supported by a pipeline.
+    pub fn unsupported(&self, name: &ComponentName, pipeline: &str) -> anyhow::Error {
+        anyhow::anyhow!(
+            "Unsupported class `{self}` for component `{name}` of the {pipeline} pipeline."
+        )
+    }
+}
+
+/// The contents of `model_index.json`.
+#[derive(Debug, Clone)]
+pub struct ModelIndex {
+    /// The pipeline class, which selects the [`Loader`](crate::Loader).
+    pub class_name: String,
+    pub diffusers_version: Option<String>,
+    /// Every component of the model. Optional components which are not shipped are `None`, they are written
+    /// as `[null, null]`.
+    pub components: BTreeMap<ComponentName, Option<ComponentClass>>,
+}
+
+impl ModelIndex {
+    /// Parse a `model_index.json` file. Keys which are not `[library, class]` pairs, such as
+    /// `requires_safety_checker`, are pipeline settings and are skipped.
+    pub fn parse(json: &str) -> Result<Self> {
+        let index: serde_json::Map<String, serde_json::Value> = serde_json::from_str(json)?;
+        let Some(class_name) = index.get("_class_name").and_then(|x| x.as_str()) else {
+            anyhow::bail!("Expected a `_class_name` string in `model_index.json`.");
+        };
+        let diffusers_version = index
+            .get("_diffusers_version")
+            .and_then(|x| x.as_str())
+            .map(ToString::to_string);
+
+        let mut components = BTreeMap::new();
+        for (key, value) in index.iter().filter(|(key, _)| !key.starts_with('_')) {
+            let Some(pair) = value.as_array() else {
+                continue;
+            };
+            let class = match pair.as_slice() {
+                [library, class] if library.is_null() && class.is_null() => None,
+                [library, class] => match (library.as_str(), class.as_str()) {
+                    (Some(library), Some(class)) => Some(ComponentClass {
+                        library: library.to_string(),
+                        class: class.to_string(),
+                    }),
+                    _ => anyhow::bail!(
+                        "Expected a `[library, class]` pair of strings for `{key}` in `model_index.json`, got `{value}`."
+                    ),
+                },
+                _ => anyhow::bail!(
+                    "Expected a `[library, class]` pair for `{key}` in `model_index.json`, got `{value}`."
+                ),
+            };
+            components.insert(ComponentName::from(key.as_str()), class);
+        }
+
+        Ok(Self {
+            class_name: class_name.to_string(),
+            diffusers_version,
+            components,
+        })
+    }
+
+    /// The class of a component, or `None` if it is missing or null.
+    pub fn class(&self, name: &ComponentName) -> Option<&ComponentClass> {
+        self.components.get(name).and_then(|class| class.as_ref())
+    }
+
+    /// The class of a component which the pipeline requires.
+    pub fn required_class(&self, name: &ComponentName) -> Result<&ComponentClass> {
+        match self.components.get(name) {
+            Some(Some(class)) => Ok(class),
+            Some(None) => anyhow::bail!("Component `{name}` is null in `model_index.json`."),
+            None => anyhow::bail!("Component `{name}` is missing from `model_index.json`."),
+        }
+    }
+}
+
+/// How the files of a component are gathered, see [`ComponentElem`](crate::ComponentElem) and
+/// [`Loader::component_kind`](crate::Loader::component_kind).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentKind {
+    /// The weights and the `config.json`, read into a [`ComponentElem::Model`](crate::ComponentElem::Model).
+    Model,
+    /// Only the JSON files, read into a [`ComponentElem::Config`](crate::ComponentElem::Config).
+    Config,
+    /// All of the files, read into a [`ComponentElem::Other`](crate::ComponentElem::Other).
+    Other,
+}
+
+const TOKENIZER_CLASSES: &[&str] = &[
+    "CLIPTokenizer",
+    "CLIPTokenizerFast",
+    "T5Tokenizer",
+    "T5TokenizerFast",
+];
+
+const MODEL_CLASSES: &[&str] = &[
+    "AutoencoderKL",
+    "ChromaTransformer2DModel",
+    "CLIPTextModel",
+    "CLIPTextModelWithProjection",
+    "FluxTransformer2DModel",
+    "T5EncoderModel",
+];
+
+const PROCESSOR_CLASSES: &[&str] = &["CLIPImageProcessor", "SiglipImageProcessor"];
+
+impl ComponentKind {
+    /// The kind of a component from its declared class, for the classes used by the built-in pipelines.
+    pub fn of(name: &ComponentName, class: &ComponentClass) -> Result<Self> {
+        let class_name = class.class.as_str();
+        if TOKENIZER_CLASSES.contains(&class_name) {
+            Ok(Self::Other)
+        } else if MODEL_CLASSES.contains(&class_name) {
+            Ok(Self::Model)
+        } else if PROCESSOR_CLASSES.contains(&class_name)
+            || (class.library == "diffusers" && class_name.ends_with("Scheduler"))
+        {
+            Ok(Self::Config)
+        } else {
+            anyhow::bail!("Unsupported class `{class}` for component `{name}`.")
+        }
+    }
+}
+
+/// Get the file `file` of the component `name`.
+pub(crate) fn component_file<'a>(
+    files: &'a HashMap<String, FileData>,
+    name: &ComponentName,
+    file: &str,
+) -> Result<&'a FileData> {
+    match files.get(&format!("{name}/{file}")) {
+        Some(data) => Ok(data),
+        None => anyhow::bail!("Missing `{name}/{file}`."),
+    }
+}
+
+/// Load a tokenizer of one of the [`TOKENIZER_CLASSES`] from its `tokenizer.json`. Slow CLIP tokenizers without
+/// one are built from `vocab.json` and `merges.txt`.
+pub(crate) fn load_tokenizer(
+    name: &ComponentName,
+    class: &ComponentClass,
+    files: &HashMap<String, FileData>,
+    source: &ModelSource,
+) -> Result<Tokenizer> {
+    match class.class.as_str() {
+        "CLIPTokenizer" if !files.contains_key(&format!("{name}/tokenizer.json")) => {
+            diffusion_rs_common::load_clip_tokenizer(
+                component_file(files, name, "vocab.json")?,
+                component_file(files, name, "merges.txt")?,
+                source,
+            )
+        }
+        "CLIPTokenizer" | "CLIPTokenizerFast" | "T5Tokenizer" | "T5TokenizerFast" => {
+            Tokenizer::from_bytes(
+                component_file(files, name, "tokenizer.json")?.read_to_string(source)?,
+            )
+            .map_err(anyhow::Error::msg)
+        }
+        _ => anyhow::bail!("Unsupported tokenizer class `{class}` for component `{name}`."),
+    }
+}
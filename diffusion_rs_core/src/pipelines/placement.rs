@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use diffusion_rs_common::core::{DType, Device};
+use tracing::info;
+
+use crate::{ModelDType, TryIntoDType};
+
+use super::ComponentName;
+
+/// Device and dtype of a single pipeline component.
+#[derive(Debug, Clone)]
+pub struct ComponentPlacement {
+    pub device: Device,
+    pub dtype: ModelDType,
+}
+
+/// Per-component device and dtype placement for [`Pipeline::load`](crate::Pipeline::load).
+///
+/// Components which are not present in the map are placed on the default device with the default dtype.
+/// For example, to run the CLIP text encoder and the VAE in F32 on the CPU:
+///
+/// ```rust,no_run
+/// use diffusion_rs_core::{ComponentName, ModelDType, PlacementMap};
+/// use diffusion_rs_common::core::Device;
+///
+/// let placement = PlacementMap::new()
+///     .with_component(ComponentName::TextEncoder(1), Device::Cpu, ModelDType::F32)
+///     .with_component(ComponentName::Vae, Device::Cpu, ModelDType::F32);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PlacementMap {
+    components: HashMap<ComponentName, ComponentPlacement>,
+}
+
+impl PlacementMap {
+    /// Create an empty placement map, placing every component on the default device.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Place `component` on `device` with `dtype`. `ModelDType::Auto` is resolved for `device` only.
+    pub fn with_component(
+        mut self,
+        component: ComponentName,
+        device: Device,
+        dtype: ModelDType,
+    ) -> Self {
+        self.components
+            .insert(component, ComponentPlacement { device, dtype });
+        self
+    }
+
+    pub fn get(&self, component: &ComponentName) -> Option<&ComponentPlacement> {
+        self.components.get(component)
+    }
+
+    /// Resolve the dtype of each component for the device it is placed on.
+    pub(crate) fn resolve(
+        &self,
+        default_device: &Device,
+        default_dtype: &dyn TryIntoDType,
+        silent: bool,
+    ) -> Result<ResolvedPlacement> {
+        let dtype = default_dtype.try_into_dtype(&[default_device], silent)?;
+
+        let mut components = HashMap::new();
+        for (name, placement) in &self.components {
+            let dtype = placement.dtype.try_into_dtype(&[&placement.device], true)?;
+            if !silent {
+                info!(
+                    "placing {name} on {:?} with dtype {dtype:?}.",
+                    placement.device.location()
+                );
+            }
+            components.insert(name.clone(), (placement.device.clone(), dtype));
+        }
+
+        Ok(ResolvedPlacement {
+            default: (default_device.clone(), dtype),
+            components,
+        })
+    }
+}
+
+/// A [`PlacementMap`] with every dtype resolved, passed to [`Loader`](crate::Loader)s.
+#[derive(Debug, Clone)]
+pub struct ResolvedPlacement {
+    default: (Device, DType),
+    components: HashMap<ComponentName, (Device, DType)>,
+}
+
+impl ResolvedPlacement {
+    /// Device of `component`, or the default device.
+    pub fn device(&self, component: &ComponentName) -> &Device {
+        &self.components.get(component).unwrap_or(&self.default).0
+    }
+
+    /// Dtype of `component`, or the default dtype.
+    pub fn dtype(&self, component: &ComponentName) -> DType {
+        self.components.get(component).unwrap_or(&self.default).1
+    }
+}
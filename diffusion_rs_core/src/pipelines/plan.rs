@@ -0,0 +1,314 @@
+use std::{collections::HashMap, fmt::Display};
+
+use anyhow::Result;
+use diffusion_rs_backend::QuantizedConfig;
+use diffusion_rs_common::{
+    core::DType, open_pth_checkpoint, read_safetensors_header, FileData, ModelSource, TensorHeader,
+};
+use serde::Deserialize;
+
+use crate::DeviceSpec;
+
+use super::ComponentName;
+
+/// Tensor name fragments of the bitsandbytes quantization state, which is loaded in its stored dtype.
+pub(crate) const QUANT_STATE_NAMES: &[&str] = &["absmax", "quant_map", "quant_state", "SCB"];
+
+/// Image and batch size to estimate the activation memory for.
+#[derive(Debug, Clone, Copy)]
+pub struct PlanParams {
+    pub height: usize,
+    pub width: usize,
+    pub batch_size: usize,
+}
+
+/// Where a component lives once the pipeline is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComponentResidence {
+    /// Resident on the device.
+    Device(DeviceSpec),
+    /// Kept in CPU memory and copied onto the device while it is used.
+    Offloaded(DeviceSpec),
+    /// Kept on disk, with at most `memory_budget` bytes of blocks materialized on the device.
+    Disk {
+        device: DeviceSpec,
+        memory_budget: usize,
+    },
+}
+
+impl ComponentResidence {
+    /// The device which runs the component.
+    pub fn device(&self) -> DeviceSpec {
+        match self {
+            Self::Device(device) | Self::Offloaded(device) | Self::Disk { device, .. } => *device,
+        }
+    }
+}
+
+impl Display for ComponentResidence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Device(device) => write!(f, "{device}"),
+            Self::Offloaded(device) => write!(f, "cpu, copied to {device} when used"),
+            Self::Disk {
+                device,
+                memory_budget,
+            } => write!(
+                f,
+                "disk, up to {} of blocks on {device}",
+                format_bytes(*memory_budget)
+            ),
+        }
+    }
+}
+
+/// Estimated memory usage of a single model component.
+#[derive(Debug, Clone)]
+pub struct ComponentPlan {
+    pub name: ComponentName,
+    pub num_parameters: usize,
+    /// Size of the weights once loaded, at `dtype` or at the quantized size.
+    pub size_in_bytes: usize,
+    pub dtype: DType,
+    /// Quantization read from the `quantization_config`, if any.
+    pub quantization: Option<String>,
+    pub residence: ComponentResidence,
+    /// Estimated peak activation memory of one forward pass of the component.
+    pub peak_activation_bytes: usize,
+}
+
+/// Result of a dry run of [`Pipeline::load`](crate::Pipeline::load), see [`Pipeline::plan`](crate::Pipeline::plan).
+///
+/// All sizes are estimates: activations are approximated from the model dimensions and do not account for
+/// allocator overhead or temporary buffers of specific kernels.
+#[derive(Debug, Clone)]
+pub struct LoadPlan {
+    pub model: String,
+    pub params: PlanParams,
+    pub components: Vec<ComponentPlan>,
+}
+
+impl LoadPlan {
+    /// Estimated peak memory usage of each device, in bytes.
+    ///
+    /// This is the memory of the resident weights, plus the largest of the activations of a component and the
+    /// weights copied onto the device while it runs. Offloaded weights count towards the CPU memory.
+    pub fn peak_memory(&self) -> Vec<(DeviceSpec, usize)> {
+        let mut resident: HashMap<DeviceSpec, usize> = HashMap::new();
+        let mut transient: HashMap<DeviceSpec, usize> = HashMap::new();
+        for component in &self.components {
+            let device = component.residence.device();
+            let (resident_bytes, copied_bytes) = match component.residence {
+                ComponentResidence::Device(_) => (component.size_in_bytes, 0),
+                ComponentResidence::Offloaded(_) => {
+                    *resident.entry(DeviceSpec::Cpu).or_default() += component.size_in_bytes;
+                    (0, component.size_in_bytes)
+                }
+                ComponentResidence::Disk { memory_budget, .. } => {
+                    (component.size_in_bytes.min(memory_budget), 0)
+                }
+            };
+            *resident.entry(device).or_default() += resident_bytes;
+            let peak = transient.entry(device).or_default();
+            *peak = (*peak).max(component.peak_activation_bytes + copied_bytes);
+        }
+
+        let mut peak_memory = resident
+            .into_iter()
+            .map(|(device, bytes)| (device, bytes + transient.get(&device).unwrap_or(&0)))
+            .collect::<Vec<_>>();
+        peak_memory.sort_by_key(|(device, _)| device.to_string());
+        peak_memory
+    }
+}
+
+impl Display for LoadPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Model: {}", self.model)?;
+        writeln!(
+            f,
+            "Estimates for a batch of {} at {}x{} (height x width).",
+            self.params.batch_size, self.params.height, self.params.width
+        )?;
+        writeln!(f)?;
+        writeln!(
+            f,
+            "{:<16} {:>10} {:>6} {:>13} {:>11} {:>11}  location",
+            "component", "parameters", "dtype", "quantization", "weights", "activations"
+        )?;
+        for component in &self.components {
+            writeln!(
+                f,
+                "{:<16} {:>10} {:>6} {:>13} {:>11} {:>11}  {}",
+                component.name.to_string(),
+                format_count(component.num_parameters),
+                component.dtype.as_str(),
+                component.quantization.as_deref().unwrap_or("-"),
+                format_bytes(component.size_in_bytes),
+                format_bytes(component.peak_activation_bytes),
+                component.residence,
+            )?;
+        }
+        writeln!(f)?;
+        writeln!(f, "Estimated peak memory:")?;
+        for (device, bytes) in self.peak_memory() {
+            writeln!(f, "  {device}: {}", format_bytes(bytes))?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn format_bytes(bytes: usize) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024. && unit < UNITS.len() - 1 {
+        value /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.2} {}", UNITS[unit])
+    }
+}
+
+fn format_count(count: usize) -> String {
+    match count {
+        0..1_000 => count.to_string(),
+        1_000..1_000_000 => format!("{:.2}K", count as f64 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.2}M", count as f64 / 1e6),
+        _ => format!("{:.2}B", count as f64 / 1e9),
+    }
+}
+
+#[derive(Deserialize)]
+struct QuantizationConfigShim {
+    quantization_config: Option<QuantizedConfig>,
+}
+
+/// Read the `quantization_config` of a component config, if any.
+pub(crate) fn read_quantization_config(
+    config: &FileData,
+    source: &ModelSource,
+) -> Result<Option<QuantizedConfig>> {
+    let QuantizationConfigShim {
+        quantization_config,
+    } = serde_json::from_str(&config.read_to_string(source)?)?;
+    Ok(quantization_config)
+}
+
+/// Short description of a quantization, such as `bnb nf4`.
+pub(crate) fn describe_quantization(cfg: &QuantizedConfig) -> String {
+    let bits = match (cfg.bits, &cfg.bnb_4bit_quant_type) {
+        (Some(bits), _) => format!("{bits} bits"),
+        (None, Some(quant_type)) => quant_type.clone(),
+        (None, None) => "int8".to_string(),
+    };
+    format!("{} {bits}", cfg.quant_method)
+}
+
+/// Parameter count and size of the weights of a model component, read from the safetensors headers or the
+/// PyTorch checkpoint metadata.
+pub(crate) struct ComponentWeights {
+    pub(crate) num_parameters: usize,
+    pub(crate) size_in_bytes: usize,
+    pub(crate) quantization: Option<String>,
+}
+
+impl ComponentWeights {
+    /// Floating point tensors are loaded at `dtype`, except for the quantization state. Quantized weights
+    /// keep their stored size, and 4-bit weights pack two parameters in each byte.
+    pub(crate) fn read(
+        weights: &HashMap<String, FileData>,
+        config: &FileData,
+        dtype: DType,
+        source: &ModelSource,
+    ) -> Result<Self> {
+        let quantization_config = read_quantization_config(config, source)?;
+        let quantization = quantization_config.as_ref().map(describe_quantization);
+        let params_per_quantized_elem = match &quantization_config {
+            Some(QuantizedConfig {
+                bits: Some(bits), ..
+            }) => {
+                if !matches!(bits, 1 | 2 | 4 | 8) {
+                    anyhow::bail!(
+                        "Unsupported `bits` of {bits} in the `quantization_config`, expected 1, 2, 4 or 8."
+                    );
+                }
+                8 / bits
+            }
+            Some(QuantizedConfig {
+                bnb_4bit_quant_type: Some(_),
+                ..
+            }) => 2,
+            _ => 1,
+        };
+
+        let mut num_parameters = 0;
+        let mut size_in_bytes = 0;
+        for file in weights.values() {
+            for (name, header) in read_tensor_headers(file, source)? {
+                let elem_count = header.elem_count();
+                let stored = header.dtype;
+                if QUANT_STATE_NAMES.iter().any(|x| name.contains(x)) {
+                    size_in_bytes += elem_count * stored.size_in_bytes();
+                } else if stored.is_float() {
+                    num_parameters += elem_count;
+                    size_in_bytes += elem_count * dtype.size_in_bytes();
+                } else {
+                    num_parameters += elem_count * params_per_quantized_elem;
+                    size_in_bytes += elem_count * stored.size_in_bytes();
+                }
+            }
+        }
+
+        Ok(Self {
+            num_parameters,
+            size_in_bytes,
+            quantization,
+        })
+    }
+}
+
+pub(crate) fn read_tensor_headers(
+    file: &FileData,
+    source: &ModelSource,
+) -> Result<HashMap<String, TensorHeader>> {
+    match file {
+        _ if file.extension().is_some_and(|ext| ext == "safetensors") => {
+            Ok(read_safetensors_header(file, source)?)
+        }
+        FileData::Path(path) => Ok(open_pth_checkpoint(path)?
+            .tensor_infos()
+            .iter()
+            .map(|(name, info)| {
+                (
+                    name.clone(),
+                    TensorHeader {
+                        dtype: info.dtype,
+                        shape: info.layout.dims().to_vec(),
+                    },
+                )
+            })
+            .collect()),
+        other => anyhow::bail!("Unexpected weights file {other:?}."),
+    }
+}
+
+/// Rough peak activation memory of one transformer layer: the residual stream, the normalized input, the
+/// attention projections and output, the feed-forward hidden states before and after the activation, and the
+/// attention scores and probabilities.
+pub(crate) fn transformer_activation_bytes(
+    batch_size: usize,
+    seq_len: usize,
+    hidden_size: usize,
+    intermediate_size: usize,
+    num_heads: usize,
+    dtype: DType,
+    attention_dtype: DType,
+) -> usize {
+    let hidden = batch_size * seq_len * (6 * hidden_size + 2 * intermediate_size);
+    let attention = 2 * batch_size * num_heads * seq_len * seq_len;
+    hidden * dtype.size_in_bytes() + attention * attention_dtype.size_in_bytes()
+}
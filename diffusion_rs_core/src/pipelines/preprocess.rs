@@ -0,0 +1,345 @@
+#![allow(clippy::cast_possible_truncation, clippy::cast_precision_loss)]
+
+use std::{fmt::Display, str::FromStr};
+
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+
+/// Thresholds of [`ControlPreprocessor::Canny`] when not specified, the usual ones of the Canny ControlNets.
+const DEFAULT_CANNY_THRESHOLDS: (f32, f32) = (100., 200.);
+const DEFAULT_SCRIBBLE_THRESHOLD: f32 = 0.25;
+const DEFAULT_BLUR_SIGMA: f32 = 3.;
+const DEFAULT_TILE_FACTOR: usize = 8;
+
+/// Standard deviation of the blur applied before computing soft edges.
+const SOFT_EDGE_SIGMA: f32 = 1.5;
+
+/// Image dimensions are aligned to the latent patches of the pipelines.
+const ALIGNMENT: usize = 16;
+
+/// Turns an image into a control image for ControlNets, FLUX.1 Canny, or FLUX.1 Depth.
+///
+/// Parses from `canny`, `canny:<low>:<high>`, `soft-edge`, `scribble`, `scribble:<threshold>`, `grayscale`,
+/// `blur`, `blur:<sigma>`, `tile`, or `tile:<factor>`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlPreprocessor {
+    /// Canny edge detection as in OpenCV. Edges with a gradient magnitude above `high_threshold` are kept,
+    /// along with the ones above `low_threshold` which are connected to them. The gradient magnitude is the
+    /// L1 norm of the Sobel derivatives on the `[0, 255]` scale.
+    Canny {
+        low_threshold: f32,
+        high_threshold: f32,
+    },
+    /// Soft edges, similar to the HED and PiDiNet edge maps: the normalized gradient magnitude of the
+    /// blurred image.
+    SoftEdge,
+    /// Thick binary strokes where the soft edges are above `threshold`, in `[0, 1]`.
+    Scribble { threshold: f32 },
+    /// The luma of the image.
+    Grayscale,
+    /// Gaussian blur with a standard deviation of `sigma` pixels.
+    Blur { sigma: f32 },
+    /// Downsample the image by `factor` and upsample it back, for tile and upscaling ControlNets.
+    Tile { factor: usize },
+}
+
+impl ControlPreprocessor {
+    /// Canny edge detection with the default thresholds of 100 and 200.
+    pub fn canny() -> Self {
+        Self::Canny {
+            low_threshold: DEFAULT_CANNY_THRESHOLDS.0,
+            high_threshold: DEFAULT_CANNY_THRESHOLDS.1,
+        }
+    }
+
+    /// Resize `image` to `width`x`height`, rounded up to multiples of 16 like the generated images, and
+    /// compute the RGB control image.
+    pub fn process(
+        &self,
+        image: &DynamicImage,
+        width: usize,
+        height: usize,
+    ) -> Result<DynamicImage> {
+        if width == 0 || height == 0 {
+            diffusion_rs_common::bail!(
+                "Control images must have a nonzero size, got {width}x{height}."
+            );
+        }
+        let width = width.div_ceil(ALIGNMENT) * ALIGNMENT;
+        let height = height.div_ceil(ALIGNMENT) * ALIGNMENT;
+        let image = image.resize_exact(width as u32, height as u32, FilterType::Lanczos3);
+
+        let control = match *self {
+            Self::Canny {
+                low_threshold,
+                high_threshold,
+            } => {
+                if low_threshold < 0. || low_threshold > high_threshold {
+                    diffusion_rs_common::bail!(
+                        "Canny thresholds must satisfy 0 <= low <= high, got {low_threshold} and {high_threshold}."
+                    );
+                }
+                canny(&image, low_threshold, high_threshold)?
+            }
+            Self::SoftEdge => soft_edge(&image)?,
+            Self::Scribble { threshold } => {
+                if !(0. ..=1.).contains(&threshold) {
+                    diffusion_rs_common::bail!(
+                        "The scribble threshold must be in [0, 1], got {threshold}."
+                    );
+                }
+                let edges = soft_edge(&image)?.ge(threshold)?.to_dtype(DType::F32)?;
+                dilate(&edges)?
+            }
+            Self::Grayscale => luma_tensor(&image)?,
+            Self::Blur { sigma } => {
+                if sigma < 0. {
+                    diffusion_rs_common::bail!("The blur sigma must be nonnegative, got {sigma}.");
+                }
+                gaussian_blur(&rgb_tensor(&image)?, sigma)?
+            }
+            Self::Tile { factor } => {
+                if factor == 0 {
+                    diffusion_rs_common::bail!("The tile factor must be nonzero.");
+                }
+                let small = image.resize_exact(
+                    (width / factor).max(1) as u32,
+                    (height / factor).max(1) as u32,
+                    FilterType::Triangle,
+                );
+                return Ok(DynamicImage::ImageRgb8(
+                    small
+                        .resize_exact(width as u32, height as u32, FilterType::CatmullRom)
+                        .to_rgb8(),
+                ));
+            }
+        };
+        tensor_to_image(&control)
+    }
+}
+
+impl FromStr for ControlPreprocessor {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parse = |value: &str, what: &str| {
+            value
+                .parse::<f32>()
+                .map_err(|e| format!("Invalid {what} `{value}`: {e}"))
+        };
+        let (name, args) = s.split_once(':').unwrap_or((s, ""));
+        match (name, args) {
+            ("canny", "") => Ok(Self::canny()),
+            ("canny", args) => {
+                let Some((low, high)) = args.split_once(':') else {
+                    return Err(format!(
+                        "Invalid Canny thresholds `{args}`, expected `<low>:<high>`"
+                    ));
+                };
+                Ok(Self::Canny {
+                    low_threshold: parse(low, "Canny threshold")?,
+                    high_threshold: parse(high, "Canny threshold")?,
+                })
+            }
+            ("soft-edge", "") => Ok(Self::SoftEdge),
+            ("scribble", "") => Ok(Self::Scribble {
+                threshold: DEFAULT_SCRIBBLE_THRESHOLD,
+            }),
+            ("scribble", threshold) => Ok(Self::Scribble {
+                threshold: parse(threshold, "scribble threshold")?,
+            }),
+            ("grayscale", "") => Ok(Self::Grayscale),
+            ("blur", "") => Ok(Self::Blur {
+                sigma: DEFAULT_BLUR_SIGMA,
+            }),
+            ("blur", sigma) => Ok(Self::Blur {
+                sigma: parse(sigma, "blur sigma")?,
+            }),
+            ("tile", "") => Ok(Self::Tile {
+                factor: DEFAULT_TILE_FACTOR,
+            }),
+            ("tile", factor) => Ok(Self::Tile {
+                factor: factor
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid tile factor `{factor}`: {e}"))?,
+            }),
+            _ => Err(format!(
+                "Invalid control preprocessor `{s}`, expected one of `canny`, `canny:<low>:<high>`, `soft-edge`, \
+                 `scribble`, `scribble:<threshold>`, `grayscale`, `blur`, `blur:<sigma>`, `tile`, `tile:<factor>`"
+            )),
+        }
+    }
+}
+
+impl Display for ControlPreprocessor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Canny {
+                low_threshold,
+                high_threshold,
+            } => write!(f, "canny:{low_threshold}:{high_threshold}"),
+            Self::SoftEdge => write!(f, "soft-edge"),
+            Self::Scribble { threshold } => write!(f, "scribble:{threshold}"),
+            Self::Grayscale => write!(f, "grayscale"),
+            Self::Blur { sigma } => write!(f, "blur:{sigma}"),
+            Self::Tile { factor } => write!(f, "tile:{factor}"),
+        }
+    }
+}
+
+/// The RGB channels of an image as a `(3, 1, height, width)` tensor in `[0, 1]`, so that the filters run on
+/// each channel separately.
+fn rgb_tensor(image: &DynamicImage) -> Result<Tensor> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    Tensor::from_vec(image.to_rgb8().into_raw(), (height, width, 3), &Device::Cpu)?
+        .permute((2, 0, 1))?
+        .unsqueeze(1)?
+        .to_dtype(DType::F32)?
+        .affine(1. / 255., 0.)
+}
+
+/// The luma of an image as a `(1, 1, height, width)` tensor in `[0, 1]`.
+fn luma_tensor(image: &DynamicImage) -> Result<Tensor> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    Tensor::from_vec(
+        image.to_luma8().into_raw(),
+        (1, 1, height, width),
+        &Device::Cpu,
+    )?
+    .to_dtype(DType::F32)?
+    .affine(1. / 255., 0.)
+}
+
+/// Convert a `(channels, 1, height, width)` tensor in `[0, 1]` with 1 or 3 channels to an RGB image.
+fn tensor_to_image(xs: &Tensor) -> Result<DynamicImage> {
+    let (channels, _, height, width) = xs.dims4()?;
+    let xs = xs.squeeze(1)?;
+    let xs = if channels == 1 {
+        xs.repeat((3, 1, 1))?
+    } else {
+        xs
+    };
+    let pixels = xs
+        .clamp(0f32, 1f32)?
+        .affine(255., 0.)?
+        .round()?
+        .to_dtype(DType::U8)?
+        .permute((1, 2, 0))?
+        .flatten_all()?
+        .to_vec1::<u8>()?;
+    let image = RgbImage::from_raw(width as u32, height as u32, pixels).ok_or_else(|| {
+        diffusion_rs_common::core::Error::Msg("Invalid control image size".into())
+    })?;
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+/// Correlate each channel of a `(channels, 1, height, width)` tensor with a `(kh, kw)` kernel, replicating the
+/// borders so that the size is unchanged.
+fn filter(xs: &Tensor, kernel: &Tensor) -> Result<Tensor> {
+    let (kh, kw) = kernel.dims2()?;
+    xs.pad_with_same(2, kh / 2, kh / 2)?
+        .pad_with_same(3, kw / 2, kw / 2)?
+        .conv2d(&kernel.reshape((1, 1, kh, kw))?, 0, 1, 1, 1)
+}
+
+fn gaussian_blur(xs: &Tensor, sigma: f32) -> Result<Tensor> {
+    if sigma == 0. {
+        return Ok(xs.clone());
+    }
+    let radius = (3. * sigma).ceil() as i64;
+    let weights: Vec<f32> = (-radius..=radius)
+        .map(|i| (-((i * i) as f32) / (2. * sigma * sigma)).exp())
+        .collect();
+    let total: f32 = weights.iter().sum();
+    let weights: Vec<f32> = weights.into_iter().map(|w| w / total).collect();
+    let len = weights.len();
+    let kernel = Tensor::from_vec(weights, (1, len), xs.device())?;
+    let xs = filter(xs, &kernel)?;
+    filter(&xs, &kernel.t()?)
+}
+
+/// The horizontal and vertical Sobel derivatives.
+fn sobel(xs: &Tensor) -> Result<(Tensor, Tensor)> {
+    let kernel = Tensor::from_vec(
+        vec![-1f32, 0., 1., -2., 0., 2., -1., 0., 1.],
+        (3, 3),
+        xs.device(),
+    )?;
+    Ok((filter(xs, &kernel)?, filter(xs, &kernel.t()?)?))
+}
+
+/// The normalized gradient magnitude of the blurred image, taking the strongest channel, as a
+/// `(1, 1, height, width)` tensor in `[0, 1]`.
+fn soft_edge(image: &DynamicImage) -> Result<Tensor> {
+    let xs = gaussian_blur(&rgb_tensor(image)?, SOFT_EDGE_SIGMA)?;
+    let (gx, gy) = sobel(&xs)?;
+    let magnitude = (gx.sqr()? + gy.sqr()?)?.sqrt()?.max_keepdim(0)?;
+    let max = magnitude.flatten_all()?.max(0)?.to_scalar::<f32>()?;
+    if max == 0. {
+        return Ok(magnitude);
+    }
+    gaussian_blur(&magnitude.affine(1. / f64::from(max), 0.)?, 1.)
+}
+
+/// Thicken the strokes of a binary `(1, 1, height, width)` tensor by a pixel in each direction.
+fn dilate(xs: &Tensor) -> Result<Tensor> {
+    xs.pad_with_zeros(2, 1, 1)?
+        .pad_with_zeros(3, 1, 1)?
+        .max_pool2d_with_stride(3, 1)
+}
+
+fn canny(image: &DynamicImage, low_threshold: f32, high_threshold: f32) -> Result<Tensor> {
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let (gx, gy) = sobel(&luma_tensor(image)?.affine(255., 0.)?)?;
+    let magnitude = (gx.abs()? + gy.abs()?)?.flatten_all()?.to_vec1::<f32>()?;
+    let gx = gx.flatten_all()?.to_vec1::<f32>()?;
+    let gy = gy.flatten_all()?.to_vec1::<f32>()?;
+
+    // Non-maximum suppression along the gradient direction, quantized to 0, 45, 90 or 135 degrees.
+    let tan_22_5 = std::f32::consts::FRAC_PI_8.tan();
+    let mut suppressed = vec![0f32; width * height];
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let i = y * width + x;
+            let m = magnitude[i];
+            if m <= low_threshold {
+                continue;
+            }
+            let (ax, ay) = (gx[i].abs(), gy[i].abs());
+            let (a, b) = if ay <= ax * tan_22_5 {
+                (i - 1, i + 1)
+            } else if ax <= ay * tan_22_5 {
+                (i - width, i + width)
+            } else if (gx[i] > 0.) == (gy[i] > 0.) {
+                (i - width - 1, i + width + 1)
+            } else {
+                (i - width + 1, i + width - 1)
+            };
+            if m > magnitude[a] && m >= magnitude[b] {
+                suppressed[i] = m;
+            }
+        }
+    }
+
+    // Hysteresis: grow the strong edges into the connected weak edges.
+    let mut edges = vec![0f32; width * height];
+    let mut stack: Vec<usize> = (0..width * height)
+        .filter(|&i| suppressed[i] > high_threshold)
+        .collect();
+    for &i in &stack {
+        edges[i] = 1.;
+    }
+    while let Some(i) = stack.pop() {
+        let (y, x) = (i / width, i % width);
+        for ny in y.saturating_sub(1)..=(y + 1).min(height - 1) {
+            for nx in x.saturating_sub(1)..=(x + 1).min(width - 1) {
+                let j = ny * width + nx;
+                if edges[j] == 0. && suppressed[j] > low_threshold {
+                    edges[j] = 1.;
+                    stack.push(j);
+                }
+            }
+        }
+    }
+    Tensor::from_vec(edges, (1, 1, height, width), &Device::Cpu)
+}
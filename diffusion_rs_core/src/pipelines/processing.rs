@@ -0,0 +1,41 @@
+#![allow(clippy::cast_possible_truncation)]
+
+use diffusion_rs_common::core::{DType, Device, Result, Tensor};
+use image::{imageops::FilterType, DynamicImage};
+
+/// Resize an image to `width`x`height` and convert it to a `(1, 3, height, width)` tensor in `[-1, 1]`, as
+/// expected by the VAE encoder.
+pub(crate) fn image_to_tensor(
+    image: &DynamicImage,
+    width: usize,
+    height: usize,
+    device: &Device,
+    dtype: DType,
+) -> Result<Tensor> {
+    let image = image
+        .resize_exact(width as u32, height as u32, FilterType::Lanczos3)
+        .to_rgb8();
+    Tensor::from_vec(image.into_raw(), (height, width, 3), device)?
+        .permute((2, 0, 1))?
+        .unsqueeze(0)?
+        .to_dtype(DType::F32)?
+        .affine(2. / 255., -1.)?
+        .to_dtype(dtype)
+}
+
+/// Resize a mask to `width`x`height` and convert it to a `(1, 1, height, width)` tensor which is `1` where
+/// the mask is white, and `0` elsewhere.
+pub(crate) fn mask_to_tensor(
+    mask: &DynamicImage,
+    width: usize,
+    height: usize,
+    device: &Device,
+    dtype: DType,
+) -> Result<Tensor> {
+    let mask = mask
+        .resize_exact(width as u32, height as u32, FilterType::Lanczos3)
+        .to_luma8();
+    Tensor::from_vec(mask.into_raw(), (1, 1, height, width), device)?
+        .ge(128u8)?
+        .to_dtype(dtype)
+}
@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use diffusion_rs_common::core::{Device, Tensor};
+
+use super::PromptEmbeddings;
+
+/// Counters of a prompt-embedding cache, see [`Pipeline::set_prompt_cache`].
+///
+/// [`Pipeline::set_prompt_cache`]: crate::Pipeline::set_prompt_cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PromptCacheStats {
+    /// Number of prompts whose embeddings were found in the cache.
+    pub hits: u64,
+    /// Number of prompts which were encoded.
+    pub misses: u64,
+    /// Number of cached prompts.
+    pub entries: usize,
+    /// Size of the cached embeddings in bytes.
+    pub bytes: usize,
+    /// Maximum size of the cached embeddings in bytes.
+    pub max_bytes: usize,
+}
+
+/// The token ids a prompt was encoded from, which determine its embeddings. The T5 ids are padded to the
+/// `max_sequence_length` they were encoded with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) struct PromptCacheKey {
+    pub(crate) t5_ids: Vec<u32>,
+    pub(crate) clip_ids: Vec<u32>,
+}
+
+struct PromptCacheEntry {
+    embeddings: PromptEmbeddings,
+    bytes: usize,
+    last_used: u64,
+}
+
+/// In-memory cache of the embeddings of single prompts, which evicts the least recently used prompts to stay
+/// within `max_bytes`. The embeddings are kept in CPU memory so that the cache does not compete with the
+/// models for device memory.
+pub(crate) struct PromptCache {
+    entries: HashMap<PromptCacheKey, PromptCacheEntry>,
+    max_bytes: usize,
+    bytes: usize,
+    /// Incremented on each access, to order the entries by their last use.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Copy a tensor into its own CPU buffer. The embeddings of a prompt are views of the batch they were encoded
+/// in, which would otherwise keep the whole batch alive.
+fn owned_cpu_copy(tensor: &Tensor) -> diffusion_rs_common::core::Result<Tensor> {
+    tensor.force_contiguous()?.to_device(&Device::Cpu)
+}
+
+fn tensor_bytes(tensor: &Tensor) -> usize {
+    tensor.elem_count() * tensor.dtype().size_in_bytes()
+}
+
+impl PromptCache {
+    pub(crate) fn new(max_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            max_bytes,
+            bytes: 0,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Change the byte budget, evicting prompts if it shrinks. The counters are kept.
+    pub(crate) fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict(0);
+    }
+
+    pub(crate) fn stats(&self) -> PromptCacheStats {
+        PromptCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+            bytes: self.bytes,
+            max_bytes: self.max_bytes,
+        }
+    }
+
+    /// Look up the embeddings of a prompt, counting a hit or a miss.
+    pub(crate) fn get(&mut self, key: &PromptCacheKey) -> Option<PromptEmbeddings> {
+        self.clock += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                entry.last_used = self.clock;
+                self.hits += 1;
+                Some(entry.embeddings.clone())
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Cache the embeddings of a single prompt. Embeddings larger than the whole budget are not cached.
+    pub(crate) fn insert(
+        &mut self,
+        key: PromptCacheKey,
+        embeddings: &PromptEmbeddings,
+    ) -> diffusion_rs_common::core::Result<()> {
+        let embeddings = PromptEmbeddings {
+            t5: owned_cpu_copy(&embeddings.t5)?,
+            t5_mask: owned_cpu_copy(&embeddings.t5_mask)?,
+            clip_pooled: embeddings
+                .clip_pooled
+                .as_ref()
+                .map(owned_cpu_copy)
+                .transpose()?,
+        };
+        let bytes = tensor_bytes(&embeddings.t5)
+            + tensor_bytes(&embeddings.t5_mask)
+            + embeddings.clip_pooled.as_ref().map_or(0, tensor_bytes);
+        if bytes > self.max_bytes {
+            return Ok(());
+        }
+        if let Some(previous) = self.entries.remove(&key) {
+            self.bytes -= previous.bytes;
+        }
+        self.evict(bytes);
+        self.clock += 1;
+        self.bytes += bytes;
+        self.entries.insert(
+            key,
+            PromptCacheEntry {
+                embeddings,
+                bytes,
+                last_used: self.clock,
+            },
+        );
+        Ok(())
+    }
+
+    /// Evict the least recently used prompts until `bytes` more fit in the budget.
+    fn evict(&mut self, bytes: usize) {
+        while self.bytes + bytes > self.max_bytes {
+            let Some(key) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = self.entries.remove(&key).unwrap();
+            self.bytes -= entry.bytes;
+        }
+    }
+}
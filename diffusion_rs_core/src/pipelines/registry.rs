@@ -0,0 +1,85 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use anyhow::Result;
+use once_cell::sync::Lazy;
+
+use super::{
+    chroma::ChromaLoader,
+    flux::{FluxLoader, FluxVariant},
+    Loader,
+};
+
+/// Loaders keyed by the `_class_name` of `model_index.json`.
+static LOADERS: Lazy<RwLock<HashMap<String, Arc<dyn Loader>>>> = Lazy::new(|| {
+    let mut loaders: HashMap<String, Arc<dyn Loader>> = HashMap::new();
+    loaders.insert("ChromaPipeline".to_string(), Arc::new(ChromaLoader));
+    loaders.insert(
+        "FluxPipeline".to_string(),
+        Arc::new(FluxLoader {
+            variant: FluxVariant::Text,
+        }),
+    );
+    loaders.insert(
+        "FluxFillPipeline".to_string(),
+        Arc::new(FluxLoader {
+            variant: FluxVariant::Fill,
+        }),
+    );
+    loaders.insert(
+        "FluxControlPipeline".to_string(),
+        Arc::new(FluxLoader {
+            variant: FluxVariant::Control,
+        }),
+    );
+    loaders.insert(
+        "FluxKontextPipeline".to_string(),
+        Arc::new(FluxLoader {
+            variant: FluxVariant::Kontext,
+        }),
+    );
+    RwLock::new(loaders)
+});
+
+/// Register `loader` for the models whose `model_index.json` has `class_name` as its `_class_name`, so that
+/// [`Pipeline::load`](crate::Pipeline::load) can load them. This returns the loader previously registered
+/// for `class_name`, if any, which allows replacing the built-in loaders.
+pub fn register_loader(
+    class_name: impl ToString,
+    loader: impl Loader + 'static,
+) -> Option<Arc<dyn Loader>> {
+    LOADERS
+        .write()
+        .expect("Could not lock the loader registry!")
+        .insert(class_name.to_string(), Arc::new(loader))
+}
+
+/// The `_class_name`s which have a registered loader, sorted.
+pub fn registered_loaders() -> Vec<String> {
+    let mut names = LOADERS
+        .read()
+        .expect("Could not lock the loader registry!")
+        .keys()
+        .cloned()
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+pub(crate) fn get_loader(class_name: &str) -> Result<Arc<dyn Loader>> {
+    // The lock is released before listing the loaders, which locks the registry again.
+    let loader = LOADERS
+        .read()
+        .expect("Could not lock the loader registry!")
+        .get(class_name)
+        .cloned();
+    match loader {
+        Some(loader) => Ok(loader),
+        None => anyhow::bail!(
+            "Unexpected loader type `{class_name:?}`, expected one of {:?}.",
+            registered_loaders()
+        ),
+    }
+}
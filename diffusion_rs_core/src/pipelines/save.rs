@@ -0,0 +1,179 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use diffusion_rs_common::{
+    core::{DType, Device},
+    from_mmaped_safetensors, from_mmaped_safetensors_lazy, DdufWriter, FileData, ModelSource,
+    NiceProgressBar, SafetensorsLayout, TensorHeader,
+};
+
+use super::{
+    plan::{read_tensor_headers, QUANT_STATE_NAMES},
+    ComponentElem, ComponentName, ResolvedPlacement,
+};
+
+/// Output layout of [`Pipeline::save`](crate::Pipeline::save).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    /// A diffusers model directory, with `model_index.json` and a directory for each component.
+    Directory,
+    /// A single DDUF file.
+    Dduf,
+}
+
+enum SaveTarget {
+    Directory(PathBuf),
+    Dduf(Box<DdufWriter>),
+}
+
+impl SaveTarget {
+    fn write_entry(
+        &mut self,
+        name: &str,
+        size: usize,
+        write: impl FnOnce(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            Self::Directory(dir) => {
+                let path = dir.join(name);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                let mut file = BufWriter::new(File::create(path)?);
+                write(&mut file)?;
+                file.flush()?;
+            }
+            Self::Dduf(writer) => write(writer.start_entry(name, size as u64)?)?,
+        }
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        self.write_entry(name, bytes.len(), |w| Ok(w.write_all(bytes)?))
+    }
+}
+
+/// Weights are saved in the dtype they are loaded with: floating point tensors are cast to the dtype of the
+/// component, while quantized tensors and the quantization state keep their stored dtype.
+fn saved_dtype(name: &str, stored: DType, dtype: DType) -> DType {
+    if QUANT_STATE_NAMES.iter().any(|x| name.contains(x)) || !stored.is_float() {
+        stored
+    } else {
+        dtype
+    }
+}
+
+/// Write the weights of `file` as a safetensors file named `name`.
+fn save_weights(
+    target: &mut SaveTarget,
+    name: &str,
+    file: &FileData,
+    dtype: DType,
+    source: &Arc<ModelSource>,
+) -> Result<()> {
+    let mut tensors = read_tensor_headers(file, source)?
+        .into_iter()
+        .map(|(name, header)| {
+            let dtype = saved_dtype(&name, header.dtype, dtype);
+            (
+                name,
+                TensorHeader {
+                    dtype,
+                    shape: header.shape,
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+    tensors.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let layout = SafetensorsLayout::new(tensors)?;
+
+    let vb = if file.extension().is_some_and(|ext| ext == "safetensors") {
+        from_mmaped_safetensors_lazy(vec![file.clone()], None, &Device::Cpu, source.clone())?
+    } else {
+        from_mmaped_safetensors(vec![file.clone()], None, &Device::Cpu, true, source.clone())?
+    };
+    target.write_entry(name, layout.size_in_bytes(), |w| {
+        Ok(layout.write(w, |name, header| vb.get_unchecked_dtype(name, header.dtype))?)
+    })
+}
+
+/// Save the components as a diffusers model, see [`Pipeline::save`](crate::Pipeline::save).
+pub(crate) fn save_components(
+    path: &Path,
+    format: SaveFormat,
+    model_index: &str,
+    components: &HashMap<ComponentName, ComponentElem>,
+    placement: &ResolvedPlacement,
+    source: &Arc<ModelSource>,
+) -> Result<()> {
+    // Only keep the components which are saved, and the optional components which are not used.
+    let mut model_index: serde_json::Map<String, serde_json::Value> =
+        serde_json::from_str(model_index)?;
+    model_index.retain(|key, value| {
+        key.starts_with('_')
+            || components.keys().any(|name| &name.to_string() == key)
+            || match value {
+                serde_json::Value::Array(class) => class.iter().all(|x| x.is_null()),
+                other => other.is_null(),
+            }
+    });
+
+    let mut target = match format {
+        SaveFormat::Directory => {
+            fs::create_dir_all(path)?;
+            SaveTarget::Directory(path.to_path_buf())
+        }
+        SaveFormat::Dduf => SaveTarget::Dduf(Box::new(DdufWriter::create(path)?)),
+    };
+    target.write_bytes(
+        "model_index.json",
+        &serde_json::to_vec_pretty(&model_index)?,
+    )?;
+
+    let mut components = components.iter().collect::<Vec<_>>();
+    components.sort_by_key(|(name, _)| *name);
+    for (name, elem) in NiceProgressBar::<_, 'g'>(components.into_iter(), "Saving components") {
+        let entry_name = |file: &FileData| -> Result<String> {
+            let Some(file_name) = file.file_name() else {
+                anyhow::bail!("Expected a file name for {file:?}.");
+            };
+            Ok(format!("{name}/{}", file_name.to_string_lossy()))
+        };
+        match elem {
+            ComponentElem::Model { weights, config } => {
+                target.write_bytes(
+                    &format!("{name}/config.json"),
+                    &config.read_to_bytes(source)?,
+                )?;
+                let mut weights = weights.values().collect::<Vec<_>>();
+                weights.sort_by_key(|file| file.file_name().map(|x| x.to_os_string()));
+                for file in weights {
+                    // PyTorch checkpoints are converted to safetensors, keeping the sharding.
+                    let entry = Path::new(&entry_name(file)?)
+                        .with_extension("safetensors")
+                        .to_string_lossy()
+                        .to_string();
+                    save_weights(&mut target, &entry, file, placement.dtype(name), source)?;
+                }
+            }
+            ComponentElem::Config { files } | ComponentElem::Other { files } => {
+                let mut files = files.values().collect::<Vec<_>>();
+                files.sort_by_key(|file| file.file_name().map(|x| x.to_os_string()));
+                for file in files {
+                    target.write_bytes(&entry_name(file)?, &file.read_to_bytes(source)?)?;
+                }
+            }
+        }
+    }
+
+    if let SaveTarget::Dduf(writer) = target {
+        writer.finish()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::Result;
+use diffusion_rs_common::core::{
+    utils::{cuda_is_available, metal_is_available},
+    Device, DeviceLocation,
+};
+use tracing::info;
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Hash)]
+/// Device to load the model on.
+///
+/// When parsing from a string, this accepts `auto`, `cpu`, `cuda`, `cuda:<ordinal>`, `metal`, or `metal:<ordinal>`.
+///
+/// Note: When using `Auto`, fallback pattern is: Metal 0 (`metal` feature) -> CUDA 0 (`cuda` feature) -> CPU
+pub enum DeviceSpec {
+    #[default]
+    Auto,
+    Cpu,
+    Cuda(usize),
+    Metal(usize),
+}
+
+impl FromStr for DeviceSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, ordinal) = match s.split_once(':') {
+            Some((kind, ordinal)) => {
+                let ordinal = ordinal
+                    .parse::<usize>()
+                    .map_err(|e| format!("Invalid device ordinal `{ordinal}`: {e}"))?;
+                (kind, Some(ordinal))
+            }
+            None => (s, None),
+        };
+        match (kind, ordinal) {
+            ("auto", None) => Ok(Self::Auto),
+            ("cpu", None) => Ok(Self::Cpu),
+            ("cuda", ordinal) => Ok(Self::Cuda(ordinal.unwrap_or(0))),
+            ("metal", ordinal) => Ok(Self::Metal(ordinal.unwrap_or(0))),
+            _ => Err(format!(
+                "Invalid device `{s}`, expected one of `auto`, `cpu`, `cuda:<ordinal>`, `metal:<ordinal>`"
+            )),
+        }
+    }
+}
+
+impl Display for DeviceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Auto => write!(f, "auto"),
+            Self::Cpu => write!(f, "cpu"),
+            Self::Cuda(ordinal) => write!(f, "cuda:{ordinal}"),
+            Self::Metal(ordinal) => write!(f, "metal:{ordinal}"),
+        }
+    }
+}
+
+impl From<&Device> for DeviceSpec {
+    fn from(device: &Device) -> Self {
+        match device.location() {
+            DeviceLocation::Cpu => Self::Cpu,
+            DeviceLocation::Cuda { gpu_id } => Self::Cuda(gpu_id),
+            DeviceLocation::Metal { gpu_id } => Self::Metal(gpu_id),
+        }
+    }
+}
+
+impl DeviceSpec {
+    /// Create the device, resolving `Auto` to the best available device.
+    pub fn into_device(self, silent: bool) -> Result<Device> {
+        let (spec, reason) = match self {
+            Self::Auto if metal_is_available() => (
+                Self::Metal(0),
+                "automatic selection, built with Metal support",
+            ),
+            Self::Auto if cuda_is_available() => (
+                Self::Cuda(0),
+                "automatic selection, built with CUDA support",
+            ),
+            Self::Auto => (
+                Self::Cpu,
+                "automatic selection, no CUDA or Metal support in this build",
+            ),
+            other => (other, "explicitly requested"),
+        };
+
+        let device = match spec {
+            Self::Auto | Self::Cpu => Device::Cpu,
+            Self::Cuda(ordinal) => Device::new_cuda(ordinal)?,
+            Self::Metal(ordinal) => Device::new_metal(ordinal)?,
+        };
+        if !silent {
+            info!("device selected is {spec} ({reason}).");
+        }
+        Ok(device)
+    }
+}
@@ -2,7 +2,8 @@ use std::time::Instant;
 
 use clap::Parser;
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    DeviceSpec, DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline,
+    PlacementMap, TokenSource,
 };
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::EnvFilter;
@@ -44,7 +45,10 @@ fn main() -> anyhow::Result<()> {
         TokenSource::CacheToken,
         None,
         args.offloading,
+        DeviceSpec::Auto,
         &ModelDType::Auto,
+        &PlacementMap::new(),
+        false,
     )?;
 
     let start = Instant::now();
@@ -56,6 +60,15 @@ fn main() -> anyhow::Result<()> {
             width: 1280,
             num_steps: args.num_steps,
             guidance_scale: args.guidance_scale,
+            negative_prompt: None,
+            reference_images: Vec::new(),
+            image: None,
+            mask_image: None,
+            control_image: None,
+            controlnet_conditions: Vec::new(),
+            ip_adapter_images: Vec::new(),
+            redux_image: None,
+            max_sequence_length: None,
         },
     )?;
 
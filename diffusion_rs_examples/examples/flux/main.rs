@@ -1,7 +1,8 @@
 use std::time::Instant;
 
 use diffusion_rs_core::{
-    DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline, TokenSource,
+    DeviceSpec, DiffusionGenerationParams, ModelDType, ModelSource, Offloading, Pipeline,
+    PlacementMap, TokenSource,
 };
 
 use clap::{Parser, ValueEnum};
@@ -50,7 +51,10 @@ fn main() -> anyhow::Result<()> {
         TokenSource::CacheToken,
         None,
         args.offloading,
+        DeviceSpec::Auto,
         &ModelDType::Auto,
+        &PlacementMap::new(),
+        false,
     )?;
     let num_steps = match args.which {
         Which::Dev => 50,
@@ -70,6 +74,15 @@ fn main() -> anyhow::Result<()> {
             width: 1280,
             num_steps,
             guidance_scale,
+            negative_prompt: None,
+            reference_images: Vec::new(),
+            image: None,
+            mask_image: None,
+            control_image: None,
+            controlnet_conditions: Vec::new(),
+            ip_adapter_images: Vec::new(),
+            redux_image: None,
+            max_sequence_length: None,
         },
     )?;
 
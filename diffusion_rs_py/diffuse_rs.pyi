@@ -1,4 +1,4 @@
-from dataclasses import dataclass
+from dataclasses import dataclass, field
 from enum import Enum
 
 @dataclass
@@ -17,9 +17,13 @@ class ModelDType(Enum):
 class Offloading(Enum):
     """
     Offloading settings for the model.
+
+    - `Full`: offload the largest components of the model to CPU memory and copy them into VRAM as necessary.
+    - `Disk`: keep the transformer blocks memory-mapped on disk and load them one at a time during denoising.
     """
 
     Full = 0
+    Disk = 1
 
 @dataclass
 class ModelSource(Enum):
@@ -34,6 +38,62 @@ class ModelSource(Enum):
     class DdufFile:
         file: str
 
+@dataclass
+class ControlNetCondition:
+    """
+    Conditioning of a ControlNet loaded with `Pipeline.load_controlnet`.
+
+    - `image`: the encoded control image, such as a Canny edge map or a depth map.
+    - `scale`: scale of the residuals of the ControlNet.
+    - `start`, `end`: fractions of the denoising steps between which the ControlNet is applied.
+    - `mode`: control mode of a Union ControlNet.
+    """
+
+    image: bytes
+    scale: float = 1.0
+    start: float = 0.0
+    end: float = 1.0
+    mode: int | None = None
+
+@dataclass
+class IpAdapterImage:
+    """
+    Image prompt of an IP-Adapter loaded with `Pipeline.load_ip_adapter`, such as a style reference.
+
+    - `image`: the encoded image.
+    - `scale`: scale of the attention to the image tokens.
+    """
+
+    image: bytes
+    scale: float = 1.0
+
+@dataclass
+class ReduxImage:
+    """
+    Image prompt of a FLUX.1 Redux prior loaded with `Pipeline.load_redux`, to generate variations of.
+
+    - `image`: the encoded image.
+    - `strength`: scale of the image tokens appended to the text embeddings.
+    """
+
+    image: bytes
+    strength: float = 1.0
+
+def preprocess_control_image(
+    image: bytes,
+    preprocessor: str,
+    width: int,
+    height: int,
+) -> bytes:
+    """
+    Compute a control image for ControlNets, FLUX.1 Canny or FLUX.1 Depth, resized to `width`x`height`
+    rounded up to multiples of 16. The encoded PNG image is returned.
+
+    `preprocessor` is one of `canny`, `canny:<low>:<high>`, `soft-edge`, `scribble`, `scribble:<threshold>`,
+    `grayscale`, `blur`, `blur:<sigma>`, `tile`, or `tile:<factor>`.
+    """
+    ...
+
 @dataclass
 class DiffusionGenerationParams:
     """
@@ -44,6 +104,52 @@ class DiffusionGenerationParams:
     width: int
     num_steps: int
     guidance_scale: float
+    negative_prompt: str | None = None
+    reference_images: list[bytes] = field(default_factory=list)
+    image: bytes | None = None
+    mask_image: bytes | None = None
+    control_image: bytes | None = None
+    controlnet_conditions: list[ControlNetCondition] = field(default_factory=list)
+    ip_adapter_images: list[IpAdapterImage] = field(default_factory=list)
+    redux_image: ReduxImage | None = None
+    max_sequence_length: int | None = None
+
+class PromptEmbeddings:
+    """
+    Text encoder outputs of a batch of prompts, from `Pipeline.encode_prompts`. They can be passed to
+    `Pipeline.forward` in place of the prompts.
+    """
+
+    @staticmethod
+    def load(path: str) -> PromptEmbeddings:
+        """
+        Load embeddings saved with `PromptEmbeddings.save`.
+        """
+        ...
+
+    def save(self, path: str) -> None:
+        """
+        Save the embeddings to a safetensors file.
+        """
+        ...
+
+@dataclass
+class PromptCacheStats:
+    """
+    Counters of the prompt-embedding cache, from `Pipeline.prompt_cache_stats`.
+
+    - `hits`: number of prompts whose embeddings were found in the cache.
+    - `misses`: number of prompts which were encoded.
+    - `entries`: number of cached prompts.
+    - `bytes`: size of the cached embeddings.
+    - `max_bytes`: maximum size of the cached embeddings.
+    """
+
+    hits: int
+    misses: int
+    entries: int
+    bytes: int
+    max_bytes: int
 
 class Pipeline:
     def __init__(
@@ -54,6 +160,9 @@ class Pipeline:
         revision: str | None = None,
         offloading: Offloading | None = None,
         ModelDType: ModelDType = ModelDType.Auto,
+        offloading_memory_budget_mb: int | None = None,
+        device: str = "auto",
+        verify: bool = False,
     ) -> None:
         """
         Load a model.
@@ -65,16 +174,84 @@ class Pipeline:
         - `token_source` specifies where to load the HF token from.
         - `offloading`: offloading setting for the model.
         - `dtype`: dtype selection for the model. The default is to use an automatic strategy with a fallback pattern: BF16 -> F16 -> F32
+        - `offloading_memory_budget_mb`: memory budget for resident transformer blocks with `Offloading.Disk`, defaults to 4096.
+        - `device`: device to run the model on: `auto`, `cpu`, `cuda:<ordinal>`, or `metal:<ordinal>`. The default is to use Metal or CUDA if supported by this build, otherwise the CPU.
+        - `verify`: check the integrity of the model files before loading them, defaults to `False`.
         """
         ...
 
-    def forward(
+    def load_controlnet(
+        self,
+        source: ModelSource,
+        silent: bool = False,
+        token: str | None = None,
+        revision: str | None = None,
+    ) -> None:
+        """
+        Load a standalone diffusers ControlNet and add it to the pipeline. It is conditioned with the
+        `controlnet_conditions` of the generation parameters, in the order the ControlNets were loaded.
+        """
+        ...
+
+    def load_ip_adapter(
+        self,
+        source: ModelSource,
+        image_encoder: ModelSource = ModelSource.ModelId("openai/clip-vit-large-patch14"),
+        silent: bool = False,
+        token: str | None = None,
+        revision: str | None = None,
+    ) -> None:
+        """
+        Load an XLabs IP-Adapter for FLUX, and the CLIP image encoder it was trained with. It is prompted with
+        the `ip_adapter_images` of the generation parameters.
+        """
+        ...
+
+    def load_redux(
+        self,
+        source: ModelSource,
+        silent: bool = False,
+        token: str | None = None,
+        revision: str | None = None,
+    ) -> None:
+        """
+        Load a FLUX.1 Redux prior, such as `black-forest-labs/FLUX.1-Redux-dev`, with its SigLIP image encoder.
+        It is prompted with the `redux_image` of the generation parameters.
+        """
+        ...
+
+    def set_prompt_cache(self, max_bytes: int | None = None) -> None:
+        """
+        Cache the embeddings of the prompts in memory, up to `max_bytes`, evicting the least recently used
+        prompts. Prompts found in the cache are not encoded again. `None` disables the cache, which is the
+        default.
+        """
+        ...
+
+    def prompt_cache_stats(self) -> PromptCacheStats | None:
+        """
+        The hit and miss counters of the prompt-embedding cache, if it is enabled.
+        """
+        ...
+
+    def encode_prompts(
         self,
         prompts: list[str],
+        max_sequence_length: int | None = None,
+    ) -> PromptEmbeddings:
+        """
+        Encode a batch of prompts with the text encoders of the model, to generate images from them without
+        encoding them again. `max_sequence_length` is the one of the generation parameters.
+        """
+        ...
+
+    def forward(
+        self,
+        prompts: list[str] | PromptEmbeddings,
         params: DiffusionGenerationParams,
     ) -> list[bytes]:
         """
-        Execute the diffusion model on the given batch of prompts.
+        Execute the diffusion model on the given batch of prompts, or their embeddings.
 
         Image data is returned as bytes objects and is in the order of the prompts
         """
@@ -1,9 +1,9 @@
 use std::io::Cursor;
 
 use pyo3::{
-    pyclass, pymethods, pymodule,
+    pyclass, pyfunction, pymethods, pymodule,
     types::{PyBytes, PyModule, PyModuleMethods},
-    Bound, Py, PyResult, Python,
+    wrap_pyfunction, Bound, FromPyObject, Py, PyResult, Python,
 };
 
 fn wrap_anyhow_error(e: anyhow::Error) -> pyo3::PyErr {
@@ -14,6 +14,7 @@ fn wrap_anyhow_error(e: anyhow::Error) -> pyo3::PyErr {
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Offloading {
     Full,
+    Disk,
 }
 
 #[pyclass]
@@ -23,6 +24,124 @@ pub enum ModelSource {
     DdufFile { file: String },
 }
 
+impl ModelSource {
+    fn into_core(self) -> PyResult<diffusion_rs_core::ModelSource> {
+        match self {
+            Self::DdufFile { file } => {
+                diffusion_rs_core::ModelSource::dduf(file).map_err(wrap_anyhow_error)
+            }
+            Self::ModelId { model_id } => {
+                Ok(diffusion_rs_core::ModelSource::from_model_id(model_id))
+            }
+        }
+    }
+}
+
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Clone, Debug)]
+pub struct ControlNetCondition {
+    pub image: Vec<u8>,
+    pub scale: f64,
+    pub start: f64,
+    pub end: f64,
+    pub mode: Option<usize>,
+}
+
+#[pymethods]
+impl ControlNetCondition {
+    #[new]
+    #[pyo3(signature = (
+        image,
+        scale = 1.0,
+        start = 0.0,
+        end = 1.0,
+        mode = None,
+    ))]
+    pub fn new(image: Vec<u8>, scale: f64, start: f64, end: f64, mode: Option<usize>) -> Self {
+        Self {
+            image,
+            scale,
+            start,
+            end,
+            mode,
+        }
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "ControlNetCondition(image = [{} bytes], scale = {}, start = {}, end = {}, mode = {:?})",
+            self.image.len(),
+            self.scale,
+            self.start,
+            self.end,
+            self.mode
+        )
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Clone, Debug)]
+pub struct IpAdapterImage {
+    pub image: Vec<u8>,
+    pub scale: f64,
+}
+
+#[pymethods]
+impl IpAdapterImage {
+    #[new]
+    #[pyo3(signature = (image, scale = 1.0))]
+    pub fn new(image: Vec<u8>, scale: f64) -> Self {
+        Self { image, scale }
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "IpAdapterImage(image = [{} bytes], scale = {})",
+            self.image.len(),
+            self.scale
+        )
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Clone, Debug)]
+pub struct ReduxImage {
+    pub image: Vec<u8>,
+    pub strength: f64,
+}
+
+#[pymethods]
+impl ReduxImage {
+    #[new]
+    #[pyo3(signature = (image, strength = 1.0))]
+    pub fn new(image: Vec<u8>, strength: f64) -> Self {
+        Self { image, strength }
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "ReduxImage(image = [{} bytes], strength = {})",
+            self.image.len(),
+            self.strength
+        )
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
 #[pyclass]
 #[pyo3(get_all)]
 #[derive(Clone, Debug)]
@@ -31,6 +150,15 @@ pub struct DiffusionGenerationParams {
     pub width: usize,
     pub num_steps: usize,
     pub guidance_scale: f64,
+    pub negative_prompt: Option<String>,
+    pub reference_images: Vec<Vec<u8>>,
+    pub image: Option<Vec<u8>>,
+    pub mask_image: Option<Vec<u8>>,
+    pub control_image: Option<Vec<u8>>,
+    pub controlnet_conditions: Vec<ControlNetCondition>,
+    pub ip_adapter_images: Vec<IpAdapterImage>,
+    pub redux_image: Option<ReduxImage>,
+    pub max_sequence_length: Option<usize>,
 }
 
 #[pyclass(eq, eq_int)]
@@ -51,23 +179,111 @@ impl DiffusionGenerationParams {
         width,
         num_steps,
         guidance_scale,
+        negative_prompt = None,
+        reference_images = Vec::new(),
+        image = None,
+        mask_image = None,
+        control_image = None,
+        controlnet_conditions = Vec::new(),
+        ip_adapter_images = Vec::new(),
+        redux_image = None,
+        max_sequence_length = None,
     ))]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         height: usize,
         width: usize,
         num_steps: usize,
         guidance_scale: f64,
+        negative_prompt: Option<String>,
+        reference_images: Vec<Vec<u8>>,
+        image: Option<Vec<u8>>,
+        mask_image: Option<Vec<u8>>,
+        control_image: Option<Vec<u8>>,
+        controlnet_conditions: Vec<ControlNetCondition>,
+        ip_adapter_images: Vec<IpAdapterImage>,
+        redux_image: Option<ReduxImage>,
+        max_sequence_length: Option<usize>,
     ) -> PyResult<Self> {
         Ok(Self {
             height,
             width,
             num_steps,
             guidance_scale,
+            negative_prompt,
+            reference_images,
+            image,
+            mask_image,
+            control_image,
+            controlnet_conditions,
+            ip_adapter_images,
+            redux_image,
+            max_sequence_length,
         })
     }
 
     pub fn __repr__(&self) -> String {
-        format!("DiffusionGenerationParams(height = {}, width = {}, num_steps = {}, guidance_scale = {})", self.height,self.width,self.num_steps,self.guidance_scale)
+        format!("DiffusionGenerationParams(height = {}, width = {}, num_steps = {}, guidance_scale = {}, negative_prompt = {:?}, reference_images = [{} images], image = {}, mask_image = {}, control_image = {}, controlnet_conditions = {:?}, ip_adapter_images = {:?}, redux_image = {:?}, max_sequence_length = {:?})", self.height,self.width,self.num_steps,self.guidance_scale,self.negative_prompt,self.reference_images.len(),self.image.is_some(),self.mask_image.is_some(),self.control_image.is_some(),self.controlnet_conditions.iter().map(ControlNetCondition::__repr__).collect::<Vec<_>>(),self.ip_adapter_images.iter().map(IpAdapterImage::__repr__).collect::<Vec<_>>(),self.redux_image.as_ref().map(ReduxImage::__repr__),self.max_sequence_length)
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+/// Text encoder outputs of a batch of prompts, from `Pipeline.encode_prompts`.
+#[pyclass]
+#[derive(Clone, Debug)]
+pub struct PromptEmbeddings(diffusion_rs_core::PromptEmbeddings);
+
+#[pymethods]
+impl PromptEmbeddings {
+    #[staticmethod]
+    fn load(path: String) -> PyResult<Self> {
+        diffusion_rs_core::PromptEmbeddings::load(path)
+            .map(Self)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn save(&self, path: String) -> PyResult<()> {
+        self.0.save(path).map_err(wrap_anyhow_error)
+    }
+
+    pub fn __repr__(&self) -> String {
+        format!(
+            "PromptEmbeddings(t5 = {:?}, clip_pooled = {:?})",
+            self.0.t5.dims(),
+            self.0
+                .clip_pooled
+                .as_ref()
+                .map(|clip_pooled| clip_pooled.dims())
+        )
+    }
+
+    pub fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+/// Counters of the prompt-embedding cache, from `Pipeline.prompt_cache_stats`.
+#[pyclass]
+#[pyo3(get_all)]
+#[derive(Clone, Debug)]
+pub struct PromptCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub max_bytes: usize,
+}
+
+#[pymethods]
+impl PromptCacheStats {
+    pub fn __repr__(&self) -> String {
+        format!(
+            "PromptCacheStats(hits = {}, misses = {}, entries = {}, bytes = {}, max_bytes = {})",
+            self.hits, self.misses, self.entries, self.bytes, self.max_bytes
+        )
     }
 
     pub fn __str__(&self) -> String {
@@ -75,6 +291,21 @@ impl DiffusionGenerationParams {
     }
 }
 
+#[derive(FromPyObject)]
+pub enum Prompts {
+    Text(Vec<String>),
+    Embeddings(PromptEmbeddings),
+}
+
+impl From<Prompts> for diffusion_rs_core::Prompts {
+    fn from(prompts: Prompts) -> Self {
+        match prompts {
+            Prompts::Text(prompts) => Self::Text(prompts),
+            Prompts::Embeddings(embeddings) => Self::Embeddings(embeddings.0),
+        }
+    }
+}
+
 #[pyclass]
 pub struct Pipeline(diffusion_rs_core::Pipeline);
 
@@ -88,7 +319,11 @@ impl Pipeline {
         revision = None,
         offloading = None,
         dtype = ModelDType::Auto,
+        offloading_memory_budget_mb = None,
+        device = "auto".to_string(),
+        verify = false,
     ))]
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         source: ModelSource,
         silent: bool,
@@ -96,21 +331,25 @@ impl Pipeline {
         revision: Option<String>,
         offloading: Option<Offloading>,
         dtype: ModelDType,
+        offloading_memory_budget_mb: Option<usize>,
+        device: String,
+        verify: bool,
     ) -> PyResult<Self> {
         let token = token
             .map(diffusion_rs_core::TokenSource::Literal)
             .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
-        let source = match source {
-            ModelSource::DdufFile { file } => {
-                diffusion_rs_core::ModelSource::dduf(file).map_err(wrap_anyhow_error)?
-            }
-            ModelSource::ModelId { model_id } => {
-                diffusion_rs_core::ModelSource::from_model_id(model_id)
-            }
-        };
+        let source = source.into_core()?;
         let offloading = offloading.map(|offloading| match offloading {
             Offloading::Full => diffusion_rs_core::Offloading::Full,
+            Offloading::Disk => diffusion_rs_core::Offloading::Disk {
+                memory_budget: offloading_memory_budget_mb
+                    .map(|mb| mb * 1024 * 1024)
+                    .unwrap_or(diffusion_rs_core::DEFAULT_DISK_OFFLOADING_BUDGET),
+            },
         });
+        let device = device
+            .parse::<diffusion_rs_core::DeviceSpec>()
+            .map_err(pyo3::exceptions::PyValueError::new_err)?;
         let dtype = match dtype {
             ModelDType::Auto => diffusion_rs_core::ModelDType::Auto,
             ModelDType::F16 => diffusion_rs_core::ModelDType::F16,
@@ -118,25 +357,194 @@ impl Pipeline {
             ModelDType::F32 => diffusion_rs_core::ModelDType::F32,
         };
         Ok(Self(
-            diffusion_rs_core::Pipeline::load(source, silent, token, revision, offloading, &dtype)
-                .map_err(wrap_anyhow_error)?,
+            diffusion_rs_core::Pipeline::load(
+                source,
+                silent,
+                token,
+                revision,
+                offloading,
+                device,
+                &dtype,
+                &diffusion_rs_core::PlacementMap::new(),
+                verify,
+            )
+            .map_err(wrap_anyhow_error)?,
         ))
     }
 
-    fn forward(
+    #[pyo3(signature = (
+        source,
+        silent = false,
+        token = None,
+        revision = None,
+    ))]
+    fn load_controlnet(
+        &self,
+        source: ModelSource,
+        silent: bool,
+        token: Option<String>,
+        revision: Option<String>,
+    ) -> PyResult<()> {
+        let token = token
+            .map(diffusion_rs_core::TokenSource::Literal)
+            .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
+        self.0
+            .load_controlnet(source.into_core()?, silent, token, revision)
+            .map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (
+        source,
+        image_encoder = ModelSource::ModelId { model_id: "openai/clip-vit-large-patch14".to_string() },
+        silent = false,
+        token = None,
+        revision = None,
+    ))]
+    fn load_ip_adapter(
+        &self,
+        source: ModelSource,
+        image_encoder: ModelSource,
+        silent: bool,
+        token: Option<String>,
+        revision: Option<String>,
+    ) -> PyResult<()> {
+        let token = token
+            .map(diffusion_rs_core::TokenSource::Literal)
+            .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
+        self.0
+            .load_ip_adapter(
+                source.into_core()?,
+                image_encoder.into_core()?,
+                silent,
+                token,
+                revision,
+            )
+            .map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (
+        source,
+        silent = false,
+        token = None,
+        revision = None,
+    ))]
+    fn load_redux(
+        &self,
+        source: ModelSource,
+        silent: bool,
+        token: Option<String>,
+        revision: Option<String>,
+    ) -> PyResult<()> {
+        let token = token
+            .map(diffusion_rs_core::TokenSource::Literal)
+            .unwrap_or(diffusion_rs_core::TokenSource::CacheToken);
+        self.0
+            .load_redux(source.into_core()?, silent, token, revision)
+            .map_err(wrap_anyhow_error)
+    }
+
+    #[pyo3(signature = (max_bytes = None))]
+    fn set_prompt_cache(&self, max_bytes: Option<usize>) -> PyResult<()> {
+        self.0
+            .set_prompt_cache(max_bytes)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn prompt_cache_stats(&self) -> Option<PromptCacheStats> {
+        self.0.prompt_cache_stats().map(|stats| PromptCacheStats {
+            hits: stats.hits,
+            misses: stats.misses,
+            entries: stats.entries,
+            bytes: stats.bytes,
+            max_bytes: stats.max_bytes,
+        })
+    }
+
+    #[pyo3(signature = (prompts, max_sequence_length = None))]
+    fn encode_prompts(
         &self,
         prompts: Vec<String>,
+        max_sequence_length: Option<usize>,
+    ) -> PyResult<PromptEmbeddings> {
+        self.0
+            .encode_prompts(prompts, max_sequence_length)
+            .map(PromptEmbeddings)
+            .map_err(wrap_anyhow_error)
+    }
+
+    fn forward(
+        &self,
+        prompts: Prompts,
         params: DiffusionGenerationParams,
     ) -> PyResult<Vec<Py<PyBytes>>> {
+        let reference_images = params
+            .reference_images
+            .iter()
+            .map(|bytes| image::load_from_memory(bytes))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| wrap_anyhow_error(e.into()))?;
+        let decode = |bytes: Option<Vec<u8>>| {
+            bytes
+                .map(|bytes| image::load_from_memory(&bytes))
+                .transpose()
+                .map_err(|e| wrap_anyhow_error(e.into()))
+        };
+        let image = decode(params.image)?;
+        let mask_image = decode(params.mask_image)?;
+        let control_image = decode(params.control_image)?;
+        let controlnet_conditions = params
+            .controlnet_conditions
+            .into_iter()
+            .map(|condition| {
+                Ok(diffusion_rs_core::ControlNetCondition {
+                    image: image::load_from_memory(&condition.image)
+                        .map_err(|e| wrap_anyhow_error(e.into()))?,
+                    scale: condition.scale,
+                    start: condition.start,
+                    end: condition.end,
+                    mode: condition.mode,
+                })
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let ip_adapter_images = params
+            .ip_adapter_images
+            .into_iter()
+            .map(|ip_image| {
+                Ok(diffusion_rs_core::IpAdapterImage {
+                    image: image::load_from_memory(&ip_image.image)
+                        .map_err(|e| wrap_anyhow_error(e.into()))?,
+                    scale: ip_image.scale,
+                })
+            })
+            .collect::<PyResult<Vec<_>>>()?;
+        let redux_image = params
+            .redux_image
+            .map(|redux_image| -> PyResult<_> {
+                Ok(diffusion_rs_core::ReduxImage {
+                    image: image::load_from_memory(&redux_image.image)
+                        .map_err(|e| wrap_anyhow_error(e.into()))?,
+                    strength: redux_image.strength,
+                })
+            })
+            .transpose()?;
         let images = self
             .0
             .forward(
-                prompts,
+                diffusion_rs_core::Prompts::from(prompts),
                 diffusion_rs_core::DiffusionGenerationParams {
                     height: params.height,
                     width: params.width,
                     num_steps: params.num_steps,
                     guidance_scale: params.guidance_scale,
+                    negative_prompt: params.negative_prompt,
+                    reference_images,
+                    image,
+                    mask_image,
+                    control_image,
+                    controlnet_conditions,
+                    ip_adapter_images,
+                    redux_image,
+                    max_sequence_length: params.max_sequence_length,
                 },
             )
             .map_err(wrap_anyhow_error)?;
@@ -155,10 +563,39 @@ impl Pipeline {
     }
 }
 
+/// Compute a control image with one of the preprocessors of `diffusion_rs_core::ControlPreprocessor`, given
+/// in the same form as the `--control-preprocessor` CLI option, such as `canny:100:200`.
+#[pyfunction]
+fn preprocess_control_image(
+    image: Vec<u8>,
+    preprocessor: String,
+    width: usize,
+    height: usize,
+) -> PyResult<Py<PyBytes>> {
+    let preprocessor = preprocessor
+        .parse::<diffusion_rs_core::ControlPreprocessor>()
+        .map_err(pyo3::exceptions::PyValueError::new_err)?;
+    let image = image::load_from_memory(&image).map_err(|e| wrap_anyhow_error(e.into()))?;
+    let image = preprocessor
+        .process(&image, width, height)
+        .map_err(|e| wrap_anyhow_error(e.into()))?;
+    let mut buf = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut buf), image::ImageFormat::Png)
+        .map_err(|e| wrap_anyhow_error(e.into()))?;
+    Ok(Python::with_gil(move |py| PyBytes::new(py, &buf).into()))
+}
+
 #[pymodule]
 fn diffusion_rs(_py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<ModelSource>()?;
+    m.add_class::<ControlNetCondition>()?;
+    m.add_class::<IpAdapterImage>()?;
+    m.add_class::<ReduxImage>()?;
+    m.add_class::<PromptEmbeddings>()?;
+    m.add_class::<PromptCacheStats>()?;
     m.add_class::<DiffusionGenerationParams>()?;
     m.add_class::<Pipeline>()?;
+    m.add_function(wrap_pyfunction!(preprocess_control_image, m)?)?;
     Ok(())
 }